@@ -0,0 +1,78 @@
+use crate::muse_packet::Band;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How far back band samples are kept, the longest selectable graph window
+pub const HISTORY_LENGTH: Duration = Duration::from_secs(600);
+
+/// Selectable graph windows, in seconds
+pub const HISTORY_WINDOWS: [u64; 5] = [10, 30, 60, 180, 600];
+
+#[derive(Debug)]
+pub struct BandHistory {
+    samples: [VecDeque<(Duration, [f32; 4])>; 5],
+    markers: VecDeque<(Duration, String)>,
+}
+
+impl BandHistory {
+    pub fn new() -> BandHistory {
+        BandHistory {
+            samples: [
+                VecDeque::new(),
+                VecDeque::new(),
+                VecDeque::new(),
+                VecDeque::new(),
+                VecDeque::new(),
+            ],
+            markers: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, band: Band, time: Duration, values: [f32; 4]) {
+        let samples = &mut self.samples[band.index()];
+        samples.push_back((time, values));
+
+        while let Some(&(oldest, _)) = samples.front() {
+            if time
+                .checked_sub(oldest)
+                .map_or(false, |age| age > HISTORY_LENGTH)
+            {
+                samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Keep a marker label for drawing over the graphs, for as long as band samples are kept
+    pub fn push_marker(&mut self, time: Duration, label: String) {
+        self.markers.push_back((time, label));
+
+        while let Some(&(oldest, _)) = self.markers.front() {
+            if time
+                .checked_sub(oldest)
+                .map_or(false, |age| age > HISTORY_LENGTH)
+            {
+                self.markers.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn markers(&self) -> &VecDeque<(Duration, String)> {
+        &self.markers
+    }
+
+    pub fn samples(&self, band: Band) -> &VecDeque<(Duration, [f32; 4])> {
+        &self.samples[band.index()]
+    }
+
+    /// Time of the most recent sample in any band
+    pub fn latest_time(&self) -> Option<Duration> {
+        self.samples
+            .iter()
+            .filter_map(|samples| samples.back().map(|&(time, _)| time))
+            .max()
+    }
+}
@@ -0,0 +1,242 @@
+use crate::muse_packet::{Band, MuseMessage};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Band means of every headset are sampled on this grid, so their histories line up
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sliding window over which synchrony is computed
+pub const SYNCHRONY_WINDOW: Duration = Duration::from_secs(30);
+
+/// Headsets silent for longer leave the crowd
+const PARTICIPANT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fewest overlapping samples to correlate, so a pair is not judged on a moment
+const MIN_CORRELATION_SAMPLES: usize = 50;
+
+/// One headset in the crowd, identified by the address its packets come from
+#[derive(Clone, Debug)]
+pub struct Participant {
+    pub address: SocketAddr,
+    /// Latest values per electrode, indexed by `Band::index()`
+    pub bands: [[f32; 4]; 5],
+    pub last_seen: Duration,
+    /// Mean over the electrodes of each band on the sample grid, newest last
+    history: VecDeque<[f32; 5]>,
+}
+
+impl Participant {
+    fn new(address: SocketAddr, time: Duration) -> Participant {
+        Participant {
+            address: address,
+            bands: [[0.0; 4]; 5],
+            last_seen: time,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// The latest value of a band averaged over the electrodes
+    pub fn band_mean(&self, band: Band) -> f32 {
+        self.bands[band.index()].iter().sum::<f32>() / 4.0
+    }
+
+    /// The band mean on the sample grid over the synchrony window, oldest first
+    pub fn band_history(&self, band: Band) -> Vec<f32> {
+        self.history
+            .iter()
+            .map(|means| means[band.index()])
+            .collect()
+    }
+}
+
+/// Every headset sending to this app, with group statistics across them
+#[derive(Debug)]
+pub struct Crowd {
+    participants: Vec<Participant>,
+    next_sample: Option<Duration>,
+}
+
+impl Crowd {
+    pub fn new() -> Crowd {
+        Crowd {
+            participants: Vec::new(),
+            next_sample: None,
+        }
+    }
+
+    /// Take the band values of a message, adding its headset to the crowd if it is new
+    pub fn push(&mut self, muse_message: &MuseMessage) {
+        let (band, values) = match muse_message.muse_message_type.band() {
+            Some(band_values) => band_values,
+            None => return,
+        };
+
+        let address = muse_message.ip_address;
+        let index = match self.position(address) {
+            Some(index) => index,
+            None => {
+                self.participants
+                    .push(Participant::new(address, muse_message.time));
+                self.participants.len() - 1
+            }
+        };
+        let participant = &mut self.participants[index];
+        participant.bands[band.index()] = values;
+        participant.last_seen = muse_message.time;
+    }
+
+    /// Drop silent headsets and sample the rest onto the grid when a sample is due
+    pub fn tick(&mut self, now: Duration) {
+        self.participants.retain(|participant| {
+            now.checked_sub(participant.last_seen)
+                .map_or(true, |age| age <= PARTICIPANT_TIMEOUT)
+        });
+
+        let due = self.next_sample.unwrap_or(now);
+        if now < due {
+            return;
+        }
+        // After a stall the grid restarts rather than catching up with repeated samples
+        self.next_sample = Some(if now - due > SAMPLE_INTERVAL {
+            now + SAMPLE_INTERVAL
+        } else {
+            due + SAMPLE_INTERVAL
+        });
+
+        let length = (SYNCHRONY_WINDOW.as_millis() / SAMPLE_INTERVAL.as_millis()) as usize;
+        for participant in self.participants.iter_mut() {
+            let mut means = [0.0; 5];
+            for band in Band::ALL.iter() {
+                means[band.index()] = participant.band_mean(*band);
+            }
+            participant.history.push_back(means);
+            while participant.history.len() > length {
+                participant.history.pop_front();
+            }
+        }
+    }
+
+    pub fn participants(&self) -> &[Participant] {
+        &self.participants
+    }
+
+    /// Index of a headset in `participants()`
+    pub fn position(&self, address: SocketAddr) -> Option<usize> {
+        self.participants
+            .iter()
+            .position(|participant| participant.address == address)
+    }
+
+    /// Mean and standard deviation across headsets of each one's band mean
+    pub fn band_spread(&self, band: Band) -> Option<(f32, f32)> {
+        if self.participants.is_empty() {
+            return None;
+        }
+
+        let count = self.participants.len() as f32;
+        let mean = self
+            .participants
+            .iter()
+            .map(|participant| participant.band_mean(band))
+            .sum::<f32>()
+            / count;
+        let variance = self
+            .participants
+            .iter()
+            .map(|participant| (participant.band_mean(band) - mean).powi(2))
+            .sum::<f32>()
+            / count;
+
+        Some((mean, variance.sqrt()))
+    }
+
+    /// Correlation of two headsets' band power over the synchrony window
+    pub fn pair_synchrony(&self, a: usize, b: usize, band: Band) -> Option<f32> {
+        let a = self.participants.get(a)?.band_history(band);
+        let b = self.participants.get(b)?.band_history(band);
+
+        correlation(&a, &b)
+    }
+
+    /// Mean band power correlation over every pair of headsets, from -1.0 to 1.0
+    pub fn synchrony(&self, band: Band) -> Option<f32> {
+        let mut sum = 0.0;
+        let mut pairs = 0;
+        for a in 0..self.participants.len() {
+            for b in a + 1..self.participants.len() {
+                if let Some(r) = self.pair_synchrony(a, b, band) {
+                    sum += r;
+                    pairs += 1;
+                }
+            }
+        }
+
+        if pairs == 0 {
+            None
+        } else {
+            Some(sum / pairs as f32)
+        }
+    }
+}
+
+/// Pearson correlation of the newest samples two series share, or None if there are too few
+/// or either is flat
+pub fn correlation(a: &[f32], b: &[f32]) -> Option<f32> {
+    let count = a.len().min(b.len());
+    if count < MIN_CORRELATION_SAMPLES {
+        return None;
+    }
+    let a = &a[a.len() - count..];
+    let b = &b[b.len() - count..];
+
+    let mean_a = a.iter().sum::<f32>() / count as f32;
+    let mean_b = b.iter().sum::<f32>() / count as f32;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a <= 0.0 || variance_b <= 0.0 {
+        return None;
+    }
+
+    Some(covariance / (variance_a * variance_b).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_series_are_fully_correlated() {
+        let a: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let rising: Vec<f32> = a.iter().map(|x| 3.0 * x + 2.0).collect();
+        let falling: Vec<f32> = a.iter().map(|x| 5.0 - 0.5 * x).collect();
+
+        assert!((correlation(&a, &rising).unwrap() - 1.0).abs() < 1e-5);
+        assert!((correlation(&a, &falling).unwrap() + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn newest_shared_samples_are_compared() {
+        let a: Vec<f32> = (0..120).map(|i| i as f32).collect();
+        // Only the last 60 samples line up with `a`
+        let b: Vec<f32> = (60..120).map(|i| i as f32 * 2.0).collect();
+
+        assert!((correlation(&a, &b).unwrap() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn flat_or_short_series_have_no_correlation() {
+        let a: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let flat = vec![4.0; 100];
+
+        assert!(correlation(&a, &flat).is_none());
+        assert!(correlation(&flat, &a).is_none());
+        assert!(correlation(&a[..MIN_CORRELATION_SAMPLES - 1], &a).is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::muse_packet::Band;
+use crate::Model;
+
+/// Weight given to the newest value in the per-frame exponential smoothing
+const SMOOTHING: f32 = 0.1;
+
+/// Signals computed from the raw Muse values each frame
+#[derive(Clone, Debug)]
+pub struct DerivedMetrics {
+    /// Exponentially smoothed band values, indexed by `Band::index()`
+    pub smoothed: [[f32; 4]; 5],
+    /// Left/right frontal alpha asymmetry, as drawn by the emotion view. Holds its last
+    /// finite value while theta is 0, before any has arrived.
+    pub asymmetry: f32,
+    /// Mean log power of the slow waves (theta and delta) minus mean log power of alpha
+    pub drowsiness: f32,
+    /// Fraction of electrodes with a good horseshoe fit, 0.0 to 1.0
+    pub quality: f32,
+}
+
+impl DerivedMetrics {
+    pub fn new() -> DerivedMetrics {
+        DerivedMetrics {
+            smoothed: [[0.0; 4]; 5],
+            asymmetry: 0.0,
+            drowsiness: 0.0,
+            quality: 0.0,
+        }
+    }
+
+    /// The metrics for the current frame, smoothing on from these
+    pub fn updated(&self, model: &Model) -> DerivedMetrics {
+        let mut smoothed = self.smoothed;
+        for band in Band::ALL.iter() {
+            let values = model.band_values(*band);
+            let smoothed = &mut smoothed[band.index()];
+            for i in 0..4 {
+                smoothed[i] = smoothed[i] + SMOOTHING * (values[i] - smoothed[i]);
+            }
+        }
+
+        let good_fits = model
+            .horseshoe
+            .iter()
+            .filter(|&&fit| fit >= 0.5 && fit < 1.5)
+            .count();
+
+        let mut asymmetry = asymmetry(&model.alpha, average_from_four_electrodes(&model.theta));
+        if !asymmetry.is_finite() {
+            asymmetry = self.asymmetry;
+        }
+
+        DerivedMetrics {
+            smoothed: smoothed,
+            asymmetry: asymmetry,
+            drowsiness: slow_waves(&model.theta, &model.delta)
+                - average_from_four_electrodes(&model.alpha),
+            quality: good_fits as f32 / 4.0,
+        }
+    }
+
+    pub fn smoothed_band(&self, band: Band) -> [f32; 4] {
+        self.smoothed[band.index()]
+    }
+}
+
+pub fn average_from_four_electrodes(x: &[f32; 4]) -> f32 {
+    (x[0] + x[1] + x[2] + x[3]) / 4.0
+}
+
+pub fn asymmetry(x: &[f32; 4], n: f32) -> f32 {
+    let base = std::f32::consts::E;
+    base.powf(x[1] / n - x[2] / n)
+}
+
+/// Average of theta and delta over all electrodes
+pub fn slow_waves(theta: &[f32; 4], delta: &[f32; 4]) -> f32 {
+    (average_from_four_electrodes(theta) + average_from_four_electrodes(delta)) / 2.0
+}
@@ -0,0 +1,425 @@
+use std::collections::VecDeque;
+use std::env;
+use std::time::Duration;
+
+/// Epoch window around each marker as "pre,post" in milliseconds, e.g. "200,800"
+pub const ERP_WINDOW_ENV: &str = "MEME_MACHINE_ERP_WINDOW";
+
+/// Epochs with a baseline-corrected sample beyond this many microvolts are rejected
+pub const ERP_REJECT_ENV: &str = "MEME_MACHINE_ERP_REJECT";
+
+/// Comma separated marker labels to epoch, each a condition, e.g. "1,2" for a two key oddball.
+/// A label also matches markers that start with it and a space, so "onset" covers every meme.
+pub const ERP_MARKERS_ENV: &str = "MEME_MACHINE_ERP_MARKERS";
+
+/// Raw EEG sample rate of the Muse
+pub const EEG_RATE: f32 = 256.0;
+
+const DEFAULT_PRE: Duration = Duration::from_millis(200);
+const DEFAULT_POST: Duration = Duration::from_millis(800);
+const DEFAULT_REJECT_MICROVOLTS: f32 = 100.0;
+const DEFAULT_MARKERS: [&str; 11] = ["onset", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9"];
+
+/// Epochs spanning a longer gap between samples are rejected as incomplete
+const MAX_SAMPLE_GAP: Duration = Duration::from_millis(50);
+
+/// Raw EEG kept beyond one epoch length, for markers that arrive a little late
+const BUFFER_MARGIN: Duration = Duration::from_secs(2);
+
+/// Running average of the accepted epochs of one marker label
+#[derive(Clone, Debug)]
+pub struct ErpCondition {
+    pub name: String,
+    sum: Vec<[f32; 4]>,
+    pub accepted: usize,
+    pub rejected: usize,
+}
+
+impl ErpCondition {
+    fn new(name: &str, length: usize) -> ErpCondition {
+        ErpCondition {
+            name: String::from(name),
+            sum: vec![[0.0; 4]; length],
+            accepted: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Mean epoch per electrode, in microvolts relative to the pre-marker baseline
+    pub fn average(&self) -> Vec<[f32; 4]> {
+        let count = self.accepted.max(1) as f32;
+
+        self.sum
+            .iter()
+            .map(|sample| {
+                [
+                    sample[0] / count,
+                    sample[1] / count,
+                    sample[2] / count,
+                    sample[3] / count,
+                ]
+            })
+            .collect()
+    }
+}
+
+/// Cuts raw EEG into epochs around markers and averages them per condition
+#[derive(Debug)]
+pub struct ErpAverager {
+    pub pre: Duration,
+    pub post: Duration,
+    reject: f32,
+    markers: Vec<String>,
+    /// Recent raw EEG with sample times spread out at `EEG_RATE`
+    samples: VecDeque<(Duration, [f32; 4])>,
+    /// Samples received since the last frame, held until `end_frame`
+    frame: Vec<[f32; 4]>,
+    /// Receive time of the newest sample in `frame`
+    frame_time: Duration,
+    /// Marker times and condition indices waiting for the end of their epoch
+    pending: Vec<(Duration, usize)>,
+    pub conditions: Vec<ErpCondition>,
+}
+
+impl ErpAverager {
+    pub fn new(pre: Duration, post: Duration, reject: f32, markers: Vec<String>) -> ErpAverager {
+        ErpAverager {
+            pre: pre,
+            post: post,
+            reject: reject,
+            markers: markers,
+            samples: VecDeque::new(),
+            frame: Vec::new(),
+            frame_time: Duration::from_secs(0),
+            pending: Vec::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Window, rejection threshold and markers from the environment, or the defaults
+    pub fn from_env() -> ErpAverager {
+        let (pre, post) = match env::var(ERP_WINDOW_ENV) {
+            Ok(window) => {
+                let parts: Vec<Option<u64>> = window
+                    .split(',')
+                    .map(|part| part.trim().parse().ok())
+                    .collect();
+                match parts.as_slice() {
+                    [Some(pre), Some(post)] if *post > 0 => {
+                        (Duration::from_millis(*pre), Duration::from_millis(*post))
+                    }
+                    _ => {
+                        eprintln!("Ignoring {}={:?}", ERP_WINDOW_ENV, window);
+                        (DEFAULT_PRE, DEFAULT_POST)
+                    }
+                }
+            }
+            Err(_) => (DEFAULT_PRE, DEFAULT_POST),
+        };
+        let reject = match env::var(ERP_REJECT_ENV) {
+            Ok(reject) => match reject.trim().parse::<f32>() {
+                Ok(reject) if reject > 0.0 => reject,
+                _ => {
+                    eprintln!("Ignoring {}={:?}", ERP_REJECT_ENV, reject);
+                    DEFAULT_REJECT_MICROVOLTS
+                }
+            },
+            Err(_) => DEFAULT_REJECT_MICROVOLTS,
+        };
+        let markers = match env::var(ERP_MARKERS_ENV) {
+            Ok(markers) => markers
+                .split(',')
+                .map(|marker| marker.trim().to_string())
+                .filter(|marker| !marker.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_MARKERS.iter().map(|m| m.to_string()).collect(),
+        };
+
+        ErpAverager::new(pre, post, reject, markers)
+    }
+
+    /// Samples in one epoch
+    pub fn epoch_length(&self) -> usize {
+        ((self.pre + self.post).as_secs_f32() * EEG_RATE).round() as usize
+    }
+
+    /// Add a raw EEG sample received at `time`. It is timed and epoched at `end_frame`.
+    pub fn push_eeg(&mut self, time: Duration, values: [f32; 4]) {
+        self.frame_time = self.frame_time.max(time);
+        self.frame.push(values);
+    }
+
+    /// Time the samples received this frame and complete any epochs they reach the end of
+    pub fn end_frame(&mut self) {
+        if !self.frame.is_empty() {
+            self.flush_frame();
+        }
+    }
+
+    /// Start an epoch at a marker if its label is one of the conditions
+    pub fn push_marker(&mut self, time: Duration, label: &str) {
+        let condition = self
+            .markers
+            .iter()
+            .find(|marker| label == marker.as_str() || label.starts_with(&format!("{} ", marker)));
+        let condition = match condition {
+            Some(condition) => condition.clone(),
+            None => return,
+        };
+
+        let index = match self.conditions.iter().position(|c| c.name == condition) {
+            Some(index) => index,
+            None => {
+                let length = self.epoch_length();
+                self.conditions.push(ErpCondition::new(&condition, length));
+                self.conditions.len() - 1
+            }
+        };
+        self.pending.push((time, index));
+    }
+
+    /// Forget buffered EEG and unfinished epochs, as after a jump in replay, keeping the averages
+    pub fn reset_stream(&mut self) {
+        self.samples.clear();
+        self.frame.clear();
+        self.frame_time = Duration::from_secs(0);
+        self.pending.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.reset_stream();
+        self.conditions.clear();
+    }
+
+    /// Packets are read once a frame, so the samples of a frame have receive times only
+    /// microseconds apart. They are spread back from the newest at the nominal rate to recover
+    /// the waveform.
+    fn flush_frame(&mut self) {
+        let period = Duration::from_secs_f32(1.0 / EEG_RATE);
+        let count = self.frame.len() as u32;
+        let last_time = self.samples.back().map(|&(time, _)| time);
+
+        for (i, values) in self.frame.drain(..).enumerate() {
+            let spread = self.frame_time.checked_sub(period * (count - 1 - i as u32));
+            let time = match (spread, last_time) {
+                (Some(time), Some(last_time)) => time.max(last_time),
+                (Some(time), None) => time,
+                (None, _) => self.frame_time,
+            };
+            self.samples.push_back((time, values));
+        }
+
+        let keep = self.pre + self.post + BUFFER_MARGIN;
+        while let Some(&(oldest, _)) = self.samples.front() {
+            if self
+                .frame_time
+                .checked_sub(oldest)
+                .map_or(false, |age| age > keep)
+            {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        self.complete_epochs();
+    }
+
+    fn complete_epochs(&mut self) {
+        let latest = match self.samples.back() {
+            Some(&(time, _)) => time,
+            None => return,
+        };
+        let post = self.post;
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .pending
+            .iter()
+            .partition(|&&(time, _)| time + post <= latest);
+        self.pending = waiting;
+
+        for (time, index) in ready {
+            match self.epoch(time) {
+                Some(epoch) => {
+                    let condition = &mut self.conditions[index];
+                    for (sum, sample) in condition.sum.iter_mut().zip(epoch.iter()) {
+                        for electrode in 0..4 {
+                            sum[electrode] += sample[electrode];
+                        }
+                    }
+                    condition.accepted += 1;
+                }
+                None => self.conditions[index].rejected += 1,
+            }
+        }
+    }
+
+    /// The baseline-corrected epoch around a marker on a regular grid, or None if it is
+    /// incomplete or has an artifact
+    fn epoch(&self, marker: Duration) -> Option<Vec<[f32; 4]>> {
+        let start = marker.checked_sub(self.pre)?;
+        let length = self.epoch_length();
+        let mut epoch = Vec::with_capacity(length);
+
+        let mut k = 0;
+        for j in 0..length {
+            let t = start + Duration::from_secs_f32(j as f32 / EEG_RATE);
+            while k + 1 < self.samples.len() && self.samples[k + 1].0 <= t {
+                k += 1;
+            }
+            let (t0, v0) = *self.samples.get(k)?;
+            let (t1, v1) = *self.samples.get(k + 1)?;
+            if t0 > t || t1 - t0 > MAX_SAMPLE_GAP {
+                return None;
+            }
+            let fraction = if t1 > t0 {
+                (t - t0).as_secs_f32() / (t1 - t0).as_secs_f32()
+            } else {
+                0.0
+            };
+            let mut sample = [0.0; 4];
+            for electrode in 0..4 {
+                sample[electrode] = v0[electrode] + (v1[electrode] - v0[electrode]) * fraction;
+            }
+            epoch.push(sample);
+        }
+
+        let baseline_length = (self.pre.as_secs_f32() * EEG_RATE).round() as usize;
+        if baseline_length > 0 {
+            let mut baseline = [0.0; 4];
+            for sample in epoch.iter().take(baseline_length) {
+                for electrode in 0..4 {
+                    baseline[electrode] += sample[electrode] / baseline_length as f32;
+                }
+            }
+            for sample in epoch.iter_mut() {
+                for electrode in 0..4 {
+                    sample[electrode] -= baseline[electrode];
+                }
+            }
+        }
+
+        let artifact = epoch
+            .iter()
+            .any(|sample| sample.iter().any(|value| value.abs() > self.reject));
+        if artifact {
+            return None;
+        }
+
+        Some(epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const START: Duration = Duration::from_secs(1_575_473_412);
+    const MARKER: Duration = Duration::from_secs(1_575_473_413);
+
+    fn averager() -> ErpAverager {
+        ErpAverager::new(
+            DEFAULT_PRE,
+            DEFAULT_POST,
+            DEFAULT_REJECT_MICROVOLTS,
+            vec![String::from("1")],
+        )
+    }
+
+    fn sample_time(i: usize) -> Duration {
+        START + Duration::from_secs_f64(i as f64 / EEG_RATE as f64)
+    }
+
+    /// Three seconds of `signal`, one sample a frame, with a marker after the first second
+    fn run(averager: &mut ErpAverager, signal: impl Fn(f32) -> Option<f32>) {
+        for i in 0..3 * EEG_RATE as usize {
+            if sample_time(i) == MARKER {
+                averager.push_marker(MARKER, "1");
+            }
+            let t = (sample_time(i) - START).as_secs_f32();
+            if let Some(value) = signal(t) {
+                averager.push_eeg(sample_time(i), [value; 4]);
+                averager.end_frame();
+            }
+        }
+    }
+
+    fn sine(t: f32) -> f32 {
+        800.0 + 20.0 * (2.0 * PI * 10.0 * t).sin()
+    }
+
+    #[test]
+    fn sine_epoch() {
+        let mut averager = averager();
+        run(&mut averager, |t| Some(sine(t)));
+
+        let condition = &averager.conditions[0];
+        assert_eq!((condition.accepted, condition.rejected), (1, 0));
+        let average = condition.average();
+        assert_eq!(average.len(), averager.epoch_length());
+
+        let marker = (MARKER - START).as_secs_f32();
+        let pre = DEFAULT_PRE.as_secs_f32();
+        let expected: Vec<f32> = (0..average.len())
+            .map(|j| sine(marker - pre + j as f32 / EEG_RATE))
+            .collect();
+        let baseline_length = (pre * EEG_RATE).round() as usize;
+        let baseline = expected[..baseline_length].iter().sum::<f32>() / baseline_length as f32;
+        // The grid falls between samples, where linear interpolation is off by up to 0.15 µV
+        for (sample, expected) in average.iter().zip(expected.iter()) {
+            for value in sample.iter() {
+                assert!((value - (expected - baseline)).abs() < 0.2);
+            }
+        }
+    }
+
+    #[test]
+    fn baseline_is_removed() {
+        // An offset far beyond the rejection threshold, stepping up 10 µV at the marker
+        let marker = (MARKER - START).as_secs_f32();
+        let mut averager = averager();
+        run(&mut averager, |t| {
+            Some(if t < marker { 500.0 } else { 510.0 })
+        });
+
+        let condition = &averager.conditions[0];
+        assert_eq!(condition.accepted, 1);
+        let average = condition.average();
+        assert!(average[0][0].abs() < 0.01);
+        assert!((average[average.len() - 1][0] - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn gap_is_rejected() {
+        let marker = (MARKER - START).as_secs_f32();
+        let gap = MAX_SAMPLE_GAP.as_secs_f32() * 2.0;
+        let mut averager = averager();
+        run(&mut averager, |t| {
+            if t > marker + 0.1 && t < marker + 0.1 + gap {
+                None
+            } else {
+                Some(sine(t))
+            }
+        });
+
+        let condition = &averager.conditions[0];
+        assert_eq!((condition.accepted, condition.rejected), (0, 1));
+    }
+
+    #[test]
+    fn frame_is_spread_at_the_sample_rate() {
+        let mut averager = averager();
+        // Four packets read in one frame, stamped microseconds apart
+        for i in 0..4 {
+            averager.push_eeg(START + Duration::from_micros(i), [0.0; 4]);
+        }
+        averager.end_frame();
+
+        let times: Vec<Duration> = averager.samples.iter().map(|&(time, _)| time).collect();
+        assert_eq!(times.last(), Some(&(START + Duration::from_micros(3))));
+        for pair in times.windows(2) {
+            let period = (pair[1] - pair[0]).as_secs_f32();
+            assert!((period - 1.0 / EEG_RATE).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::muse_storage::civil_time;
+use nannou::prelude::*;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Directory new frame sequences are written under, the current directory if unset
+pub const CAPTURE_DIR_ENV: &str = "MEME_MACHINE_CAPTURE_DIR";
+
+pub const RENDER_USAGE: &str = "Usage: meme_machine --render <session file> [<directory> [<fps>]]";
+
+/// Frame rate of an offline render when none is given
+const DEFAULT_RENDER_FPS: f64 = 30.0;
+
+/// Frame rates a render is clamped to
+const MIN_RENDER_FPS: f64 = 1.0;
+const MAX_RENDER_FPS: f64 = 240.0;
+
+/// Saves every frame the window draws as a numbered PNG sequence
+#[derive(Debug)]
+pub struct FrameCapture {
+    directory: PathBuf,
+    /// App frame counted as the first of the sequence
+    first_frame: u64,
+}
+
+impl FrameCapture {
+    pub fn new(directory: PathBuf, first_frame: u64) -> io::Result<FrameCapture> {
+        fs::create_dir_all(&directory)?;
+
+        Ok(FrameCapture {
+            directory: directory,
+            first_frame: first_frame,
+        })
+    }
+
+    /// Capture to a new directory named after the time, under `MEME_MACHINE_CAPTURE_DIR`
+    pub fn start(now: Duration, first_frame: u64) -> io::Result<FrameCapture> {
+        let parent = env::var(CAPTURE_DIR_ENV).unwrap_or_else(|_| String::from("."));
+        let (year, month, day, hour, minute, second) = civil_time(now);
+        let name = format!(
+            "capture_{:04}{:02}{:02}_{:02}{:02}{:02}",
+            year, month, day, hour, minute, second
+        );
+
+        FrameCapture::new(Path::new(&parent).join(name), first_frame)
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Frames captured so far
+    pub fn frames(&self, app: &App) -> u64 {
+        app.elapsed_frames().saturating_sub(self.first_frame)
+    }
+
+    /// Save the frame being drawn. Called from the view, the PNG is written in the background.
+    pub fn capture(&self, app: &App) {
+        let path = self
+            .directory
+            .join(format!("frame_{:06}.png", self.frames(app)));
+        app.main_window().capture_frame(path);
+    }
+}
+
+/// Replays a session file to a PNG sequence at a fixed frame rate. Time steps one frame per
+/// update however long drawing takes, so slow views still give smooth video.
+#[derive(Debug)]
+pub struct OfflineRender {
+    pub session_path: PathBuf,
+    /// Where to write frames, a new capture directory if not given
+    pub directory: Option<PathBuf>,
+    frame_interval: Duration,
+    time: Duration,
+}
+
+impl OfflineRender {
+    /// The render asked for by `meme_machine --render <session file> [<directory> [<fps>]]`,
+    /// None without a session file
+    pub fn from_args(args: &[String]) -> Option<OfflineRender> {
+        if args.get(1).map(String::as_str) != Some("--render") {
+            return None;
+        }
+        let session_path = PathBuf::from(args.get(2)?);
+        let fps = match args.get(4).map(|fps| fps.parse::<f64>()) {
+            Some(Ok(fps)) if fps.is_finite() && fps > 0.0 => {
+                if !(MIN_RENDER_FPS..=MAX_RENDER_FPS).contains(&fps) {
+                    eprintln!(
+                        "Frame rate {} is out of range, using {} to {}",
+                        fps, MIN_RENDER_FPS, MAX_RENDER_FPS
+                    );
+                }
+                fps.clamp(MIN_RENDER_FPS, MAX_RENDER_FPS)
+            }
+            Some(_) => {
+                eprintln!("Ignoring frame rate {:?}", args[4]);
+                DEFAULT_RENDER_FPS
+            }
+            None => DEFAULT_RENDER_FPS,
+        };
+
+        Some(OfflineRender {
+            session_path: session_path,
+            directory: args.get(3).map(PathBuf::from),
+            frame_interval: Duration::from_secs_f64(1.0 / fps),
+            time: Duration::from_secs(0),
+        })
+    }
+
+    /// Take over the app clock from `start`
+    pub fn start(&mut self, start: Duration) {
+        self.time = start;
+    }
+
+    /// Move the app clock on by one frame
+    pub fn next_frame(&mut self) {
+        self.time += self.frame_interval;
+    }
+
+    /// The app clock while rendering
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+}
@@ -0,0 +1,101 @@
+use crate::muse_packet::ELECTRODE_NAMES;
+use crate::Model;
+use nannou::prelude::*;
+use std::time::Duration;
+
+const HUD_WIDTH: f32 = 260.0;
+const HUD_MARGIN: f32 = 20.0;
+const HUD_LINE_SPACING: f32 = 22.0;
+const HUD_FONT_SIZE: u32 = 14;
+
+/// Status overlay drawn on top of every view, toggled with H
+pub fn draw_hud(app: &App, model: &Model, draw: &nannou::app::Draw) {
+    let theme = &model.theme;
+    let lines = hud_lines(model);
+    let win = app.window_rect();
+    let height = HUD_LINE_SPACING * (lines.len() + 1) as f32;
+    let x = win.left() + HUD_MARGIN + HUD_WIDTH / 2.0;
+    let top = win.top() - HUD_MARGIN;
+
+    // Cover old text, since not every view clears the background each frame
+    draw.rect()
+        .x_y(x, top - height / 2.0)
+        .w_h(HUD_WIDTH, height)
+        .color(theme.background);
+
+    for (i, line) in lines.iter().enumerate() {
+        theme
+            .text(draw, line)
+            .x_y(x, top - HUD_LINE_SPACING * (i + 1) as f32)
+            .font_size(HUD_FONT_SIZE)
+            .color(theme.foreground);
+    }
+}
+
+fn hud_lines(model: &Model) -> Vec<String> {
+    let now = model.now();
+    let status = &model.stream_status;
+    let mut lines = Vec::new();
+
+    lines.push(format!("Battery: {}%", model.batt));
+    lines.push(match status.sender {
+        Some(sender) => format!("Sender: {}", sender),
+        None => String::from("Sender: none"),
+    });
+    // Headsets join and leave the crowd as they start and stop sending
+    let headsets = model.crowd.participants().len();
+    if headsets > 1 {
+        lines.push(format!("Crowd: {} headsets", headsets));
+    }
+    lines.push(match status.since_last_packet(now) {
+        Some(since) => format!("Last packet: {:.1} s ago", since.as_secs_f32()),
+        None => String::from("No packets received"),
+    });
+
+    let quality: Vec<String> = ELECTRODE_NAMES
+        .iter()
+        .zip(model.horseshoe.iter())
+        .map(|(name, &fit)| format!("{} {}", name, signal_quality(fit)))
+        .collect();
+    lines.push(quality.join(" "));
+
+    for (name, rate) in status.rates().iter() {
+        if status.is_stale(name, now) {
+            lines.push(format!("{}: {}/s stale", name, rate));
+        } else {
+            lines.push(format!("{}: {}/s", name, rate));
+        }
+    }
+
+    lines.push(match model.storage.recording() {
+        Some(session) => format!(
+            "Recording {:.0} s, {} messages",
+            now.checked_sub(session.start_time)
+                .unwrap_or(Duration::from_secs(0))
+                .as_secs_f32(),
+            session.messages.len()
+        ),
+        None => match model.storage.last_session() {
+            Some(session) => format!(
+                "Not recording, last {} messages (R)",
+                session.messages.len()
+            ),
+            None => String::from("Not recording (R)"),
+        },
+    });
+
+    lines
+}
+
+/// Muse horseshoe fit: 1 is good, 2 is medium, 4 is bad and 0 means no data yet
+fn signal_quality(fit: f32) -> &'static str {
+    if fit < 0.5 {
+        "--"
+    } else if fit < 1.5 {
+        "good"
+    } else if fit < 2.5 {
+        "ok"
+    } else {
+        "bad"
+    }
+}
@@ -0,0 +1,154 @@
+use crate::muse_packet::{
+    time_since_epoch, Band, MuseMessage, MuseMessageType, ELECTRODE_NAMES, MOTION_AXES,
+};
+use lsl::{ChannelFormat, ExPushable, StreamInfo, StreamOutlet};
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::time::Duration;
+
+const SOURCE_ID: &str = "meme_machine";
+const EEG_SAMPLE_RATE: f64 = 256.0;
+const MOTION_SAMPLE_RATE: f64 = 52.0;
+const BAND_SAMPLE_RATE: f64 = 10.0;
+
+/// Lab Streaming Layer outlets for raw EEG, band powers, motion and event markers
+pub struct LslOutlets {
+    eeg: StreamOutlet,
+    bands: Vec<StreamOutlet>,
+    accelerometer: StreamOutlet,
+    gyro: StreamOutlet,
+    markers: StreamOutlet,
+    /// LSL `local_clock()` minus the `MuseMessage.time` clock, in seconds
+    clock_offset: f64,
+}
+
+impl Debug for LslOutlets {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<LslOutlets>")
+    }
+}
+
+impl LslOutlets {
+    pub fn new() -> Result<LslOutlets, lsl::Error> {
+        let electrode_labels: Vec<&str> = ELECTRODE_NAMES.iter().cloned().collect();
+        let eeg = outlet(
+            "Muse EEG",
+            "EEG",
+            EEG_SAMPLE_RATE,
+            &electrode_labels,
+            "microvolts",
+        )?;
+
+        let mut bands = Vec::with_capacity(Band::ALL.len());
+        for band in Band::ALL.iter() {
+            bands.push(outlet(
+                &format!("Muse {}", band.name()),
+                &format!("{}Power", band.name()),
+                BAND_SAMPLE_RATE,
+                &electrode_labels,
+                "bels",
+            )?);
+        }
+
+        let accelerometer = outlet(
+            "Muse Accelerometer",
+            "ACC",
+            MOTION_SAMPLE_RATE,
+            &MOTION_AXES,
+            "g",
+        )?;
+        let gyro = outlet("Muse Gyro", "GYRO", MOTION_SAMPLE_RATE, &MOTION_AXES, "dps")?;
+
+        let marker_info = StreamInfo::new(
+            "Meme Machine Markers",
+            "Markers",
+            1,
+            lsl::IRREGULAR_RATE,
+            ChannelFormat::String,
+            &format!("{}_markers", SOURCE_ID),
+        )?;
+        let markers = StreamOutlet::new(&marker_info, 0, 360)?;
+
+        Ok(LslOutlets {
+            eeg: eeg,
+            bands: bands,
+            accelerometer: accelerometer,
+            gyro: gyro,
+            markers: markers,
+            clock_offset: lsl::local_clock() - time_since_epoch().as_secs_f64(),
+        })
+    }
+
+    pub fn push_message(&self, muse_message: &MuseMessage) {
+        let timestamp = self.timestamp(muse_message.time);
+        let values = muse_message.muse_message_type.values();
+
+        let result = match muse_message.muse_message_type {
+            MuseMessageType::Eeg { .. } => self.eeg.push_sample_ex(&values, timestamp, true),
+            MuseMessageType::Accelerometer { .. } => {
+                self.accelerometer.push_sample_ex(&values, timestamp, true)
+            }
+            MuseMessageType::Gyro { .. } => self.gyro.push_sample_ex(&values, timestamp, true),
+            MuseMessageType::Blink { blink: true } => self.push_marker_at("blink", timestamp),
+            MuseMessageType::JawClench { clench: true } => {
+                self.push_marker_at("jaw_clench", timestamp)
+            }
+            ref muse_message_type => match muse_message_type.band() {
+                Some((band, _)) => {
+                    self.bands[band.index()].push_sample_ex(&values, timestamp, true)
+                }
+                None => Ok(()),
+            },
+        };
+
+        if let Err(e) = result {
+            eprintln!("Could not push LSL sample: {:?}", e);
+        }
+    }
+
+    /// Send a text marker on the `MuseMessage.time` clock
+    pub fn push_marker(&self, time: Duration, marker: &str) {
+        if let Err(e) = self.push_marker_at(marker, self.timestamp(time)) {
+            eprintln!("Could not push LSL marker: {:?}", e);
+        }
+    }
+
+    fn push_marker_at(&self, marker: &str, timestamp: f64) -> Result<(), lsl::Error> {
+        self.markers
+            .push_sample_ex(&vec![marker.to_string()], timestamp, true)
+    }
+
+    fn timestamp(&self, time: Duration) -> f64 {
+        time.as_secs_f64() + self.clock_offset
+    }
+}
+
+/// A regular float outlet with labelled channels
+fn outlet(
+    name: &str,
+    stream_type: &str,
+    sample_rate: f64,
+    labels: &[&str],
+    unit: &str,
+) -> Result<StreamOutlet, lsl::Error> {
+    let mut info = StreamInfo::new(
+        name,
+        stream_type,
+        labels.len() as u32,
+        sample_rate,
+        ChannelFormat::Float32,
+        &format!("{}_{}", SOURCE_ID, stream_type.to_lowercase()),
+    )?;
+
+    let mut channels = info.desc().append_child("channels");
+    for label in labels.iter() {
+        channels
+            .append_child("channel")
+            .append_child_value("label", label)
+            .append_child_value("unit", unit)
+            .append_child_value("type", stream_type);
+    }
+
+    StreamOutlet::new(&info, 0, 360)
+}
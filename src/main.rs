@@ -17,18 +17,70 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod band_history;
+mod crowd;
+mod derived_metrics;
+mod erp;
+mod frame_capture;
+mod hud;
+#[cfg(feature = "lsl")]
+mod lsl_outlet;
 mod muse_packet;
 mod muse_storage;
+mod osc_relay;
+mod protocol;
+mod rating;
+mod reactions;
+mod replay;
+mod sonification;
+mod stimulus;
+mod stream_status;
+mod synchrony;
+mod theme;
 mod view_circles;
+mod view_crowd;
+mod view_erp;
+mod view_history;
+mod view_leaderboard;
+mod view_neurofeedback;
+mod view_synchrony;
+mod view_topomap;
+mod visualization;
+mod websocket_server;
 
 extern crate meme_machine_database;
 
+use crate::band_history::BandHistory;
+use crate::crowd::Crowd;
+use crate::derived_metrics::DerivedMetrics;
+use crate::erp::ErpAverager;
+use crate::frame_capture::{FrameCapture, OfflineRender, RENDER_USAGE};
+#[cfg(feature = "lsl")]
+use crate::lsl_outlet::LslOutlets;
 use crate::muse_packet::*;
+use crate::muse_storage::edf::{read_edf, write_edf, EdfHeaderInfo};
+use crate::muse_storage::session_file::{recover_all, SessionFileReader, LOAD_SESSION_ENV};
+use crate::muse_storage::xdf::write_xdf;
+use crate::muse_storage::{MuseSession, MuseStorage, SessionEvent};
+use crate::osc_relay::OscRelay;
+use crate::protocol::{ProtocolAction, ProtocolRunner, PROTOCOL_ENV};
+use crate::rating::RatingScreen;
+use crate::reactions::{score_session, write_reactions_csv, StimulusReaction};
+use crate::replay::Replay;
+use crate::sonification::{write_session_wav, Sonifier};
+use crate::stimulus::{StimulusPresenter, STIMULUS_DIR_ENV};
+use crate::stream_status::{ConnectionChange, StreamStatus};
+use crate::synchrony::{write_synchrony_csv, SynchronySample, SynchronyTracker};
+use crate::theme::{Theme, ThemeList};
+use crate::visualization::{ViewContext, VisualizationRegistry};
+use crate::websocket_server::WebSocketServer;
 use nannou::prelude::*;
 use nannou_osc as osc;
+use std::env;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::time::Duration;
@@ -41,8 +93,50 @@ const FOREHEAD_COUNTDOWN: i32 = 30;
 const BLINK_COUNTDOWN: i32 = 30;
 const CLENCH_COUNTDOWN: i32 = 30;
 
+/// Fraction of a stale value kept each frame while it fades out
+const STALE_DECAY: f32 = 0.95;
+
 fn main() {
-    nannou::app(model).update(update).run();
+    // `meme_machine --sonify <session file> [<wav file>]` renders a recording without a window
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--sonify") {
+        sonify_session_file(&args[2..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("--render") && args.len() < 3 {
+        eprintln!("{}", RENDER_USAGE);
+        return;
+    }
+
+    nannou::app(model).update(update).exit(exit).run();
+}
+
+/// Write the sound of a session file to a WAV file, next to it unless a path is given
+fn sonify_session_file(args: &[String]) {
+    let session_path = match args.first() {
+        Some(path) => Path::new(path),
+        None => {
+            eprintln!("Usage: meme_machine --sonify <session file> [<wav file>]");
+            return;
+        }
+    };
+    let wav_path = match args.get(1) {
+        Some(path) => PathBuf::from(path),
+        None => session_path.with_extension("wav"),
+    };
+
+    let loaded = SessionFileReader::open(session_path).and_then(|mut reader| reader.read_session());
+    let session = match loaded {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", session_path.display(), e);
+            return;
+        }
+    };
+    match write_session_wav(&wav_path, &session) {
+        Ok(()) => println!("Wrote {}", wav_path.display()),
+        Err(e) => eprintln!("Could not write {}: {}", wav_path.display(), e),
+    }
 }
 
 struct ReceiverDebug {
@@ -55,18 +149,12 @@ impl Debug for ReceiverDebug {
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum DisplayType {
-    FourCircles,
-    Dowsiness,
-    Emotion,
-}
-
 #[derive(Debug)]
 pub struct Model {
     message_receive_time: Duration,
     tx_eeg: Sender<(Duration, MuseMessageType)>,
-    rx_eeg: Receiver<(Duration, MuseMessageType)>,
+    storage: MuseStorage,
+    stream_status: StreamStatus,
     receiver: ReceiverDebug,
     clicked: bool,
     clear_background: bool,
@@ -77,6 +165,31 @@ pub struct Model {
     gamma: [f32; 4],
     delta: [f32; 4],
     theta: [f32; 4],
+    history: BandHistory,
+    /// Every headset sending, for group views
+    crowd: Crowd,
+    /// Synchrony between two of the headsets
+    synchrony: SynchronyTracker,
+    erp: ErpAverager,
+    metrics: DerivedMetrics,
+    osc_relay: Option<OscRelay>,
+    websocket_server: Option<WebSocketServer>,
+    replay: Option<Replay>,
+    stimuli: Option<StimulusPresenter>,
+    /// True if the recording was started by the meme presentation and ends with it
+    recording_presentation: bool,
+    protocol: Option<ProtocolRunner>,
+    sonifier: Option<Sonifier>,
+    /// Frames being saved as PNGs
+    capture: Option<FrameCapture>,
+    /// Set while rendering a session file offline with `--render`
+    render: Option<OfflineRender>,
+    /// Self-report scales waiting to be answered
+    rating: Option<RatingScreen>,
+    /// Reactions to the memes in the last session, strongest first
+    reactions: Vec<StimulusReaction>,
+    #[cfg(feature = "lsl")]
+    lsl_outlets: Option<LslOutlets>,
     batt: i32,
     horseshoe: [f32; 4],
     blink_countdown: i32,
@@ -84,7 +197,43 @@ pub struct Model {
     jaw_clench_countdown: i32,
     scale: f32,
     full_screen: bool,
-    display_type: DisplayType,
+    show_hud: bool,
+    theme: Theme,
+    /// Themes Y switches between
+    themes: ThemeList,
+    visualizations: VisualizationRegistry,
+}
+
+impl Model {
+    /// Current time on the same clock as `MuseMessage.time`. An offline render steps it one
+    /// frame per update instead of following the system clock.
+    pub fn now(&self) -> Duration {
+        match self.render {
+            Some(ref render) => render.time(),
+            None => time_since_epoch(),
+        }
+    }
+
+    /// Latest per-electrode values of one band
+    pub fn band_values(&self, band: Band) -> [f32; 4] {
+        match band {
+            Band::Alpha => self.alpha,
+            Band::Beta => self.beta,
+            Band::Gamma => self.gamma,
+            Band::Delta => self.delta,
+            Band::Theta => self.theta,
+        }
+    }
+
+    fn band_values_mut(&mut self, band: Band) -> &mut [f32; 4] {
+        match band {
+            Band::Alpha => &mut self.alpha,
+            Band::Beta => &mut self.beta,
+            Band::Gamma => &mut self.gamma,
+            Band::Delta => &mut self.delta,
+            Band::Theta => &mut self.theta,
+        }
+    }
 }
 
 fn model(app: &App) -> Model {
@@ -95,6 +244,7 @@ fn model(app: &App) -> Model {
         .view(view_circles::view)
         .mouse_pressed(mouse_pressed)
         .mouse_released(mouse_released)
+        .mouse_moved(mouse_moved)
         .key_pressed(key_pressed)
         .key_released(key_released)
         .build()
@@ -111,10 +261,39 @@ fn model(app: &App) -> Model {
 
     let receiver_debug = ReceiverDebug { receiver: receiver };
 
-    Model {
+    // Session files left unfinished by a crash are made readable again
+    match recover_all(Path::new(".")) {
+        Ok(recovered) => {
+            for (path, blocks) in recovered.iter() {
+                println!("Recovered {} blocks of {}", blocks, path.display());
+            }
+        }
+        Err(e) => eprintln!("Could not check for unfinished session files: {}", e),
+    }
+
+    let mut storage = MuseStorage::new(rx_eeg);
+    load_session_from_env(&mut storage);
+    let reactions = storage
+        .last_session()
+        .map_or_else(Vec::new, score_reactions);
+
+    let mut visualizations = VisualizationRegistry::new();
+    view_circles::register_visualizations(&mut visualizations);
+    let args: Vec<String> = env::args().collect();
+    let render = OfflineRender::from_args(&args);
+    // An offline render starts the sound at the session's start, once its clock is set
+    let sonifier = match render {
+        Some(_) => None,
+        None => Sonifier::from_env(time_since_epoch()),
+    };
+    let mut themes = ThemeList::from_env();
+    let theme = themes.current(app);
+
+    let mut model = Model {
         message_receive_time: Duration::from_secs(0),
         tx_eeg: tx_eeg,
-        rx_eeg: rx_eeg,
+        storage: storage,
+        stream_status: StreamStatus::from_env(),
         receiver: receiver_debug,
         clicked: false,
         clear_background: false,
@@ -125,6 +304,30 @@ fn model(app: &App) -> Model {
         gamma: [0.0, 0.0, 0.0, 0.0], // 30-44Hz
         delta: [0.0, 0.0, 0.0, 0.0], // 1-4Hz
         theta: [0.0, 0.0, 0.0, 0.0], // 4-8Hz
+        history: BandHistory::new(),
+        crowd: Crowd::new(),
+        synchrony: SynchronyTracker::from_env(),
+        erp: ErpAverager::from_env(),
+        metrics: DerivedMetrics::new(),
+        osc_relay: OscRelay::from_env(),
+        websocket_server: WebSocketServer::from_env(),
+        replay: None,
+        stimuli: StimulusPresenter::from_env(),
+        recording_presentation: false,
+        protocol: ProtocolRunner::from_env(),
+        sonifier: sonifier,
+        capture: None,
+        render: render,
+        rating: None,
+        reactions: reactions,
+        #[cfg(feature = "lsl")]
+        lsl_outlets: match LslOutlets::new() {
+            Ok(lsl_outlets) => Some(lsl_outlets),
+            Err(e) => {
+                eprintln!("Could not create LSL outlets: {:?}", e);
+                None
+            }
+        },
         batt: 0,
         horseshoe: [0.0, 0.0, 0.0, 0.0],
         blink_countdown: 0,
@@ -132,7 +335,96 @@ fn model(app: &App) -> Model {
         jaw_clench_countdown: 0,
         scale: 2.5,
         full_screen: false,
-        display_type: DisplayType::FourCircles,
+        show_hud: true,
+        theme: theme,
+        themes: themes,
+        visualizations: visualizations,
+    };
+    start_offline_render(app, &mut model);
+
+    model
+}
+
+/// Replay the session file given to `--render`, capturing every frame
+fn start_offline_render(app: &App, model: &mut Model) {
+    let render = match model.render.as_mut() {
+        Some(render) => render,
+        None => return,
+    };
+
+    let replay = match Replay::open(&render.session_path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", render.session_path.display(), e);
+            model.render = None;
+            app.quit();
+            return;
+        }
+    };
+    let capture = match render.directory.clone() {
+        Some(directory) => FrameCapture::new(directory, app.elapsed_frames()),
+        None => FrameCapture::start(replay.start_time(), app.elapsed_frames()),
+    };
+    let capture = match capture {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("Could not create a directory for the frames: {}", e);
+            model.render = None;
+            app.quit();
+            return;
+        }
+    };
+
+    println!(
+        "Rendering {} to {}",
+        render.session_path.display(),
+        capture.directory().display()
+    );
+    render.start(replay.start_time());
+    model.sonifier = Sonifier::from_env(replay.start_time());
+    model.capture = Some(capture);
+    model.replay = Some(replay);
+}
+
+/// Stop an offline render once its replay has played to the end. Pausing it with K holds
+/// the render rather than ending it.
+fn finish_offline_render(app: &App, model: &mut Model) {
+    let finished = model
+        .replay
+        .as_ref()
+        .map_or(true, |replay| replay.position() >= replay.duration());
+    if model.render.is_none() || !finished {
+        return;
+    }
+
+    if let Some(capture) = model.capture.take() {
+        println!(
+            "Rendered {} frames to {}",
+            capture.frames(app),
+            capture.directory().display()
+        );
+    }
+    model.render = None;
+    app.quit();
+}
+
+/// Start or stop saving every frame as a PNG
+fn toggle_capture(app: &App, model: &mut Model) {
+    if let Some(capture) = model.capture.take() {
+        println!(
+            "Captured {} frames to {}",
+            capture.frames(app),
+            capture.directory().display()
+        );
+        return;
+    }
+
+    match FrameCapture::start(model.now(), app.elapsed_frames()) {
+        Ok(capture) => {
+            println!("Capturing frames to {}", capture.directory().display());
+            model.capture = Some(capture);
+        }
+        Err(e) => eprintln!("Could not start capturing frames: {}", e),
     }
 }
 
@@ -144,22 +436,448 @@ fn is_full_screen_capable_platform() -> bool {
     !cfg!(target_os = "macos")
 }
 
-fn mouse_pressed(_app: &App, model: &mut Model, _button: MouseButton) {
+fn mouse_pressed(app: &App, model: &mut Model, _button: MouseButton) {
     model.clicked = true;
+
+    let position = app.mouse.position();
+    if let Some(rating) = model.rating.as_ref() {
+        if let Some(value) = rating.value_at(position) {
+            answer_rating(model, value);
+        }
+        return;
+    }
+    if let Some(replay) = model.replay.as_mut() {
+        if replay.is_on_timeline(app, position) {
+            replay.scrubbing = true;
+            seek_replay(app, model, position.x);
+        }
+    }
 }
 
 fn mouse_released(_app: &App, model: &mut Model, _button: MouseButton) {
     model.clicked = false;
+
+    if let Some(replay) = model.replay.as_mut() {
+        replay.scrubbing = false;
+    }
 }
 
-fn key_pressed(_app: &App, model: &mut Model, key: Key) {
+fn mouse_moved(app: &App, model: &mut Model, position: Point2) {
+    let scrubbing = model
+        .replay
+        .as_ref()
+        .map_or(false, |replay| replay.scrubbing);
+    if scrubbing && model.clicked {
+        seek_replay(app, model, position.x);
+    }
+}
+
+/// Start replaying the last session, or go back to live data
+fn toggle_replay(model: &mut Model) {
+    if model.replay.take().is_some() {
+        // The replayed session's headset is not necessarily the one sending now
+        model.stream_status.sender = None;
+        println!("Stopped replay");
+        return;
+    }
+    if model.storage.is_recording() {
+        eprintln!("Stop recording with R before replaying");
+        return;
+    }
+
+    let replay = match (
+        model.storage.last_session_path(),
+        model.storage.last_session(),
+    ) {
+        (Some(path), _) => match Replay::open(path) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Could not replay {}: {}", path.display(), e);
+                return;
+            }
+        },
+        (None, Some(session)) => Replay::from_session(session.clone()),
+        (None, None) => {
+            eprintln!(
+                "Nothing to replay, record a session with R or set {}",
+                LOAD_SESSION_ENV
+            );
+            return;
+        }
+    };
+    model.history = BandHistory::new();
+    model.erp.reset_stream();
+    model.replay = Some(replay);
+}
+
+/// Start presenting the memes, or stop early
+fn toggle_presentation(model: &mut Model) {
+    let now = model.now();
+    if is_presenting(model) {
+        stop_presentation(model, now);
+        println!("Stopped presentation");
+    } else {
+        start_presentation(model, now, None);
+    }
+}
+
+/// True while a headset is connected or a session is replaying
+fn receiving(model: &Model) -> bool {
+    model.replay.is_some() || model.stream_status.is_connected()
+}
+
+fn is_presenting(model: &Model) -> bool {
+    model
+        .stimuli
+        .as_ref()
+        .map_or(false, |stimuli| stimuli.is_presenting())
+}
+
+/// Present all memes or the first `count`, recording a session if none is being recorded
+fn start_presentation(model: &mut Model, now: Duration, count: Option<usize>) {
+    match model.stimuli.as_mut() {
+        Some(stimuli) => stimuli.start(now, count),
+        None => {
+            eprintln!(
+                "Nothing to present, set {} to a folder of images",
+                STIMULUS_DIR_ENV
+            );
+            return;
+        }
+    }
+
+    if !model.storage.is_recording() && start_recording(model, now) {
+        model.recording_presentation = true;
+    }
+}
+
+/// End a presentation early, marking the offset of an image still on screen
+fn stop_presentation(model: &mut Model, now: Duration) {
+    let markers = match model.stimuli.as_mut() {
+        Some(stimuli) => stimuli.stop(),
+        None => Vec::new(),
+    };
+    model.rating = None;
+    for label in markers {
+        add_marker(model, now, label);
+    }
+}
+
+/// Start the protocol, or move it on to the next step or question
+fn advance_protocol(model: &mut Model) {
+    let now = model.now();
+    let actions = match model.protocol.as_mut() {
+        Some(protocol) if protocol.is_running() => protocol.advance(now),
+        Some(protocol) => protocol.start(now),
+        None => {
+            eprintln!(
+                "No protocol to run, set {} to a protocol file",
+                PROTOCOL_ENV
+            );
+            return;
+        }
+    };
+    apply_protocol_actions(model, now, actions);
+}
+
+/// Carry out what a protocol step asked for
+fn apply_protocol_actions(model: &mut Model, now: Duration, actions: Vec<ProtocolAction>) {
+    for action in actions {
+        match action {
+            ProtocolAction::Marker(label) => add_marker(model, now, label),
+            ProtocolAction::SelectView(name) => {
+                if !model.visualizations.select_by_name(&name) {
+                    eprintln!("Protocol asked for unknown view {:?}", name);
+                }
+            }
+            ProtocolAction::StartRecording => {
+                if !model.storage.is_recording() {
+                    start_recording(model, now);
+                }
+            }
+            ProtocolAction::StopRecording => {
+                if model.storage.is_recording() {
+                    stop_recording(model);
+                }
+            }
+            ProtocolAction::PresentStimuli(count) => start_presentation(model, now, count),
+            ProtocolAction::StopStimuli => stop_presentation(model, now),
+            ProtocolAction::ShowRatings(scales) => {
+                model.rating = Some(RatingScreen::new(scales, None))
+            }
+            ProtocolAction::CloseRatings => model.rating = None,
+        }
+    }
+}
+
+/// Store the answer to the current rating scale, closing the screen after the last one
+fn answer_rating(model: &mut Model, value: u8) {
+    let event = match model.rating.as_mut() {
+        Some(rating) => rating.answer(value),
+        None => return,
+    };
+    if let Some(event) = event {
+        let now = model.now();
+        model.history.push_marker(now, event.label());
+        record_event(model, now, event);
+    }
+
+    let finished = model
+        .rating
+        .as_ref()
+        .map_or(false, RatingScreen::is_finished);
+    if finished {
+        model.rating = None;
+    }
+}
+
+/// Jump the replay to the timeline position under x
+fn seek_replay(app: &App, model: &mut Model, x: f32) {
+    if let Some(replay) = model.replay.as_mut() {
+        let position = replay.position_at(app, x);
+        replay.seek(position);
+
+        // Graphs restart from the new position rather than joining it to the old one
+        model.history = BandHistory::new();
+        model.erp.reset_stream();
+    }
+}
+
+fn key_pressed(app: &App, model: &mut Model, key: Key) {
+    // Number keys answer an open rating screen instead of adding markers
+    if model.rating.is_some() {
+        if let Some(value) = marker_key(key).and_then(|label| label.parse().ok()) {
+            answer_rating(model, value);
+            return;
+        }
+    }
+
     match key {
         Key::Space => model.clear_background = !model.clear_background,
         Key::F => model.full_screen = !model.full_screen,
-        Key::F1 => model.display_type = DisplayType::FourCircles,
-        Key::F2 => model.display_type = DisplayType::Dowsiness,
-        Key::F3 => model.display_type = DisplayType::Emotion,
-        _ => (),
+        Key::Tab => model.visualizations.next(),
+        Key::L => model.visualizations.show_list = !model.visualizations.show_list,
+        Key::H => model.show_hud = !model.show_hud,
+        Key::R => {
+            if model.storage.is_recording() {
+                stop_recording(model);
+            } else {
+                let now = model.now();
+                start_recording(model, now);
+            }
+        }
+        Key::E => export_last_session(model),
+        Key::P => toggle_replay(model),
+        Key::M => toggle_presentation(model),
+        Key::C => model.erp.clear(),
+        Key::V => toggle_capture(app, model),
+        Key::Y => {
+            model.theme = model.themes.next(app);
+            println!("Theme {}", model.theme.name);
+        }
+        Key::Return => advance_protocol(model),
+        Key::Back => {
+            if let Some(protocol) = model.protocol.as_mut() {
+                let actions = protocol.stop();
+                let now = model.now();
+                apply_protocol_actions(model, now, actions);
+            }
+        }
+        _ => match marker_key(key) {
+            Some(label) => {
+                let now = model.now();
+                add_marker(model, now, String::from(label));
+            }
+            None => match model.visualizations.function_key_index(key) {
+                Some(index) => model.visualizations.select(index),
+                None => {
+                    let replay_key = match model.replay.as_mut() {
+                        Some(replay) => replay.key_pressed(key),
+                        None => false,
+                    };
+                    if !replay_key {
+                        let context = ViewContext {
+                            metrics: &model.metrics,
+                            receiving: receiving(model),
+                            now: model.now(),
+                        };
+                        model.visualizations.key_pressed(&context, key);
+                    }
+                }
+            },
+        },
+    }
+}
+
+/// The marker label for a number key on the main row or keypad
+fn marker_key(key: Key) -> Option<&'static str> {
+    match key {
+        Key::Key0 | Key::Numpad0 => Some("0"),
+        Key::Key1 | Key::Numpad1 => Some("1"),
+        Key::Key2 | Key::Numpad2 => Some("2"),
+        Key::Key3 | Key::Numpad3 => Some("3"),
+        Key::Key4 | Key::Numpad4 => Some("4"),
+        Key::Key5 | Key::Numpad5 => Some("5"),
+        Key::Key6 | Key::Numpad6 => Some("6"),
+        Key::Key7 | Key::Numpad7 => Some("7"),
+        Key::Key8 | Key::Numpad8 => Some("8"),
+        Key::Key9 | Key::Numpad9 => Some("9"),
+        _ => None,
+    }
+}
+
+/// Start recording unless a replay is running, since that would record the replayed data
+fn start_recording(model: &mut Model, now: Duration) -> bool {
+    if model.replay.is_some() {
+        eprintln!("Not recording during a replay, press P to go back to live data first");
+        return false;
+    }
+    model
+        .storage
+        .start_recording(now, model.stream_status.sender);
+    model.synchrony.start_recording(now);
+
+    true
+}
+
+/// Finish the recording, then score the memes shown during it
+fn stop_recording(model: &mut Model) {
+    model.storage.stop_recording();
+    // A recording started later is not the presentation's to stop
+    model.recording_presentation = false;
+    if let Some(session) = model.storage.last_session() {
+        model.reactions = score_reactions(session);
+        export_synchrony(session, &model.synchrony);
+    }
+    model.synchrony.stop_recording();
+}
+
+/// Write the synchrony measured during a session next to the session file
+fn export_synchrony(session: &MuseSession, synchrony: &SynchronyTracker) {
+    let (start, end) = (session.start_time, session.end_time());
+    let samples: Vec<SynchronySample> = synchrony
+        .series()
+        .iter()
+        .filter(|sample| sample.time >= start && sample.time <= end)
+        .cloned()
+        .collect();
+    if samples.is_empty() {
+        return;
+    }
+
+    let path = session.file_name("synchrony.csv");
+    match write_synchrony_csv(&path, &samples) {
+        Ok(()) => println!(
+            "Wrote {} synchrony samples to {}",
+            samples.len(),
+            path.display()
+        ),
+        Err(e) => eprintln!("Could not write {}: {}", path.display(), e),
+    }
+}
+
+/// Per-meme reactions in a session, also written to CSV next to the session file
+fn score_reactions(session: &MuseSession) -> Vec<StimulusReaction> {
+    let reactions = score_session(session);
+    if reactions.is_empty() {
+        return reactions;
+    }
+
+    let path = session.file_name("reactions.csv");
+    match write_reactions_csv(&path, &reactions) {
+        Ok(()) => println!(
+            "Scored {} memes, strongest {}, in {}",
+            reactions.len(),
+            reactions[0].name,
+            path.display()
+        ),
+        Err(e) => eprintln!("Could not write {}: {}", path.display(), e),
+    }
+
+    reactions
+}
+
+/// Load the session file in `MEME_MACHINE_LOAD_SESSION` for export and replay
+fn load_session_from_env(storage: &mut MuseStorage) {
+    let path = match env::var(LOAD_SESSION_ENV) {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return,
+    };
+
+    let loaded = SessionFileReader::open(&path).and_then(|mut reader| {
+        let session = reader.read_session()?;
+        Ok((session, reader.header.device))
+    });
+    match loaded {
+        Ok((session, device)) => {
+            println!(
+                "Loaded {} recorded from {} with {} messages and {} events",
+                path.display(),
+                device.as_deref().unwrap_or("-"),
+                session.messages.len(),
+                session.events.len()
+            );
+            storage.set_last_session(session, path);
+        }
+        Err(e) => eprintln!("Could not load {}: {}", path.display(), e),
+    }
+}
+
+/// Write the last finished recording as EDF+ and XDF into the working directory
+fn export_last_session(model: &Model) {
+    let session = match model.storage.last_session() {
+        Some(session) => session,
+        None => {
+            eprintln!("Nothing to export, press R to start and stop a recording first");
+            return;
+        }
+    };
+
+    let xdf_path = session.file_name("xdf");
+    match write_xdf(&xdf_path, session) {
+        Ok(()) => println!("Exported {}", xdf_path.display()),
+        Err(e) => eprintln!("Could not export {}: {}", xdf_path.display(), e),
+    }
+
+    let path = session.file_name("edf");
+    let mut info = EdfHeaderInfo::default();
+    if let Some(device) = session.device {
+        info.equipment = format!("Muse {}", device);
+    }
+    if let Err(e) = write_edf(&path, session, &info) {
+        eprintln!("Could not export {}: {}", path.display(), e);
+        return;
+    }
+
+    // Read the file back so a broken export is noticed while the session is still in memory
+    match read_edf(&path) {
+        Ok(edf) => {
+            let (seconds, unit) = match edf.signals.first() {
+                Some(signal) => (
+                    signal.samples.len() as f64 / signal.samples_per_record as f64
+                        * edf.record_duration,
+                    signal.physical_dimension.as_str(),
+                ),
+                None => (0.0, ""),
+            };
+            println!("Exported {}", path.display());
+            println!(
+                "  {} | {} | {} {} +{:.3} s",
+                edf.patient_id, edf.recording_id, edf.start_date, edf.start_time, edf.start_offset
+            );
+            println!(
+                "  {} signals of {:.0} s in {}, {} annotations",
+                edf.signals.len(),
+                seconds,
+                unit,
+                edf.annotations.len()
+            );
+        }
+        Err(e) => eprintln!(
+            "Exported {} but could not read it back: {}",
+            path.display(),
+            e
+        ),
     }
 }
 
@@ -169,11 +887,27 @@ fn key_released(_app: &App, _model: &mut Model, key: Key) {
     }
 }
 
+/// Complete the sound file, which is otherwise left with the length of its last flush
+fn exit(_app: &App, model: Model) {
+    let now = model.now();
+    if let Some(mut sonifier) = model.sonifier {
+        sonifier.render_until(now);
+        if let Err(e) = sonifier.finish() {
+            eprintln!("Could not finish the sound file: {}", e);
+        }
+    }
+}
+
 fn update(app: &App, model: &mut Model, _update: Update) {
     //     cls();
     //     println!("update: model: alpha: {:#?}", model.alpha);
     let mut received_packets = Vec::new();
 
+    // Offline rendering moves time on by exactly one frame per update
+    if let Some(render) = model.render.as_mut() {
+        render.next_frame();
+    }
+
     // println!("Full screen capable: {}", is_full_screen_capable_platform());
 
     if is_full_screen_capable_platform() && !model.full_screen {
@@ -182,19 +916,135 @@ fn update(app: &App, model: &mut Model, _update: Update) {
         app.main_window().set_fullscreen(Some(monitor));
     }
 
-    // Receive any pending osc packets.
-    for (packet, addr) in model.receiver.receiver.try_iter() {
-        received_packets.push((addr, packet));
+    // Receive any pending osc packets. An offline render shows only its session file, so live
+    // packets are neither handled nor forwarded while it runs.
+    if model.render.is_none() {
+        for (packet, addr) in model.receiver.receiver.try_iter() {
+            received_packets.push((addr, packet));
+        }
     }
 
-    for &(addr, ref packet) in received_packets.iter().rev() {
+    // In arrival order, so raw EEG samples stay in sequence
+    for &(addr, ref packet) in received_packets.iter() {
+        if let Some(osc_relay) = model.osc_relay.as_ref() {
+            osc_relay.forward(packet);
+        }
+
+        // Live messages would mix with the replayed ones
+        if model.replay.is_some() {
+            continue;
+        }
+
         let muse_messages = parse_muse_packet(addr, packet);
 
         for muse_message in muse_messages {
             handle_message(&muse_message, model);
         }
+
+        for label in parse_marker_packet(packet) {
+            let now = model.now();
+            add_marker(model, now, label);
+        }
     }
 
+    let now = model.now();
+    let (replayed, replayed_markers) = match model.replay.as_mut() {
+        Some(replay) => replay.advance(now),
+        None => (Vec::new(), Vec::new()),
+    };
+    for muse_message in replayed.iter() {
+        handle_message(muse_message, model);
+    }
+    for (time, label) in replayed_markers {
+        add_marker(model, time, label);
+    }
+    model.erp.end_frame();
+    finish_offline_render(app, model);
+
+    let rating_open = model.rating.is_some();
+    let stimulus_markers = match model.stimuli.as_mut() {
+        Some(stimuli) => {
+            let was_rating = stimuli.rating_screen().is_some();
+            let markers = stimuli.update(app, now, rating_open);
+            if !was_rating {
+                if let Some(rating) = stimuli.rating_screen() {
+                    model.rating = Some(rating);
+                }
+            }
+            markers
+        }
+        None => Vec::new(),
+    };
+    for label in stimulus_markers {
+        add_marker(model, now, label);
+    }
+    let presenting = is_presenting(model);
+    let rating_open = model.rating.is_some();
+    let protocol_actions = match model.protocol.as_mut() {
+        Some(protocol) => protocol.update(now, presenting, rating_open),
+        None => Vec::new(),
+    };
+    apply_protocol_actions(model, now, protocol_actions);
+
+    if model.recording_presentation && !is_presenting(model) {
+        stop_recording(model);
+    }
+
+    if let Some(sonifier) = model.sonifier.as_mut() {
+        sonifier.render_until(now);
+    }
+
+    model.storage.receive();
+    model.storage.tick(now);
+    model.stream_status.tick(now);
+    model.crowd.tick(now);
+    // Values computed from a replay are not sent out as if they were live
+    let live = model.replay.is_none();
+    if let Some(sample) = model.synchrony.update(now, &model.crowd) {
+        if live {
+            if let Some(osc_relay) = model.osc_relay.as_ref() {
+                osc_relay.send_synchrony(sample);
+            }
+            if let Some(websocket_server) = model.websocket_server.as_ref() {
+                websocket_server.publish_synchrony(sample);
+            }
+        }
+    }
+
+    // A paused replay holds its values instead of going stale
+    if model.replay.is_none() {
+        match model.stream_status.check_connection(now) {
+            Some(ConnectionChange::Connected) => {
+                let sender = model.stream_status.sender;
+                record_event(model, now, SessionEvent::Connected { sender: sender });
+            }
+            Some(ConnectionChange::Disconnected) => {
+                record_event(model, now, SessionEvent::Disconnected);
+            }
+            None => (),
+        }
+        // A silent headset is let go outside recordings, so the next one to send is followed
+        if !model.stream_status.is_connected() && !model.storage.is_recording() {
+            model.stream_status.sender = None;
+        }
+        decay_stale_values(model, now);
+    }
+    model.metrics = model.metrics.updated(model);
+    if live {
+        if let Some(osc_relay) = model.osc_relay.as_ref() {
+            osc_relay.send_metrics(&model.metrics);
+        }
+        if let Some(websocket_server) = model.websocket_server.as_ref() {
+            websocket_server.publish_metrics(now, &model.metrics);
+        }
+    }
+    let context = ViewContext {
+        metrics: &model.metrics,
+        receiving: receiving(model),
+        now: now,
+    };
+    model.visualizations.update(app, &context);
+
     if model.blink_countdown > 0 {
         model.blink_countdown = model.blink_countdown - 1;
     }
@@ -206,7 +1056,83 @@ fn update(app: &App, model: &mut Model, _update: Update) {
     }
 }
 
+/// Annotate the session at `time`, on the same clock as `MuseMessage.time`
+fn add_marker(model: &mut Model, time: Duration, label: String) {
+    model.erp.push_marker(time, &label);
+    model.history.push_marker(time, label.clone());
+    if let Some(sonifier) = model.sonifier.as_mut() {
+        sonifier.marker(time);
+    }
+    record_event(model, time, SessionEvent::Marker { label: label });
+}
+
+/// Store a session event and pass it on to anything streaming markers
+fn record_event(model: &mut Model, time: Duration, event: SessionEvent) {
+    #[cfg(feature = "lsl")]
+    {
+        if let Some(lsl_outlets) = model.lsl_outlets.as_ref() {
+            if model.replay.is_none() {
+                lsl_outlets.push_marker(time, &event.label());
+            }
+        }
+    }
+    model.storage.record_event(time, event);
+}
+
+/// Fade out band values that are no longer arriving and clear stale sensor readings
+fn decay_stale_values(model: &mut Model, now: Duration) {
+    for band in Band::ALL.iter() {
+        if model.stream_status.is_stale(band.stream_name(), now) {
+            for value in model.band_values_mut(*band).iter_mut() {
+                *value = *value * STALE_DECAY;
+            }
+        }
+    }
+
+    if model.stream_status.is_stale("horseshoe", now) {
+        model.horseshoe = [0.0, 0.0, 0.0, 0.0];
+    }
+    if model.stream_status.is_stale("acc", now) {
+        model.accelerometer = [0.0, 0.0, 0.0];
+    }
+    if model.stream_status.is_stale("gyro", now) {
+        model.gyro = [0.0, 0.0, 0.0];
+    }
+}
+
 fn handle_message(muse_message: &MuseMessage, model: &mut Model) {
+    // Every headset takes part in the crowd views, and WebSocket clients can filter by sender
+    model.crowd.push(muse_message);
+    model.synchrony.push(muse_message);
+    // Replayed messages only drive the views, live outputs would pass them off as new data
+    if model.replay.is_none() {
+        if let Some(websocket_server) = model.websocket_server.as_ref() {
+            websocket_server.publish_message(muse_message);
+        }
+    }
+
+    // Everything else follows one headset, so several on the port do not mix into one stream
+    if model.replay.is_none() && !model.stream_status.follows(muse_message.ip_address) {
+        return;
+    }
+    model.stream_status.record(muse_message);
+
+    #[cfg(feature = "lsl")]
+    {
+        if model.replay.is_none() {
+            if let Some(lsl_outlets) = model.lsl_outlets.as_ref() {
+                lsl_outlets.push_message(muse_message);
+            }
+        }
+    }
+
+    if let Some((band, values)) = muse_message.muse_message_type.band() {
+        model.history.push(band, muse_message.time, values);
+    }
+    if let Some(sonifier) = model.sonifier.as_mut() {
+        sonifier.handle_message(muse_message.time, &muse_message.muse_message_type);
+    }
+
     match muse_message.muse_message_type {
         MuseMessageType::Accelerometer { x, y, z } => {
             model.accelerometer = [x, y, z];
@@ -244,6 +1170,7 @@ fn handle_message(muse_message: &MuseMessage, model: &mut Model) {
                 .expect("Could not tx Horeshoe");
         }
         MuseMessageType::Eeg { a, b, c, d } => {
+            model.erp.push_eeg(muse_message.time, [a, b, c, d]);
             model
                 .tx_eeg
                 .send((
@@ -21,18 +21,206 @@ pub enum MuseMessageType {
     Gamma { a: f32, b: f32, c: f32, d: f32 }, // microVolts
     Delta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
     Theta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Batt { batt: i32 },
+    Batt { batt: i32 },                       // percent
     Horseshoe { a: f32, b: f32, c: f32, d: f32 },
     TouchingForehead { touch: bool },
     Blink { blink: bool },
     JawClench { clench: bool },
 }
 
+/// Electrode labels in the order the Muse reports them (a, b, c, d)
+pub const ELECTRODE_NAMES: [&str; 4] = ["TP9", "AF7", "AF8", "TP10"];
+
+/// Accelerometer and gyro axis labels (x, y, z)
+pub const MOTION_AXES: [&str; 3] = ["X", "Y", "Z"];
+
+/// OSC address for session markers sent alongside the Muse messages
+pub const MARKER_ADDRESS: &str = "/marker";
+
+/// Longest marker label kept, in characters
+pub const MAX_MARKER_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    Alpha,
+    Beta,
+    Gamma,
+    Delta,
+    Theta,
+}
+
+impl Band {
+    pub const ALL: [Band; 5] = [
+        Band::Alpha,
+        Band::Beta,
+        Band::Gamma,
+        Band::Delta,
+        Band::Theta,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Band::Alpha => "Alpha",
+            Band::Beta => "Beta",
+            Band::Gamma => "Gamma",
+            Band::Delta => "Delta",
+            Band::Theta => "Theta",
+        }
+    }
+
+    /// Name of the band's message stream, as in `MuseMessageType::name`
+    pub fn stream_name(self) -> &'static str {
+        match self {
+            Band::Alpha => "alpha",
+            Band::Beta => "beta",
+            Band::Gamma => "gamma",
+            Band::Delta => "delta",
+            Band::Theta => "theta",
+        }
+    }
+
+    /// Frequencies the Muse band power covers, in Hz
+    pub fn frequency_range(self) -> (f32, f32) {
+        match self {
+            Band::Alpha => (7.5, 13.0),
+            Band::Beta => (13.0, 30.0),
+            Band::Gamma => (30.0, 44.0),
+            Band::Delta => (1.0, 4.0),
+            Band::Theta => (4.0, 8.0),
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Band::Alpha => 0,
+            Band::Beta => 1,
+            Band::Gamma => 2,
+            Band::Delta => 3,
+            Band::Theta => 4,
+        }
+    }
+}
+
+impl MuseMessageType {
+    /// Short name of the message type, used for display and per-type statistics
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MuseMessageType::Eeg { .. } => "eeg",
+            MuseMessageType::Accelerometer { .. } => "acc",
+            MuseMessageType::Gyro { .. } => "gyro",
+            MuseMessageType::Alpha { .. } => "alpha",
+            MuseMessageType::Beta { .. } => "beta",
+            MuseMessageType::Gamma { .. } => "gamma",
+            MuseMessageType::Delta { .. } => "delta",
+            MuseMessageType::Theta { .. } => "theta",
+            MuseMessageType::Batt { .. } => "batt",
+            MuseMessageType::Horseshoe { .. } => "horseshoe",
+            MuseMessageType::TouchingForehead { .. } => "touching_forehead",
+            MuseMessageType::Blink { .. } => "blink",
+            MuseMessageType::JawClench { .. } => "jaw_clench",
+        }
+    }
+
+    /// The message payload as numbers, with booleans as 0.0 or 1.0
+    pub fn values(&self) -> Vec<f32> {
+        match *self {
+            MuseMessageType::Eeg { a, b, c, d }
+            | MuseMessageType::Alpha { a, b, c, d }
+            | MuseMessageType::Beta { a, b, c, d }
+            | MuseMessageType::Gamma { a, b, c, d }
+            | MuseMessageType::Delta { a, b, c, d }
+            | MuseMessageType::Theta { a, b, c, d }
+            | MuseMessageType::Horseshoe { a, b, c, d } => vec![a, b, c, d],
+            MuseMessageType::Accelerometer { x, y, z } | MuseMessageType::Gyro { x, y, z } => {
+                vec![x, y, z]
+            }
+            MuseMessageType::Batt { batt } => vec![batt as f32],
+            MuseMessageType::TouchingForehead { touch: on }
+            | MuseMessageType::Blink { blink: on }
+            | MuseMessageType::JawClench { clench: on } => vec![if on { 1.0 } else { 0.0 }],
+        }
+    }
+
+    /// Rebuild a message from its `name()` and `values()`
+    pub fn from_values(name: &str, values: &[f32]) -> Option<MuseMessageType> {
+        let muse_message_type = match (name, values) {
+            ("eeg", &[a, b, c, d]) => MuseMessageType::Eeg {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("acc", &[x, y, z]) => MuseMessageType::Accelerometer { x: x, y: y, z: z },
+            ("gyro", &[x, y, z]) => MuseMessageType::Gyro { x: x, y: y, z: z },
+            ("alpha", &[a, b, c, d]) => MuseMessageType::Alpha {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("beta", &[a, b, c, d]) => MuseMessageType::Beta {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("gamma", &[a, b, c, d]) => MuseMessageType::Gamma {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("delta", &[a, b, c, d]) => MuseMessageType::Delta {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("theta", &[a, b, c, d]) => MuseMessageType::Theta {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("horseshoe", &[a, b, c, d]) => MuseMessageType::Horseshoe {
+                a: a,
+                b: b,
+                c: c,
+                d: d,
+            },
+            ("batt", &[batt]) => MuseMessageType::Batt { batt: batt as i32 },
+            ("touching_forehead", &[on]) => MuseMessageType::TouchingForehead { touch: on != 0.0 },
+            ("blink", &[on]) => MuseMessageType::Blink { blink: on != 0.0 },
+            ("jaw_clench", &[on]) => MuseMessageType::JawClench { clench: on != 0.0 },
+            _ => return None,
+        };
+
+        Some(muse_message_type)
+    }
+
+    /// The band and per-electrode values if this is a band power message
+    pub fn band(&self) -> Option<(Band, [f32; 4])> {
+        match *self {
+            MuseMessageType::Alpha { a, b, c, d } => Some((Band::Alpha, [a, b, c, d])),
+            MuseMessageType::Beta { a, b, c, d } => Some((Band::Beta, [a, b, c, d])),
+            MuseMessageType::Gamma { a, b, c, d } => Some((Band::Gamma, [a, b, c, d])),
+            MuseMessageType::Delta { a, b, c, d } => Some((Band::Delta, [a, b, c, d])),
+            MuseMessageType::Theta { a, b, c, d } => Some((Band::Theta, [a, b, c, d])),
+            _ => None,
+        }
+    }
+}
+
+/// Current time on the same clock as `MuseMessage.time`
+pub fn time_since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is not set correctly")
+}
+
 pub fn parse_muse_packet(addr: SocketAddr, packet: &nannou_osc::Packet) -> Vec<MuseMessage> {
     let mut raw_messages = Vec::new();
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("System clock is not set correctly");
+    let time = time_since_epoch();
 
     //TODO Add current time as message receive time here
 
@@ -40,6 +228,9 @@ pub fn parse_muse_packet(addr: SocketAddr, packet: &nannou_osc::Packet) -> Vec<M
     let mut muse_messages = Vec::with_capacity(raw_messages.len());
 
     for raw_message in raw_messages {
+        if raw_message.addr == MARKER_ADDRESS {
+            continue;
+        }
         let muse_message_type_option = parse_muse_message_type(raw_message);
         match muse_message_type_option {
             Some(muse_message_type) => {
@@ -57,6 +248,43 @@ pub fn parse_muse_packet(addr: SocketAddr, packet: &nannou_osc::Packet) -> Vec<M
     muse_messages
 }
 
+/// Labels of the `/marker` messages in a packet, sent by stimulus software on the Muse port.
+/// The first argument is the label. Without a usable one the label is "osc".
+pub fn parse_marker_packet(packet: &nannou_osc::Packet) -> Vec<String> {
+    let mut raw_messages = Vec::new();
+    packet.clone().unfold(&mut raw_messages);
+
+    raw_messages
+        .into_iter()
+        .filter(|raw_message| raw_message.addr == MARKER_ADDRESS)
+        .map(|raw_message| {
+            let label = match raw_message.args.as_ref().and_then(|args| args.first()) {
+                Some(OscType::String(value)) => marker_label(value),
+                Some(OscType::Int(value)) => value.to_string(),
+                Some(OscType::Long(value)) => value.to_string(),
+                Some(OscType::Float(value)) => value.to_string(),
+                Some(OscType::Double(value)) => value.to_string(),
+                _ => String::new(),
+            };
+            if label.is_empty() {
+                String::from("osc")
+            } else {
+                label
+            }
+        })
+        .collect()
+}
+
+/// A marker label safe for every export format: no control characters and not too long
+pub fn marker_label(label: &str) -> String {
+    label
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_MARKER_LENGTH)
+        .collect()
+}
+
 fn parse_muse_message_type(raw_message: OscMessage) -> Option<MuseMessageType> {
     let service = raw_message.addr.as_ref();
     let args = raw_message
@@ -67,9 +295,9 @@ fn parse_muse_message_type(raw_message: OscMessage) -> Option<MuseMessageType> {
     let r = match service {
         "/muse/eeg" => {
             let a = get_float_from_args(0, &args);
-            let b = get_float_from_args(0, &args);
-            let c = get_float_from_args(0, &args);
-            let d = get_float_from_args(0, &args);
+            let b = get_float_from_args(1, &args);
+            let c = get_float_from_args(2, &args);
+            let d = get_float_from_args(3, &args);
 
             // println!("EEG: [{:#?}, {:#?}, {:#?}, {:#?}]", a, b, c, d);
 
@@ -146,8 +374,9 @@ fn parse_muse_message_type(raw_message: OscMessage) -> Option<MuseMessageType> {
             Some(MuseMessageType::Blink { blink: blink != 0 })
         }
 
+        // State of charge is sent as percent * 100
         "/muse/batt" => Some(MuseMessageType::Batt {
-            batt: (get_int_from_args(1, &args) as f32 / get_int_from_args(0, &args) as f32) as i32,
+            batt: get_int_from_args(0, &args) / 100,
         }),
 
         "/muse/elements/jaw_clench" => Some(MuseMessageType::JawClench {
@@ -0,0 +1,313 @@
+pub mod edf;
+pub mod session_file;
+pub mod xdf;
+
+use crate::muse_packet::MuseMessageType;
+use session_file::{SessionFileWriter, SESSION_FILE_EXTENSION};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// Something that happened during a session which is not a Muse message
+#[derive(Clone, Debug)]
+pub enum SessionEvent {
+    Connected {
+        sender: Option<SocketAddr>,
+    },
+    Disconnected,
+    /// Annotation from a number key or a `/marker` OSC message
+    Marker {
+        label: String,
+    },
+    /// Answer to a rating scale, about a meme if one was just shown
+    Rating {
+        scale: String,
+        value: u8,
+        stimulus: Option<String>,
+    },
+}
+
+impl SessionEvent {
+    /// Short text describing the event, for markers and annotations
+    pub fn label(&self) -> String {
+        match *self {
+            SessionEvent::Connected {
+                sender: Some(sender),
+            } => format!("connected {}", sender),
+            SessionEvent::Connected { sender: None } => String::from("connected"),
+            SessionEvent::Disconnected => String::from("disconnected"),
+            SessionEvent::Marker { ref label } => format!("marker {}", label),
+            SessionEvent::Rating {
+                ref scale,
+                value,
+                stimulus: Some(ref stimulus),
+            } => format!("rating {} {} {}", scale, value, stimulus),
+            SessionEvent::Rating {
+                ref scale,
+                value,
+                stimulus: None,
+            } => format!("rating {} {}", scale, value),
+        }
+    }
+
+    /// The event a `label()` was made from
+    pub fn from_label(label: &str) -> Option<SessionEvent> {
+        if let Some(marker) = label.strip_prefix("marker ") {
+            return Some(SessionEvent::Marker {
+                label: String::from(marker),
+            });
+        }
+        if let Some(rating) = label.strip_prefix("rating ") {
+            let mut parts = rating.splitn(3, ' ');
+            let scale = parts.next()?;
+            let value = parts.next()?.parse().ok()?;
+            return Some(SessionEvent::Rating {
+                scale: String::from(scale),
+                value: value,
+                stimulus: parts.next().map(String::from),
+            });
+        }
+
+        match label {
+            "connected" => Some(SessionEvent::Connected { sender: None }),
+            "disconnected" => Some(SessionEvent::Disconnected),
+            _ => label
+                .strip_prefix("connected ")
+                .and_then(|sender| sender.parse().ok())
+                .map(|sender| SessionEvent::Connected {
+                    sender: Some(sender),
+                }),
+        }
+    }
+}
+
+/// Messages captured between starting and stopping a recording
+#[derive(Clone, Debug)]
+pub struct MuseSession {
+    pub start_time: Duration,
+    /// The headset the session was recorded from, if known
+    pub device: Option<SocketAddr>,
+    pub messages: Vec<(Duration, MuseMessageType)>,
+    pub events: Vec<(Duration, SessionEvent)>,
+}
+
+impl MuseSession {
+    pub fn new(start_time: Duration) -> MuseSession {
+        MuseSession {
+            start_time: start_time,
+            device: None,
+            messages: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Time of the last message or event, or the start time if there are none
+    pub fn end_time(&self) -> Duration {
+        let last_message = self.messages.last().map(|&(time, _)| time);
+        let last_event = self.events.last().map(|&(time, _)| time);
+
+        last_message
+            .into_iter()
+            .chain(last_event)
+            .fold(self.start_time, Duration::max)
+    }
+
+    /// Blinks, jaw clenches, markers, ratings and session events as labelled times, in time order
+    pub fn annotations(&self) -> Vec<(Duration, String)> {
+        let mut annotations: Vec<(Duration, String)> = self
+            .messages
+            .iter()
+            .filter_map(|&(time, ref muse_message_type)| match *muse_message_type {
+                MuseMessageType::Blink { blink: true } => Some((time, String::from("blink"))),
+                MuseMessageType::JawClench { clench: true } => {
+                    Some((time, String::from("jaw_clench")))
+                }
+                _ => None,
+            })
+            .chain(
+                self.events
+                    .iter()
+                    .map(|&(time, ref event)| (time, event.label())),
+            )
+            .collect();
+        annotations.sort_by_key(|&(time, _)| time);
+
+        annotations
+    }
+
+    /// A file name such as "session_20191204_153012.edf" from the start time
+    pub fn file_name(&self, extension: &str) -> PathBuf {
+        let (year, month, day, hour, minute, second) = civil_time(self.start_time);
+
+        PathBuf::from(format!(
+            "session_{:04}{:02}{:02}_{:02}{:02}{:02}.{}",
+            year, month, day, hour, minute, second, extension
+        ))
+    }
+}
+
+/// Drains the message channel, keeping messages while a recording is active.
+/// Recordings are also appended to a session file as they happen.
+#[derive(Debug)]
+pub struct MuseStorage {
+    rx_eeg: Receiver<(Duration, MuseMessageType)>,
+    recording: Option<MuseSession>,
+    session_file: Option<SessionFileWriter>,
+    last_session: Option<MuseSession>,
+    /// The file `last_session` was written to or loaded from, if it has one
+    last_session_path: Option<PathBuf>,
+}
+
+impl MuseStorage {
+    pub fn new(rx_eeg: Receiver<(Duration, MuseMessageType)>) -> MuseStorage {
+        MuseStorage {
+            rx_eeg: rx_eeg,
+            recording: None,
+            session_file: None,
+            last_session: None,
+            last_session_path: None,
+        }
+    }
+
+    /// Take all pending messages from the channel
+    pub fn receive(&mut self) {
+        for (time, muse_message_type) in self.rx_eeg.try_iter() {
+            if let Some(session) = self.recording.as_mut() {
+                if let Some(writer) = self.session_file.as_mut() {
+                    if let Err(e) = writer.push_message(time, &muse_message_type) {
+                        eprintln!("Stopped writing {}: {}", writer.path.display(), e);
+                        self.session_file = None;
+                    }
+                }
+                session.messages.push((time, muse_message_type));
+            }
+        }
+    }
+
+    /// Write buffered samples of the session file that have waited too long, so a crash
+    /// loses little even when nothing new arrives
+    pub fn tick(&mut self, now: Duration) {
+        if let Some(writer) = self.session_file.as_mut() {
+            if let Err(e) = writer.flush_older_than(now) {
+                eprintln!("Stopped writing {}: {}", writer.path.display(), e);
+                self.session_file = None;
+            }
+        }
+    }
+
+    /// Add an event to the current recording, if there is one
+    pub fn record_event(&mut self, time: Duration, event: SessionEvent) {
+        if let Some(session) = self.recording.as_mut() {
+            if let Some(writer) = self.session_file.as_mut() {
+                match writer.push_event(time, &event) {
+                    Ok(()) => (),
+                    // Only this event is refused, the file is still good
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        eprintln!("Not recording an event: {}", e);
+                        return;
+                    }
+                    Err(e) => {
+                        eprintln!("Stopped writing {}: {}", writer.path.display(), e);
+                        self.session_file = None;
+                    }
+                }
+            }
+            session.events.push((time, event));
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// The session being recorded, if any
+    pub fn recording(&self) -> Option<&MuseSession> {
+        self.recording.as_ref()
+    }
+
+    /// Start a session, and its file unless that cannot be created
+    pub fn start_recording(&mut self, time: Duration, device: Option<SocketAddr>) {
+        if self.recording.is_some() {
+            return;
+        }
+
+        let mut session = MuseSession::new(time);
+        session.device = device;
+        let path = session.file_name(SESSION_FILE_EXTENSION);
+        self.session_file = match SessionFileWriter::create(&path, time, device) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                eprintln!(
+                    "Recording in memory only, could not create {}: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+        self.recording = Some(session);
+    }
+
+    /// Stop recording, keeping the finished session as `last_session`
+    pub fn stop_recording(&mut self) {
+        self.receive();
+        self.last_session_path = None;
+        if let Some(writer) = self.session_file.take() {
+            let path = writer.path.clone();
+            match writer.finish() {
+                Ok(()) => {
+                    println!("Recorded {}", path.display());
+                    self.last_session_path = Some(path);
+                }
+                Err(e) => eprintln!("Could not finish {}: {}", path.display(), e),
+            }
+        }
+        if let Some(session) = self.recording.take() {
+            self.last_session = Some(session);
+        }
+    }
+
+    pub fn last_session(&self) -> Option<&MuseSession> {
+        self.last_session.as_ref()
+    }
+
+    pub fn last_session_path(&self) -> Option<&Path> {
+        self.last_session_path.as_deref()
+    }
+
+    /// Make a session loaded from a file the one that exports and replays use
+    pub fn set_last_session(&mut self, session: MuseSession, path: PathBuf) {
+        self.last_session = Some(session);
+        self.last_session_path = Some(path);
+    }
+}
+
+/// UTC date and time of a time since the UNIX epoch, as (year, month, day, hour, minute, second)
+pub fn civil_time(time: Duration) -> (i64, usize, u64, u64, u64, u64) {
+    let seconds = time.as_secs();
+    let days = (seconds / 86400) as i64;
+    let seconds_of_day = seconds % 86400;
+
+    // Days to civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u64;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as usize;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+    )
+}
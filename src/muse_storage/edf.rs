@@ -0,0 +1,623 @@
+use super::{civil_time, MuseSession};
+use crate::muse_packet::{MuseMessageType, ELECTRODE_NAMES};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+pub const EEG_SAMPLE_RATE: usize = 256;
+
+/// Range of the Muse raw EEG, in microvolts
+const PHYSICAL_MIN: f32 = 0.0;
+const PHYSICAL_MAX: f32 = 1682.815;
+const DIGITAL_MIN: i32 = -32768;
+const DIGITAL_MAX: i32 = 32767;
+
+const ANNOTATIONS_LABEL: &str = "EDF Annotations";
+/// Annotation text marking EEG filled in for lost packets
+const GAP_LABEL: &str = "eeg_gap";
+/// How far receive times may wander from the 256 Hz grid before samples are taken as lost
+const GAP_TOLERANCE: Duration = Duration::from_millis(250);
+const RECORD_DURATION_SECS: usize = 1;
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+/// Patient and recording identification written to the EDF+ header. Use "X" for unknown.
+#[derive(Clone, Debug)]
+pub struct EdfHeaderInfo {
+    pub patient_code: String,
+    /// "M", "F" or "X"
+    pub patient_sex: String,
+    /// As "02-AUG-1951", or "X"
+    pub patient_birthdate: String,
+    pub patient_name: String,
+    pub admin_code: String,
+    pub technician: String,
+    pub equipment: String,
+}
+
+impl Default for EdfHeaderInfo {
+    fn default() -> EdfHeaderInfo {
+        EdfHeaderInfo {
+            patient_code: String::from("X"),
+            patient_sex: String::from("X"),
+            patient_birthdate: String::from("X"),
+            patient_name: String::from("X"),
+            admin_code: String::from("X"),
+            technician: String::from("X"),
+            equipment: String::from("Muse"),
+        }
+    }
+}
+
+/// A signal read back from an EDF file, in physical units
+#[derive(Clone, Debug)]
+pub struct EdfSignal {
+    pub label: String,
+    pub physical_dimension: String,
+    pub samples_per_record: usize,
+    pub samples: Vec<f32>,
+}
+
+/// The contents of an EDF+ file, as read by `read_edf`
+#[derive(Clone, Debug)]
+pub struct EdfData {
+    pub patient_id: String,
+    pub recording_id: String,
+    pub start_date: String,
+    pub start_time: String,
+    pub record_duration: f64,
+    /// Seconds from the whole-second header start time to the first data record
+    pub start_offset: f64,
+    pub signals: Vec<EdfSignal>,
+    /// Onset in seconds from the start of the file, and text
+    pub annotations: Vec<(f64, String)>,
+}
+
+/// Write the raw EEG of a session as EDF+ with one signal per electrode.
+///
+/// EEG messages are laid on a 256 Hz grid from the first one, since packets carry a receive
+/// time rather than a sample time, and lost packets are filled in as in `eeg_on_grid`. The
+/// header start time only has whole seconds, so the fraction goes in the time-keeping
+/// annotation of each record and annotation onsets are from the whole second.
+pub fn write_edf(path: &Path, session: &MuseSession, info: &EdfHeaderInfo) -> io::Result<()> {
+    let start = session
+        .messages
+        .iter()
+        .find(|&&(_, ref muse_message_type)| match *muse_message_type {
+            MuseMessageType::Eeg { .. } => true,
+            _ => false,
+        })
+        .map(|&(time, _)| time)
+        .unwrap_or(session.start_time);
+    let (eeg, gaps) = eeg_on_grid(session, start);
+
+    let samples_per_record = EEG_SAMPLE_RATE * RECORD_DURATION_SECS;
+    let record_count = ((eeg.len() + samples_per_record - 1) / samples_per_record).max(1);
+
+    let file_start = Duration::from_secs(start.as_secs());
+    let annotations = annotation_records(session, &gaps, file_start, start, record_count);
+    let annotation_bytes = annotations.iter().map(|a| a.len()).max().unwrap_or(0);
+    let annotation_samples = (annotation_bytes + 1) / 2;
+
+    let mut w = BufWriter::new(File::create(path)?);
+    write_header(
+        &mut w,
+        info,
+        file_start,
+        record_count,
+        samples_per_record,
+        annotation_samples,
+    )?;
+
+    for (record, annotation) in annotations.iter().enumerate() {
+        for channel in 0..ELECTRODE_NAMES.len() {
+            for i in 0..samples_per_record {
+                let value = eeg
+                    .get(record * samples_per_record + i)
+                    .map_or(PHYSICAL_MIN, |sample| sample[channel]);
+                w.write_all(&to_digital(value).to_le_bytes())?;
+            }
+        }
+
+        let mut bytes = annotation.clone();
+        bytes.resize(annotation_samples * 2, 0);
+        w.write_all(&bytes)?;
+    }
+
+    w.flush()
+}
+
+/// Read an EDF or EDF+ file, including any "EDF Annotations" signal
+pub fn read_edf(path: &Path) -> io::Result<EdfData> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let mut r = FieldReader {
+        bytes: &bytes,
+        position: 0,
+    };
+    let _version = r.text(8)?;
+    let patient_id = r.text(80)?;
+    let recording_id = r.text(80)?;
+    let start_date = r.text(8)?;
+    let start_time = r.text(8)?;
+    let header_bytes = r.number(8)? as usize;
+    let _reserved = r.text(44)?;
+    let record_count = r.number(8)? as usize;
+    let record_duration = r.number(8)?;
+    let signal_count = r.number(4)? as usize;
+
+    let labels = r.texts(16, signal_count)?;
+    let _transducers = r.texts(80, signal_count)?;
+    let dimensions = r.texts(8, signal_count)?;
+    let physical_min = r.numbers(8, signal_count)?;
+    let physical_max = r.numbers(8, signal_count)?;
+    let digital_min = r.numbers(8, signal_count)?;
+    let digital_max = r.numbers(8, signal_count)?;
+    let _prefiltering = r.texts(80, signal_count)?;
+    let samples_per_record = r.numbers(8, signal_count)?;
+    let _reserved = r.texts(32, signal_count)?;
+
+    let mut signals: Vec<EdfSignal> = (0..signal_count)
+        .map(|i| EdfSignal {
+            label: labels[i].clone(),
+            physical_dimension: dimensions[i].clone(),
+            samples_per_record: samples_per_record[i] as usize,
+            samples: Vec::new(),
+        })
+        .collect();
+    let mut annotations = Vec::new();
+    let mut start_offset = None;
+
+    r.position = header_bytes;
+    for _ in 0..record_count {
+        for (i, signal) in signals.iter_mut().enumerate() {
+            let data = r.take(signal.samples_per_record * 2)?;
+            if signal.label == ANNOTATIONS_LABEL {
+                let (record_onset, record_annotations) = parse_annotations(data);
+                start_offset = start_offset.or(record_onset);
+                annotations.extend(record_annotations);
+                continue;
+            }
+
+            let digital_range = digital_max[i] - digital_min[i];
+            if digital_range == 0.0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("EDF signal {:?} has no digital range", signal.label),
+                ));
+            }
+            let gain = (physical_max[i] - physical_min[i]) / digital_range;
+            for pair in data.chunks(2) {
+                let digital = i16::from_le_bytes([pair[0], pair[1]]) as f64;
+                let physical = physical_min[i] + (digital - digital_min[i]) * gain;
+                signal.samples.push(physical as f32);
+            }
+        }
+    }
+    signals.retain(|signal| signal.label != ANNOTATIONS_LABEL);
+
+    Ok(EdfData {
+        patient_id: patient_id,
+        recording_id: recording_id,
+        start_date: start_date,
+        start_time: start_time,
+        record_duration: record_duration,
+        start_offset: start_offset.unwrap_or(0.0),
+        signals: signals,
+        annotations: annotations,
+    })
+}
+
+fn write_header<W: Write>(
+    w: &mut W,
+    info: &EdfHeaderInfo,
+    start: Duration,
+    record_count: usize,
+    samples_per_record: usize,
+    annotation_samples: usize,
+) -> io::Result<()> {
+    let signal_count = ELECTRODE_NAMES.len() + 1;
+    let (year, month, day, hour, minute, second) = civil_time(start);
+    let patient_id = format!(
+        "{} {} {} {}",
+        field(&info.patient_code),
+        field(&info.patient_sex),
+        field(&info.patient_birthdate),
+        field(&info.patient_name)
+    );
+    let recording_id = format!(
+        "Startdate {:02}-{}-{} {} {} {}",
+        day,
+        MONTHS[month - 1],
+        year,
+        field(&info.admin_code),
+        field(&info.technician),
+        field(&info.equipment)
+    );
+
+    write_field(w, "0", 8)?;
+    write_field(w, &patient_id, 80)?;
+    write_field(w, &recording_id, 80)?;
+    write_field(w, &format!("{:02}.{:02}.{:02}", day, month, year % 100), 8)?;
+    write_field(w, &format!("{:02}.{:02}.{:02}", hour, minute, second), 8)?;
+    write_field(w, &(256 * (signal_count + 1)).to_string(), 8)?;
+    write_field(w, "EDF+C", 44)?;
+    write_field(w, &record_count.to_string(), 8)?;
+    write_field(w, &RECORD_DURATION_SECS.to_string(), 8)?;
+    write_field(w, &signal_count.to_string(), 4)?;
+
+    // Each header field is written for every signal before the next field
+    let eeg_and_annotation = |eeg: &str, annotation: &str| {
+        let mut values = vec![eeg.to_string(); ELECTRODE_NAMES.len()];
+        values.push(annotation.to_string());
+        values
+    };
+    let labels: Vec<String> = ELECTRODE_NAMES
+        .iter()
+        .map(|name| format!("EEG {}", name))
+        .chain(Some(ANNOTATIONS_LABEL.to_string()))
+        .collect();
+
+    write_fields(w, &labels, 16)?;
+    write_fields(w, &eeg_and_annotation("Dry electrode", ""), 80)?;
+    write_fields(w, &eeg_and_annotation("uV", ""), 8)?;
+    write_fields(
+        w,
+        &eeg_and_annotation(&format!("{}", PHYSICAL_MIN), "-1"),
+        8,
+    )?;
+    write_fields(w, &eeg_and_annotation(&format!("{}", PHYSICAL_MAX), "1"), 8)?;
+    write_fields(
+        w,
+        &eeg_and_annotation(&DIGITAL_MIN.to_string(), &DIGITAL_MIN.to_string()),
+        8,
+    )?;
+    write_fields(
+        w,
+        &eeg_and_annotation(&DIGITAL_MAX.to_string(), &DIGITAL_MAX.to_string()),
+        8,
+    )?;
+    write_fields(w, &eeg_and_annotation("", ""), 80)?;
+    write_fields(
+        w,
+        &eeg_and_annotation(
+            &samples_per_record.to_string(),
+            &annotation_samples.to_string(),
+        ),
+        8,
+    )?;
+    write_fields(w, &eeg_and_annotation("", ""), 32)
+}
+
+/// The EEG of a session as consecutive 256 Hz samples from `start`, and the start time and
+/// length of each gap filled in.
+///
+/// Packets arrive in bursts, so receive times wander around the grid by up to
+/// `GAP_TOLERANCE`. A sample received later than that follows lost packets, and the last
+/// sample is repeated up to its receive time so the signal stays in step with the annotations.
+/// Samples arriving that far ahead of the grid cannot be placed, so they are only warned about.
+fn eeg_on_grid(
+    session: &MuseSession,
+    start: Duration,
+) -> (Vec<[f32; 4]>, Vec<(Duration, Duration)>) {
+    let rate = EEG_SAMPLE_RATE as f64;
+    let mut samples: Vec<[f32; 4]> = Vec::new();
+    let mut gaps = Vec::new();
+    let mut lead = Duration::from_secs(0);
+
+    for &(time, ref muse_message_type) in session.messages.iter() {
+        let values = match *muse_message_type {
+            MuseMessageType::Eeg { a, b, c, d } => [a, b, c, d],
+            _ => continue,
+        };
+
+        let grid_time = start + Duration::from_secs_f64(samples.len() as f64 / rate);
+        if time > grid_time + GAP_TOLERANCE {
+            let missing = (seconds_between(start, time) * rate).round() as usize - samples.len();
+            let fill = samples.last().copied().unwrap_or(values);
+            samples.resize(samples.len() + missing, fill);
+            gaps.push((grid_time, Duration::from_secs_f64(missing as f64 / rate)));
+        } else if grid_time > time + GAP_TOLERANCE {
+            lead = lead.max(grid_time - time);
+        }
+        samples.push(values);
+    }
+
+    if lead > Duration::from_secs(0) {
+        eprintln!(
+            "EEG arrived up to {:.2} s ahead of {} Hz, so the EDF signal runs behind its annotations",
+            lead.as_secs_f32(),
+            EEG_SAMPLE_RATE
+        );
+    }
+
+    (samples, gaps)
+}
+
+/// The time-keeping TAL plus any annotations and filled gaps falling in each data record.
+/// Onsets are from `file_start`, the header start time, and the first record starts at `start`.
+fn annotation_records(
+    session: &MuseSession,
+    gaps: &[(Duration, Duration)],
+    file_start: Duration,
+    start: Duration,
+    record_count: usize,
+) -> Vec<Vec<u8>> {
+    let offset = seconds_between(file_start, start);
+    let mut records: Vec<Vec<u8>> = (0..record_count)
+        .map(|record| {
+            let onset = offset + (record * RECORD_DURATION_SECS) as f64;
+            format!("+{:.4}\x14\x14\0", onset).into_bytes()
+        })
+        .collect();
+
+    for (time, label) in session.annotations() {
+        let onset = seconds_between(file_start, time);
+        let record =
+            ((onset - offset).max(0.0) as usize / RECORD_DURATION_SECS).min(record_count - 1);
+        let text = label.replace('\x14', " ").replace('\x15', " ");
+        records[record].extend(format!("{:+.4}\x14{}\x14\0", onset, text).into_bytes());
+    }
+    for &(time, duration) in gaps.iter() {
+        let onset = seconds_between(file_start, time);
+        let record =
+            ((onset - offset).max(0.0) as usize / RECORD_DURATION_SECS).min(record_count - 1);
+        records[record].extend(
+            format!(
+                "{:+.4}\x15{:.4}\x14{}\x14\0",
+                onset,
+                duration.as_secs_f64(),
+                GAP_LABEL
+            )
+            .into_bytes(),
+        );
+    }
+
+    records
+}
+
+/// The onset of the record from its time-keeping TAL, and the annotations in it
+fn parse_annotations(data: &[u8]) -> (Option<f64>, Vec<(f64, String)>) {
+    let mut record_onset = None;
+    let mut annotations = Vec::new();
+
+    let tals = data.split(|&b| b == 0).filter(|tal| !tal.is_empty());
+    for (i, tal) in tals.enumerate() {
+        let mut parts = tal.split(|&b| b == 0x14);
+        let onset = parts
+            .next()
+            .map(|timing| timing.split(|&b| b == 0x15).next().unwrap_or(timing))
+            .and_then(|onset| String::from_utf8_lossy(onset).parse::<f64>().ok());
+
+        if let Some(onset) = onset {
+            // The first TAL of each record keeps time, giving the onset of the record
+            if i == 0 {
+                record_onset = Some(onset);
+            }
+            for text in parts.filter(|text| !text.is_empty()) {
+                annotations.push((onset, String::from_utf8_lossy(text).into_owned()));
+            }
+        }
+    }
+
+    (record_onset, annotations)
+}
+
+fn to_digital(value: f32) -> i16 {
+    let scale = (DIGITAL_MAX - DIGITAL_MIN) as f32 / (PHYSICAL_MAX - PHYSICAL_MIN);
+    let digital = (value - PHYSICAL_MIN) * scale + DIGITAL_MIN as f32;
+
+    digital
+        .round()
+        .max(DIGITAL_MIN as f32)
+        .min(DIGITAL_MAX as f32) as i16
+}
+
+fn seconds_between(start: Duration, time: Duration) -> f64 {
+    time.as_secs_f64() - start.as_secs_f64()
+}
+
+/// EDF+ subfields are separated by spaces, so spaces inside one become underscores
+fn field(value: &str) -> String {
+    let value = value.trim();
+    if value.is_empty() {
+        return String::from("X");
+    }
+
+    value.replace(' ', "_")
+}
+
+fn write_field<W: Write>(w: &mut W, value: &str, width: usize) -> io::Result<()> {
+    let mut bytes: Vec<u8> = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(width)
+        .collect();
+    bytes.resize(width, b' ');
+
+    w.write_all(&bytes)
+}
+
+fn write_fields<W: Write>(w: &mut W, values: &[String], width: usize) -> io::Result<()> {
+    for value in values.iter() {
+        write_field(w, value, width)?;
+    }
+
+    Ok(())
+}
+
+struct FieldReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.position + length > self.bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "EDF file is truncated",
+            ));
+        }
+        let bytes = &self.bytes[self.position..self.position + length];
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn text(&mut self, width: usize) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.take(width)?)
+            .trim()
+            .to_string())
+    }
+
+    fn number(&mut self, width: usize) -> io::Result<f64> {
+        let text = self.text(width)?;
+        text.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("EDF header field {:?} is not a number", text),
+            )
+        })
+    }
+
+    fn texts(&mut self, width: usize, count: usize) -> io::Result<Vec<String>> {
+        (0..count).map(|_| self.text(width)).collect()
+    }
+
+    fn numbers(&mut self, width: usize, count: usize) -> io::Result<Vec<f64>> {
+        (0..count).map(|_| self.number(width)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_storage::SessionEvent;
+    use std::env;
+    use std::fs;
+
+    /// 2019-12-04 15:30:12.375 UTC
+    const START_MS: u64 = 1_575_473_412_375;
+
+    fn eeg_value(i: usize, channel: usize) -> f32 {
+        800.0 + 400.0 * ((i as f32 * 0.1) + channel as f32).sin()
+    }
+
+    fn synthetic_session() -> MuseSession {
+        let start = Duration::from_millis(START_MS);
+        let mut session = MuseSession::new(start);
+        for i in 0..300 {
+            let time = start + Duration::from_secs_f64(i as f64 / EEG_SAMPLE_RATE as f64);
+            session.messages.push((
+                time,
+                MuseMessageType::Eeg {
+                    a: eeg_value(i, 0),
+                    b: eeg_value(i, 1),
+                    c: eeg_value(i, 2),
+                    d: eeg_value(i, 3),
+                },
+            ));
+        }
+        session.messages.push((
+            start + Duration::from_millis(500),
+            MuseMessageType::Blink { blink: true },
+        ));
+        session.messages.sort_by_key(|&(time, _)| time);
+        session.events.push((
+            start + Duration::from_millis(1250),
+            SessionEvent::Marker {
+                label: String::from("onset cat"),
+            },
+        ));
+
+        session
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = env::temp_dir().join(format!("meme_machine_edf_{}.edf", std::process::id()));
+        write_edf(&path, &synthetic_session(), &EdfHeaderInfo::default()).unwrap();
+        let edf = read_edf(&path);
+        fs::remove_file(&path).unwrap();
+        let edf = edf.unwrap();
+
+        assert_eq!(edf.patient_id, "X X X X");
+        assert!(edf
+            .recording_id
+            .starts_with("Startdate 04-DEC-2019 X X Muse"));
+        assert_eq!(edf.start_date, "04.12.19");
+        assert_eq!(edf.start_time, "15.30.12");
+        assert_eq!(edf.record_duration, RECORD_DURATION_SECS as f64);
+        assert!((edf.start_offset - 0.375).abs() < 1e-4);
+
+        let step = (PHYSICAL_MAX - PHYSICAL_MIN) / (DIGITAL_MAX - DIGITAL_MIN) as f32;
+        assert_eq!(edf.signals.len(), ELECTRODE_NAMES.len());
+        for (channel, signal) in edf.signals.iter().enumerate() {
+            assert_eq!(signal.label, format!("EEG {}", ELECTRODE_NAMES[channel]));
+            assert_eq!(signal.physical_dimension, "uV");
+            assert_eq!(signal.samples_per_record, EEG_SAMPLE_RATE);
+            // Two records, the second padded
+            assert_eq!(signal.samples.len(), 2 * EEG_SAMPLE_RATE);
+            for i in 0..300 {
+                assert!((signal.samples[i] - eeg_value(i, channel)).abs() <= step);
+            }
+        }
+
+        // Onsets are from the whole second in the header
+        assert_eq!(edf.annotations.len(), 2);
+        assert!((edf.annotations[0].0 - 0.875).abs() < 1e-4);
+        assert_eq!(edf.annotations[0].1, "blink");
+        assert!((edf.annotations[1].0 - 1.625).abs() < 1e-4);
+        assert_eq!(edf.annotations[1].1, "marker onset cat");
+    }
+
+    #[test]
+    fn lost_packets_are_filled() {
+        let start = Duration::from_millis(START_MS);
+        let mut session = MuseSession::new(start);
+        // Half a second of samples, from 256 to 384, never arrives
+        for i in (0..256).chain(384..640) {
+            let time = start + Duration::from_secs_f64(i as f64 / EEG_SAMPLE_RATE as f64);
+            session.messages.push((
+                time,
+                MuseMessageType::Eeg {
+                    a: eeg_value(i, 0),
+                    b: eeg_value(i, 1),
+                    c: eeg_value(i, 2),
+                    d: eeg_value(i, 3),
+                },
+            ));
+        }
+
+        let path = env::temp_dir().join(format!("meme_machine_gap_{}.edf", std::process::id()));
+        write_edf(&path, &session, &EdfHeaderInfo::default()).unwrap();
+        let edf = read_edf(&path);
+        fs::remove_file(&path).unwrap();
+        let edf = edf.unwrap();
+
+        let step = (PHYSICAL_MAX - PHYSICAL_MIN) / (DIGITAL_MAX - DIGITAL_MIN) as f32;
+        let samples = &edf.signals[0].samples;
+        assert_eq!(samples.len(), 3 * EEG_SAMPLE_RATE);
+        for &sample in samples[256..384].iter() {
+            assert!((sample - eeg_value(255, 0)).abs() <= step);
+        }
+        for (i, &sample) in samples.iter().enumerate().take(640).skip(384) {
+            assert!((sample - eeg_value(i, 0)).abs() <= step);
+        }
+
+        assert_eq!(edf.annotations.len(), 1);
+        assert!((edf.annotations[0].0 - 1.375).abs() < 1e-4);
+        assert_eq!(edf.annotations[0].1, GAP_LABEL);
+    }
+}
@@ -0,0 +1,847 @@
+use super::{MuseSession, SessionEvent};
+use crate::muse_packet::{MuseMessageType, ELECTRODE_NAMES, MOTION_AXES};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File extension of native session files
+pub const SESSION_FILE_EXTENSION: &str = "mms";
+
+/// A session file loaded at startup for export and replay
+pub const LOAD_SESSION_ENV: &str = "MEME_MACHINE_LOAD_SESSION";
+
+const FILE_MAGIC: &[u8; 8] = b"MMSESS01";
+const BLOCK_MAGIC: &[u8; 4] = b"MMB1";
+const INDEX_MAGIC: &[u8; 4] = b"MMI1";
+const TRAILER_MAGIC: &[u8; 8] = b"MMSEND01";
+
+/// Magic, stream, sample count, first and last time, payload length and CRC
+const BLOCK_HEADER_LENGTH: usize = 4 + 1 + 4 + 8 + 8 + 4 + 4;
+const INDEX_ENTRY_LENGTH: usize = 1 + 8 + 8 + 8;
+const TRAILER_LENGTH: usize = 8 + 8;
+
+/// A stream's buffer is written as a block once it holds this many samples
+const BLOCK_SAMPLES: usize = 2560;
+
+/// ...or once it spans this long. Every stream's buffer is also written once its first sample
+/// is this old, which bounds what a crash can lose.
+const BLOCK_DURATION: Duration = Duration::from_secs(10);
+
+/// Stream ids are positions in this list. Session events are stored as text in the last one.
+const STREAM_NAMES: [&str; 14] = [
+    "eeg",
+    "acc",
+    "gyro",
+    "alpha",
+    "beta",
+    "gamma",
+    "delta",
+    "theta",
+    "horseshoe",
+    "batt",
+    "touching_forehead",
+    "blink",
+    "jaw_clench",
+    "events",
+];
+const EVENTS_STREAM: u8 = 13;
+
+fn stream_channels(name: &str) -> &'static [&'static str] {
+    match name {
+        "eeg" | "alpha" | "beta" | "gamma" | "delta" | "theta" | "horseshoe" => &ELECTRODE_NAMES,
+        "acc" | "gyro" => &MOTION_AXES,
+        "batt" => &["charge"],
+        "events" => &["label"],
+        _ => &["on"],
+    }
+}
+
+/// What the JSON header of a session file describes
+#[derive(Clone, Debug)]
+pub struct SessionFileHeader {
+    pub start_time: Duration,
+    pub device: Option<String>,
+    /// Stream names with their channel labels, indexed by stream id
+    pub streams: Vec<(String, Vec<String>)>,
+}
+
+/// Where one block is and what it covers
+#[derive(Clone, Copy, Debug)]
+struct BlockEntry {
+    stream: u8,
+    first_time: Duration,
+    last_time: Duration,
+    offset: u64,
+}
+
+/// Samples of one stream waiting to be written as a block
+struct PendingBlock {
+    first_time: Duration,
+    last_time: Duration,
+    sample_count: u32,
+    payload: Vec<u8>,
+}
+
+/// Appends a session to a chunked, compressed file as it is recorded.
+///
+/// Every block is complete and checksummed on disk once written, so a file left
+/// by a crash can be recovered up to its last block with `recover`.
+pub struct SessionFileWriter {
+    file: File,
+    pub path: PathBuf,
+    pending: Vec<Option<PendingBlock>>,
+    index: Vec<BlockEntry>,
+}
+
+impl Debug for SessionFileWriter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<SessionFileWriter: {}>", self.path.display())
+    }
+}
+
+impl SessionFileWriter {
+    pub fn create(
+        path: &Path,
+        start_time: Duration,
+        device: Option<SocketAddr>,
+    ) -> io::Result<SessionFileWriter> {
+        let streams: Vec<Value> = STREAM_NAMES
+            .iter()
+            .map(|name| json!({ "name": name, "channels": stream_channels(name) }))
+            .collect();
+        let header = json!({
+            "start_time_us": start_time.as_micros() as u64,
+            "device": device.map(|device| device.to_string()),
+            "streams": streams,
+        })
+        .to_string();
+
+        let mut bytes = FILE_MAGIC.to_vec();
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+
+        let mut file = File::create(path)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+
+        Ok(SessionFileWriter {
+            file: file,
+            path: path.to_path_buf(),
+            pending: STREAM_NAMES.iter().map(|_| None).collect(),
+            index: Vec::new(),
+        })
+    }
+
+    pub fn push_message(
+        &mut self,
+        time: Duration,
+        muse_message_type: &MuseMessageType,
+    ) -> io::Result<()> {
+        let stream = match STREAM_NAMES
+            .iter()
+            .position(|&name| name == muse_message_type.name())
+        {
+            Some(stream) => stream as u8,
+            None => return Ok(()),
+        };
+        let mut sample = Vec::new();
+        for value in muse_message_type.values().iter() {
+            sample.extend_from_slice(&value.to_le_bytes());
+        }
+
+        self.push(stream, time, &sample)
+    }
+
+    /// Add an event. Labels are stored with a 16 bit length, so longer ones are refused with
+    /// `InvalidInput` and leave the file as it was.
+    pub fn push_event(&mut self, time: Duration, event: &SessionEvent) -> io::Result<()> {
+        let label = event.label();
+        if label.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("event label of {} bytes is too long", label.len()),
+            ));
+        }
+        let mut sample = (label.len() as u16).to_le_bytes().to_vec();
+        sample.extend_from_slice(label.as_bytes());
+
+        self.push(EVENTS_STREAM, time, &sample)
+    }
+
+    /// Write the pending samples of every stream whose oldest sample is `BLOCK_DURATION` before
+    /// `now`, so sparse streams such as markers reach the disk as often as continuous ones
+    pub fn flush_older_than(&mut self, now: Duration) -> io::Result<()> {
+        for stream in 0..self.pending.len() {
+            let old = match self.pending[stream] {
+                Some(ref block) => now >= block.first_time + BLOCK_DURATION,
+                None => false,
+            };
+            if old {
+                self.write_block(stream as u8)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write all pending samples and the block index. The file is complete after this.
+    pub fn finish(mut self) -> io::Result<()> {
+        for stream in 0..self.pending.len() {
+            self.write_block(stream as u8)?;
+        }
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        write_index(&mut self.file, &self.index, offset)?;
+        self.file.sync_all()
+    }
+
+    fn push(&mut self, stream: u8, time: Duration, sample: &[u8]) -> io::Result<()> {
+        self.flush_older_than(time)?;
+        let full = match self.pending[stream as usize] {
+            Some(ref block) => block.sample_count as usize >= BLOCK_SAMPLES,
+            None => false,
+        };
+        if full {
+            self.write_block(stream)?;
+        }
+
+        let block = self.pending[stream as usize].get_or_insert(PendingBlock {
+            first_time: time,
+            last_time: time,
+            sample_count: 0,
+            payload: Vec::new(),
+        });
+        block
+            .payload
+            .extend_from_slice(&(time.as_micros() as u64).to_le_bytes());
+        block.payload.extend_from_slice(sample);
+        block.last_time = time;
+        block.sample_count += 1;
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, stream: u8) -> io::Result<()> {
+        let block = match self.pending[stream as usize].take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&block.payload)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&compressed);
+
+        let mut bytes = Vec::with_capacity(BLOCK_HEADER_LENGTH + compressed.len());
+        bytes.extend_from_slice(BLOCK_MAGIC);
+        bytes.push(stream);
+        bytes.extend_from_slice(&block.sample_count.to_le_bytes());
+        bytes.extend_from_slice(&(block.first_time.as_micros() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(block.last_time.as_micros() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc.sum().to_le_bytes());
+        bytes.extend_from_slice(&compressed);
+
+        let offset = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+
+        self.index.push(BlockEntry {
+            stream: stream,
+            first_time: block.first_time,
+            last_time: block.last_time,
+            offset: offset,
+        });
+
+        Ok(())
+    }
+}
+
+/// Random access to a session file through its block index
+pub struct SessionFileReader {
+    file: File,
+    file_length: u64,
+    pub header: SessionFileHeader,
+    /// Blocks of each stream in time order, indexed by stream id
+    streams: Vec<Vec<BlockEntry>>,
+}
+
+impl Debug for SessionFileReader {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let blocks: usize = self.streams.iter().map(Vec::len).sum();
+        write!(f, "<SessionFileReader: {} blocks>", blocks)
+    }
+}
+
+impl SessionFileReader {
+    /// Open a session file, scanning its blocks if it was never finished
+    pub fn open(path: &Path) -> io::Result<SessionFileReader> {
+        let mut file = File::open(path)?;
+        let file_length = file.seek(SeekFrom::End(0))?;
+        let (header, blocks_start) = read_header(&mut file)?;
+        let index = match read_index(&mut file)? {
+            Some(index) => index,
+            None => scan_blocks(&mut file, blocks_start)?.0,
+        };
+
+        // Blocks of one stream are written in time order
+        let mut streams: Vec<Vec<BlockEntry>> = header.streams.iter().map(|_| Vec::new()).collect();
+        for entry in index {
+            if let Some(blocks) = streams.get_mut(entry.stream as usize) {
+                blocks.push(entry);
+            }
+        }
+
+        Ok(SessionFileReader {
+            file: file,
+            file_length: file_length,
+            header: header,
+            streams: streams,
+        })
+    }
+
+    /// Time of the last sample in the file, or the start time if it has none
+    pub fn end_time(&self) -> Duration {
+        self.streams
+            .iter()
+            .filter_map(|blocks| blocks.last())
+            .map(|entry| entry.last_time)
+            .fold(self.header.start_time, Duration::max)
+    }
+
+    /// Messages and events with times from `start` to `end` inclusive, in time order.
+    ///
+    /// Each stream's index is searched for the first block ending at or after `start`, and only
+    /// blocks overlapping the range are read and decompressed.
+    pub fn read_between(&mut self, start: Duration, end: Duration) -> io::Result<MuseSession> {
+        let mut session = MuseSession::new(self.header.start_time);
+        session.device = self
+            .header
+            .device
+            .as_ref()
+            .and_then(|device| device.parse().ok());
+
+        let mut entries: Vec<BlockEntry> = Vec::new();
+        for blocks in self.streams.iter() {
+            let first = blocks.partition_point(|entry| entry.last_time < start);
+            entries.extend(
+                blocks[first..]
+                    .iter()
+                    .take_while(|entry| entry.first_time <= end),
+            );
+        }
+        for entry in entries.iter() {
+            let payload = self.read_block(entry)?;
+            let (name, channel_count) = match self.header.streams.get(entry.stream as usize) {
+                Some(&(ref name, ref channels)) => (name.clone(), channels.len()),
+                None => continue,
+            };
+
+            let mut position = 0;
+            while position < payload.len() {
+                let time = Duration::from_micros(read_u64(take(&payload, &mut position, 8)?));
+                let in_range = time >= start && time <= end;
+
+                if entry.stream == EVENTS_STREAM {
+                    let length = read_u16(take(&payload, &mut position, 2)?) as usize;
+                    let label = String::from_utf8_lossy(take(&payload, &mut position, length)?);
+                    if in_range {
+                        if let Some(event) = SessionEvent::from_label(&label) {
+                            session.events.push((time, event));
+                        }
+                    }
+                } else {
+                    let values: Vec<f32> = take(&payload, &mut position, channel_count * 4)?
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect();
+                    if in_range {
+                        if let Some(muse_message_type) =
+                            MuseMessageType::from_values(&name, &values)
+                        {
+                            session.messages.push((time, muse_message_type));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Blocks of different streams overlap in time, sorting is stable within a stream
+        session.messages.sort_by_key(|&(time, _)| time);
+        session.events.sort_by_key(|&(time, _)| time);
+
+        Ok(session)
+    }
+
+    /// The whole session
+    pub fn read_session(&mut self) -> io::Result<MuseSession> {
+        let end = self.end_time();
+
+        self.read_between(Duration::from_secs(0), end)
+    }
+
+    fn read_block(&mut self, entry: &BlockEntry) -> io::Result<Vec<u8>> {
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        let mut header = [0; BLOCK_HEADER_LENGTH];
+        self.file.read_exact(&mut header)?;
+        if &header[..4] != BLOCK_MAGIC {
+            return Err(invalid_data("block index points outside a block"));
+        }
+        let length = read_u32(&header[25..]) as usize;
+        if entry.offset + (BLOCK_HEADER_LENGTH + length) as u64 > self.file_length {
+            return Err(invalid_data("block runs past the end of the file"));
+        }
+
+        let mut compressed = vec![0; length];
+        self.file.read_exact(&mut compressed)?;
+        let mut crc = Crc::new();
+        crc.update(&compressed);
+        if crc.sum() != read_u32(&header[29..]) {
+            return Err(invalid_data("block checksum does not match"));
+        }
+        let mut payload = Vec::new();
+        DeflateDecoder::new(&compressed[..]).read_to_end(&mut payload)?;
+
+        Ok(payload)
+    }
+}
+
+/// Make an unfinished file readable by cutting it after its last intact block and
+/// writing the index. Returns the number of blocks kept, or None if it was complete.
+pub fn recover(path: &Path) -> io::Result<Option<usize>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (_, blocks_start) = read_header(&mut file)?;
+    if read_index(&mut file)?.is_some() {
+        return Ok(None);
+    }
+
+    let (index, end) = scan_blocks(&mut file, blocks_start)?;
+    file.set_len(end)?;
+    write_index(&mut file, &index, end)?;
+    file.sync_all()?;
+
+    Ok(Some(index.len()))
+}
+
+/// Recover every unfinished session file in a directory, as left by a crash during recording.
+/// A file that cannot be recovered is reported and skipped so the others still are.
+pub fn recover_all(directory: &Path) -> io::Result<Vec<(PathBuf, usize)>> {
+    let mut recovered = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                eprintln!("Could not list {}: {}", directory.display(), e);
+                continue;
+            }
+        };
+        if path
+            .extension()
+            .map_or(false, |e| e == SESSION_FILE_EXTENSION)
+        {
+            match recover(&path) {
+                Ok(Some(blocks)) => recovered.push((path, blocks)),
+                Ok(None) => (),
+                Err(e) => eprintln!("Could not recover {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    Ok(recovered)
+}
+
+fn read_header(file: &mut File) -> io::Result<(SessionFileHeader, u64)> {
+    let mut start = [0; 12];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut start)?;
+    if &start[..8] != FILE_MAGIC {
+        return Err(invalid_data("not a session file"));
+    }
+
+    let length = read_u32(&start[8..]) as usize;
+    if length as u64 > file.seek(SeekFrom::End(0))? {
+        return Err(invalid_data("header runs past the end of the file"));
+    }
+    file.seek(SeekFrom::Start(12))?;
+    let mut text = vec![0; length];
+    file.read_exact(&mut text)?;
+    let value: Value = serde_json::from_slice(&text).map_err(|e| invalid_data(&e.to_string()))?;
+
+    let streams = value["streams"]
+        .as_array()
+        .ok_or_else(|| invalid_data("header has no streams"))?
+        .iter()
+        .map(|stream| {
+            let name = stream["name"].as_str().unwrap_or("").to_string();
+            let channels = stream["channels"]
+                .as_array()
+                .map(|channels| {
+                    channels
+                        .iter()
+                        .map(|c| c.as_str().unwrap_or("").to_string())
+                        .collect()
+                })
+                .unwrap_or_else(Vec::new);
+            (name, channels)
+        })
+        .collect();
+
+    let header = SessionFileHeader {
+        start_time: Duration::from_micros(value["start_time_us"].as_u64().unwrap_or(0)),
+        device: value["device"].as_str().map(|device| device.to_string()),
+        streams: streams,
+    };
+
+    Ok((header, 12 + length as u64))
+}
+
+/// The index from the trailer, or None if the file was not finished
+fn read_index(file: &mut File) -> io::Result<Option<Vec<BlockEntry>>> {
+    let file_length = file.seek(SeekFrom::End(0))?;
+    if file_length < TRAILER_LENGTH as u64 {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; TRAILER_LENGTH];
+    file.seek(SeekFrom::End(-(TRAILER_LENGTH as i64)))?;
+    file.read_exact(&mut trailer)?;
+    if &trailer[8..] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+
+    let index_offset = read_u64(&trailer);
+    let index_space = (file_length - TRAILER_LENGTH as u64)
+        .checked_sub(index_offset)
+        .and_then(|space| space.checked_sub(8))
+        .ok_or_else(|| invalid_data("index offset is past the end of the file"))?;
+    file.seek(SeekFrom::Start(index_offset))?;
+    let mut start = [0; 8];
+    file.read_exact(&mut start)?;
+    if &start[..4] != INDEX_MAGIC {
+        return Ok(None);
+    }
+
+    // The count is checked against the file length before anything is allocated for it
+    let count = read_u32(&start[4..]) as u64;
+    if count * INDEX_ENTRY_LENGTH as u64 != index_space {
+        return Err(invalid_data("index length does not match the file"));
+    }
+    let mut entries = vec![0; count as usize * INDEX_ENTRY_LENGTH];
+    file.read_exact(&mut entries)?;
+
+    Ok(Some(
+        entries
+            .chunks_exact(INDEX_ENTRY_LENGTH)
+            .map(|e| BlockEntry {
+                stream: e[0],
+                first_time: Duration::from_micros(read_u64(&e[1..])),
+                last_time: Duration::from_micros(read_u64(&e[9..])),
+                offset: read_u64(&e[17..]),
+            })
+            .collect(),
+    ))
+}
+
+/// Walk the blocks from the start, stopping at the first truncated or corrupt one.
+/// Returns the index of intact blocks and where they end.
+fn scan_blocks(file: &mut File, blocks_start: u64) -> io::Result<(Vec<BlockEntry>, u64)> {
+    let file_length = file.seek(SeekFrom::End(0))?;
+    let mut index = Vec::new();
+    let mut offset = blocks_start;
+
+    while offset + BLOCK_HEADER_LENGTH as u64 <= file_length {
+        let mut header = [0; BLOCK_HEADER_LENGTH];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        if &header[..4] != BLOCK_MAGIC {
+            break;
+        }
+
+        let length = read_u32(&header[25..]) as u64;
+        let end = offset + BLOCK_HEADER_LENGTH as u64 + length;
+        if end > file_length {
+            break;
+        }
+        let mut compressed = vec![0; length as usize];
+        file.read_exact(&mut compressed)?;
+        let mut crc = Crc::new();
+        crc.update(&compressed);
+        if crc.sum() != read_u32(&header[29..]) {
+            break;
+        }
+
+        index.push(BlockEntry {
+            stream: header[4],
+            first_time: Duration::from_micros(read_u64(&header[9..])),
+            last_time: Duration::from_micros(read_u64(&header[17..])),
+            offset: offset,
+        });
+        offset = end;
+    }
+
+    Ok((index, offset))
+}
+
+fn write_index(file: &mut File, index: &[BlockEntry], offset: u64) -> io::Result<()> {
+    let mut bytes = INDEX_MAGIC.to_vec();
+    bytes.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    for entry in index.iter() {
+        bytes.push(entry.stream);
+        bytes.extend_from_slice(&(entry.first_time.as_micros() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(entry.last_time.as_micros() as u64).to_le_bytes());
+        bytes.extend_from_slice(&entry.offset.to_le_bytes());
+    }
+    bytes.extend_from_slice(&offset.to_le_bytes());
+    bytes.extend_from_slice(TRAILER_MAGIC);
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The next `length` bytes of a block payload, or an error if it ends first
+fn take<'a>(payload: &'a [u8], position: &mut usize, length: usize) -> io::Result<&'a [u8]> {
+    let end = position
+        .checked_add(length)
+        .filter(|&end| end <= payload.len())
+        .ok_or_else(|| invalid_data("block payload is truncated"))?;
+    let bytes = &payload[*position..end];
+    *position = end;
+
+    Ok(bytes)
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0; 8];
+    array.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const START: Duration = Duration::from_secs(1_575_473_412);
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "meme_machine_{}_{}.{}",
+            name,
+            std::process::id(),
+            SESSION_FILE_EXTENSION
+        ))
+    }
+
+    fn alpha(value: f32) -> MuseMessageType {
+        MuseMessageType::Alpha {
+            a: value,
+            b: value,
+            c: value,
+            d: value,
+        }
+    }
+
+    fn alpha_stream() -> usize {
+        STREAM_NAMES
+            .iter()
+            .position(|&name| name == "alpha")
+            .unwrap()
+    }
+
+    /// One alpha message a second for 25 seconds, so blocks hold 0-9 s, 10-19 s and 20-24 s
+    fn write_alpha(path: &Path) -> SessionFileWriter {
+        let mut writer = SessionFileWriter::create(path, START, None).unwrap();
+        for second in 0..25 {
+            let time = START + Duration::from_secs(second);
+            writer.push_message(time, &alpha(second as f32)).unwrap();
+        }
+
+        writer
+    }
+
+    fn message_seconds(session: &MuseSession) -> Vec<u64> {
+        session
+            .messages
+            .iter()
+            .map(|&(time, _)| (time - START).as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn read_between_block_edges() {
+        let path = temp_path("edges");
+        write_alpha(&path).finish().unwrap();
+        let mut reader = SessionFileReader::open(&path).unwrap();
+
+        let second = |s: u64| START + Duration::from_secs(s);
+        let across = reader.read_between(second(9), second(10)).unwrap();
+        let at_start = reader.read_between(second(10), second(10)).unwrap();
+        let at_end = reader.read_between(second(19), second(19)).unwrap();
+        let between = reader
+            .read_between(
+                second(9) + Duration::from_millis(1),
+                second(10) - Duration::from_millis(1),
+            )
+            .unwrap();
+        let all = reader.read_session().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(message_seconds(&across), vec![9, 10]);
+        assert_eq!(message_seconds(&at_start), vec![10]);
+        assert_eq!(message_seconds(&at_end), vec![19]);
+        assert!(between.messages.is_empty());
+        assert_eq!(message_seconds(&all), (0..25).collect::<Vec<u64>>());
+        match all.messages[24].1 {
+            MuseMessageType::Alpha { a, .. } => assert_eq!(a, 24.0),
+            _ => panic!("expected alpha"),
+        }
+    }
+
+    #[test]
+    fn recover_truncated_file() {
+        let path = temp_path("truncated");
+        // Not finished, as after a crash: the last block is still pending
+        drop(write_alpha(&path));
+        assert_eq!(recover(&path).unwrap(), Some(2));
+        assert_eq!(recover(&path).unwrap(), None);
+        let recovered = SessionFileReader::open(&path).unwrap().read_session();
+
+        // Cut into the second block, which leaves only the first
+        drop(write_alpha(&path));
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(length - 3)
+            .unwrap();
+        let cut_blocks = recover(&path).unwrap();
+        let cut = SessionFileReader::open(&path).unwrap().read_session();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            message_seconds(&recovered.unwrap()),
+            (0..20).collect::<Vec<u64>>()
+        );
+        assert_eq!(cut_blocks, Some(1));
+        assert_eq!(
+            message_seconds(&cut.unwrap()),
+            (0..10).collect::<Vec<u64>>()
+        );
+    }
+
+    #[test]
+    fn sparse_streams_are_flushed() {
+        let path = temp_path("sparse");
+        let mut writer = SessionFileWriter::create(&path, START, None).unwrap();
+        let marker = SessionEvent::Marker {
+            label: String::from("1"),
+        };
+        writer.push_event(START, &marker).unwrap();
+        writer.flush_older_than(START + BLOCK_DURATION).unwrap();
+        drop(writer);
+
+        recover(&path).unwrap();
+        let session = SessionFileReader::open(&path).unwrap().read_session();
+        fs::remove_file(&path).unwrap();
+
+        let session = session.unwrap();
+        assert_eq!(session.events.len(), 1);
+        assert_eq!(session.events[0].1.label(), "marker 1");
+    }
+
+    #[test]
+    fn corrupt_payload_is_an_error() {
+        let path = temp_path("corrupt");
+        let mut writer = SessionFileWriter::create(&path, START, None).unwrap();
+        writer.push_message(START, &alpha(1.0)).unwrap();
+        // A checksummed block whose payload ends in the middle of a sample
+        if let Some(ref mut block) = writer.pending[alpha_stream()] {
+            block.payload.truncate(12);
+        }
+        writer.finish().unwrap();
+
+        let read = SessionFileReader::open(&path).and_then(|mut reader| reader.read_session());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupt_block_fails_its_checksum() {
+        let path = temp_path("checksum");
+        write_alpha(&path).finish().unwrap();
+        let first_block = SessionFileReader::open(&path).unwrap().streams[alpha_stream()][0].offset;
+        // Flip a bit of the first block's compressed payload
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[first_block as usize + BLOCK_HEADER_LENGTH] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let read = SessionFileReader::open(&path).and_then(|mut reader| reader.read_session());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unreadable_file_does_not_stop_recovery() {
+        let directory =
+            env::temp_dir().join(format!("meme_machine_recover_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let bad = directory.join(format!("bad.{}", SESSION_FILE_EXTENSION));
+        let good = directory.join(format!("good.{}", SESSION_FILE_EXTENSION));
+        fs::write(&bad, b"not a session").unwrap();
+        drop(write_alpha(&good));
+
+        let recovered = recover_all(&directory);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(recovered.unwrap(), vec![(good, 2)]);
+    }
+
+    #[test]
+    fn long_event_label_is_refused() {
+        let path = temp_path("label");
+        let mut writer = SessionFileWriter::create(&path, START, None).unwrap();
+        let long = SessionEvent::Marker {
+            label: "x".repeat(u16::MAX as usize),
+        };
+        let short = SessionEvent::Marker {
+            label: String::from("1"),
+        };
+        let refused = writer.push_event(START, &long);
+        writer.push_event(START, &short).unwrap();
+        writer.finish().unwrap();
+        let session = SessionFileReader::open(&path).and_then(|mut reader| reader.read_session());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let session = session.unwrap();
+        assert_eq!(session.events.len(), 1);
+        assert_eq!(session.events[0].1.label(), "marker 1");
+    }
+}
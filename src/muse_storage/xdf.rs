@@ -0,0 +1,480 @@
+use super::MuseSession;
+use crate::muse_packet::{ELECTRODE_NAMES, MOTION_AXES};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const FILE_MAGIC: &[u8] = b"XDF:";
+
+const TAG_FILE_HEADER: u16 = 1;
+const TAG_STREAM_HEADER: u16 = 2;
+const TAG_SAMPLES: u16 = 3;
+const TAG_CLOCK_OFFSET: u16 = 4;
+const TAG_STREAM_FOOTER: u16 = 6;
+
+/// Samples per Samples chunk, so readers never need one huge chunk in memory
+const SAMPLES_PER_CHUNK: usize = 1024;
+
+/// Seconds between ClockOffset measurements, as LabRecorder writes them
+const CLOCK_OFFSET_INTERVAL: f64 = 5.0;
+
+/// Nominal rate of streams that only arrive on change
+const IRREGULAR_RATE: f64 = 0.0;
+
+/// How one `MuseMessageType` family is described as an XDF stream
+struct StreamFamily {
+    message_name: &'static str,
+    name: &'static str,
+    stream_type: &'static str,
+    nominal_srate: f64,
+    labels: &'static [&'static str],
+    unit: &'static str,
+}
+
+/// Every numeric message family gets its own stream. Blinks and jaw clenches go to the marker stream.
+const STREAM_FAMILIES: [StreamFamily; 11] = [
+    StreamFamily {
+        message_name: "eeg",
+        name: "Muse EEG",
+        stream_type: "EEG",
+        nominal_srate: 256.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "microvolts",
+    },
+    StreamFamily {
+        message_name: "acc",
+        name: "Muse Accelerometer",
+        stream_type: "ACC",
+        nominal_srate: 52.0,
+        labels: &MOTION_AXES,
+        unit: "g",
+    },
+    StreamFamily {
+        message_name: "gyro",
+        name: "Muse Gyro",
+        stream_type: "GYRO",
+        nominal_srate: 52.0,
+        labels: &MOTION_AXES,
+        unit: "dps",
+    },
+    StreamFamily {
+        message_name: "alpha",
+        name: "Muse Alpha",
+        stream_type: "AlphaPower",
+        nominal_srate: 10.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "bels",
+    },
+    StreamFamily {
+        message_name: "beta",
+        name: "Muse Beta",
+        stream_type: "BetaPower",
+        nominal_srate: 10.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "bels",
+    },
+    StreamFamily {
+        message_name: "gamma",
+        name: "Muse Gamma",
+        stream_type: "GammaPower",
+        nominal_srate: 10.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "bels",
+    },
+    StreamFamily {
+        message_name: "delta",
+        name: "Muse Delta",
+        stream_type: "DeltaPower",
+        nominal_srate: 10.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "bels",
+    },
+    StreamFamily {
+        message_name: "theta",
+        name: "Muse Theta",
+        stream_type: "ThetaPower",
+        nominal_srate: 10.0,
+        labels: &ELECTRODE_NAMES,
+        unit: "bels",
+    },
+    StreamFamily {
+        message_name: "horseshoe",
+        name: "Muse Horseshoe",
+        stream_type: "Quality",
+        nominal_srate: IRREGULAR_RATE,
+        labels: &ELECTRODE_NAMES,
+        unit: "fit",
+    },
+    StreamFamily {
+        message_name: "batt",
+        name: "Muse Battery",
+        stream_type: "Battery",
+        nominal_srate: IRREGULAR_RATE,
+        labels: &["Charge"],
+        unit: "percent",
+    },
+    StreamFamily {
+        message_name: "touching_forehead",
+        name: "Muse Touching Forehead",
+        stream_type: "Contact",
+        nominal_srate: IRREGULAR_RATE,
+        labels: &["Touching"],
+        unit: "boolean",
+    },
+];
+
+/// Blinks, jaw clenches and session events as one string channel
+const MARKER_STREAM: StreamFamily = StreamFamily {
+    message_name: "markers",
+    name: "Meme Machine Markers",
+    stream_type: "Markers",
+    nominal_srate: IRREGULAR_RATE,
+    labels: &["Marker"],
+    unit: "",
+};
+
+/// Values of one sample in either of the channel formats written
+enum SampleValues<'a> {
+    Float(Vec<f32>),
+    Text(&'a str),
+}
+
+/// Write a session as XDF, with one stream per message family plus a string marker stream.
+///
+/// Timestamps are the receive times in seconds since the UNIX epoch, so all streams
+/// share one clock and every ClockOffset is zero. pyxdf's dejittering recovers
+/// regular sample times for the streams with a nominal rate.
+pub fn write_xdf(path: &Path, session: &MuseSession) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(FILE_MAGIC)?;
+    write_chunk(
+        &mut w,
+        TAG_FILE_HEADER,
+        br#"<?xml version="1.0"?><info><version>1.0</version></info>"#,
+    )?;
+
+    let mut stream_id = 0;
+    for family in STREAM_FAMILIES.iter() {
+        let samples: Vec<(f64, SampleValues)> = session
+            .messages
+            .iter()
+            .filter(|&(_, muse_message_type)| muse_message_type.name() == family.message_name)
+            .map(|&(time, ref muse_message_type)| {
+                (
+                    time.as_secs_f64(),
+                    SampleValues::Float(muse_message_type.values()),
+                )
+            })
+            .collect();
+
+        if !samples.is_empty() {
+            stream_id += 1;
+            let header = stream_header(session, family, "float32");
+            write_stream(&mut w, stream_id, &header, &samples)?;
+        }
+    }
+
+    let annotations = session.annotations();
+    let markers: Vec<(f64, SampleValues)> = annotations
+        .iter()
+        .map(|&(time, ref text)| (time.as_secs_f64(), SampleValues::Text(text)))
+        .collect();
+    let header = stream_header(session, &MARKER_STREAM, "string");
+    write_stream(&mut w, stream_id + 1, &header, &markers)?;
+
+    w.flush()
+}
+
+/// Header, samples, clock offsets and footer of one stream
+fn write_stream<W: Write>(
+    w: &mut W,
+    stream_id: u32,
+    header: &str,
+    samples: &[(f64, SampleValues)],
+) -> io::Result<()> {
+    let mut content = stream_id.to_le_bytes().to_vec();
+    content.extend_from_slice(header.as_bytes());
+    write_chunk(w, TAG_STREAM_HEADER, &content)?;
+
+    for chunk in samples.chunks(SAMPLES_PER_CHUNK) {
+        let mut content = stream_id.to_le_bytes().to_vec();
+        push_length(&mut content, chunk.len() as u64);
+        for &(timestamp, ref values) in chunk.iter() {
+            content.push(8);
+            content.extend_from_slice(&timestamp.to_le_bytes());
+            match *values {
+                SampleValues::Float(ref values) => {
+                    for value in values.iter() {
+                        content.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                SampleValues::Text(text) => {
+                    push_length(&mut content, text.len() as u64);
+                    content.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        write_chunk(w, TAG_SAMPLES, &content)?;
+    }
+
+    let first_timestamp = samples.first().map_or(0.0, |&(t, _)| t);
+    let last_timestamp = samples.last().map_or(0.0, |&(t, _)| t);
+    let mut offset_times = vec![first_timestamp];
+    while offset_times[offset_times.len() - 1] + CLOCK_OFFSET_INTERVAL < last_timestamp {
+        let next = offset_times[offset_times.len() - 1] + CLOCK_OFFSET_INTERVAL;
+        offset_times.push(next);
+    }
+    if last_timestamp > first_timestamp {
+        offset_times.push(last_timestamp);
+    }
+
+    let mut offsets_xml = String::new();
+    for &time in offset_times.iter() {
+        let mut content = stream_id.to_le_bytes().to_vec();
+        content.extend_from_slice(&time.to_le_bytes());
+        content.extend_from_slice(&0.0f64.to_le_bytes());
+        write_chunk(w, TAG_CLOCK_OFFSET, &content)?;
+        offsets_xml.push_str(&format!(
+            "<offset><time>{}</time><value>0</value></offset>",
+            time
+        ));
+    }
+
+    let footer = format!(
+        "<?xml version=\"1.0\"?><info><first_timestamp>{}</first_timestamp>\
+         <last_timestamp>{}</last_timestamp><sample_count>{}</sample_count>\
+         <clock_offsets>{}</clock_offsets></info>",
+        first_timestamp,
+        last_timestamp,
+        samples.len(),
+        offsets_xml
+    );
+    let mut content = stream_id.to_le_bytes().to_vec();
+    content.extend_from_slice(footer.as_bytes());
+    write_chunk(w, TAG_STREAM_FOOTER, &content)
+}
+
+/// StreamHeader XML with the fields pyxdf and MNE read, and channel labels in `desc`
+fn stream_header(session: &MuseSession, family: &StreamFamily, channel_format: &str) -> String {
+    let channels: String = family
+        .labels
+        .iter()
+        .map(|label| {
+            format!(
+                "<channel><label>{}</label><unit>{}</unit><type>{}</type></channel>",
+                label, family.unit, family.stream_type
+            )
+        })
+        .collect();
+    // Sessions from different headsets get different source ids
+    let device = session
+        .device
+        .map_or_else(|| String::from("unknown"), |device| device.to_string());
+
+    format!(
+        "<?xml version=\"1.0\"?><info><name>{}</name><type>{}</type>\
+         <channel_count>{}</channel_count><nominal_srate>{}</nominal_srate>\
+         <channel_format>{}</channel_format><source_id>meme_machine_{}_{}</source_id>\
+         <version>1.1</version><created_at>{}</created_at>\
+         <desc><channels>{}</channels>\
+         <acquisition><manufacturer>Interaxon</manufacturer><model>Muse</model></acquisition>\
+         </desc></info>",
+        family.name,
+        family.stream_type,
+        family.labels.len(),
+        family.nominal_srate,
+        channel_format,
+        device,
+        family.message_name,
+        session.start_time.as_secs_f64(),
+        channels
+    )
+}
+
+/// One chunk: variable length byte count including the tag, the tag, then the content
+fn write_chunk<W: Write>(w: &mut W, tag: u16, content: &[u8]) -> io::Result<()> {
+    let mut header = Vec::with_capacity(11);
+    push_length(&mut header, content.len() as u64 + 2);
+    header.extend_from_slice(&tag.to_le_bytes());
+    w.write_all(&header)?;
+    w.write_all(content)
+}
+
+/// XDF variable length integer: a byte count of 1, 4 or 8, then the little endian value
+fn push_length(bytes: &mut Vec<u8>, length: u64) {
+    if length <= u64::from(u8::MAX) {
+        bytes.push(1);
+        bytes.push(length as u8);
+    } else if length <= u64::from(u32::MAX) {
+        bytes.push(4);
+        bytes.extend_from_slice(&(length as u32).to_le_bytes());
+    } else {
+        bytes.push(8);
+        bytes.extend_from_slice(&length.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_packet::MuseMessageType;
+    use crate::muse_storage::SessionEvent;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    const START_MS: u64 = 1_575_473_412_375;
+
+    fn session() -> MuseSession {
+        let start = Duration::from_millis(START_MS);
+        let mut session = MuseSession::new(start);
+        for i in 0..300 {
+            let time = start + Duration::from_secs_f64(i as f64 / 256.0);
+            let value = i as f32;
+            session.messages.push((
+                time,
+                MuseMessageType::Eeg {
+                    a: value,
+                    b: value + 1.0,
+                    c: value + 2.0,
+                    d: value + 3.0,
+                },
+            ));
+        }
+        for i in 0..10 {
+            let time = start + Duration::from_millis(i * 100);
+            session.messages.push((
+                time,
+                MuseMessageType::Alpha {
+                    a: 0.5,
+                    b: 0.5,
+                    c: 0.5,
+                    d: 0.5,
+                },
+            ));
+        }
+        session.messages.push((
+            start + Duration::from_millis(500),
+            MuseMessageType::Blink { blink: true },
+        ));
+        session.messages.sort_by_key(|&(time, _)| time);
+        session.events.push((
+            start + Duration::from_millis(1250),
+            SessionEvent::Marker {
+                label: String::from("onset cat"),
+            },
+        ));
+
+        session
+    }
+
+    fn read_length(bytes: &[u8], position: &mut usize) -> usize {
+        let width = bytes[*position] as usize;
+        let mut value = [0u8; 8];
+        value[..width].copy_from_slice(&bytes[*position + 1..*position + 1 + width]);
+        *position += 1 + width;
+
+        u64::from_le_bytes(value) as usize
+    }
+
+    /// Tag and content of every chunk after the magic
+    fn chunks(bytes: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut chunks = Vec::new();
+        let mut position = FILE_MAGIC.len();
+        while position < bytes.len() {
+            let length = read_length(bytes, &mut position);
+            let tag = u16::from_le_bytes([bytes[position], bytes[position + 1]]);
+            chunks.push((tag, &bytes[position + 2..position + length]));
+            position += length;
+        }
+        assert_eq!(position, bytes.len());
+
+        chunks
+    }
+
+    fn stream_id(content: &[u8]) -> u32 {
+        u32::from_le_bytes([content[0], content[1], content[2], content[3]])
+    }
+
+    fn xml_field<'a>(xml: &'a str, name: &str) -> &'a str {
+        let open = format!("<{}>", name);
+        let start = xml.find(&open).unwrap() + open.len();
+        let end = xml[start..].find('<').unwrap() + start;
+
+        &xml[start..end]
+    }
+
+    #[test]
+    fn chunk_stream() {
+        let path = env::temp_dir().join(format!("meme_machine_xdf_{}.xdf", std::process::id()));
+        write_xdf(&path, &session()).unwrap();
+        let bytes = fs::read(&path);
+        fs::remove_file(&path).unwrap();
+        let bytes = bytes.unwrap();
+
+        assert_eq!(&bytes[..FILE_MAGIC.len()], FILE_MAGIC);
+        let chunks = chunks(&bytes);
+        assert_eq!(chunks[0].0, TAG_FILE_HEADER);
+
+        let mut headers: BTreeMap<u32, String> = BTreeMap::new();
+        let mut sample_counts: BTreeMap<u32, usize> = BTreeMap::new();
+        let mut footer_counts: BTreeMap<u32, usize> = BTreeMap::new();
+        let mut markers = Vec::new();
+        for &(tag, content) in chunks[1..].iter() {
+            let id = stream_id(content);
+            match tag {
+                TAG_STREAM_HEADER => {
+                    headers.insert(id, String::from_utf8_lossy(&content[4..]).into_owned());
+                }
+                TAG_SAMPLES => {
+                    let header = &headers[&id];
+                    let channels: usize = xml_field(header, "channel_count").parse().unwrap();
+                    let text = xml_field(header, "channel_format") == "string";
+
+                    let mut position = 4;
+                    let count = read_length(content, &mut position);
+                    for _ in 0..count {
+                        assert_eq!(content[position], 8);
+                        position += 9;
+                        if text {
+                            let length = read_length(content, &mut position);
+                            markers.push(
+                                String::from_utf8_lossy(&content[position..position + length])
+                                    .into_owned(),
+                            );
+                            position += length;
+                        } else {
+                            position += 4 * channels;
+                        }
+                    }
+                    assert_eq!(position, content.len());
+                    *sample_counts.entry(id).or_insert(0) += count;
+                }
+                TAG_CLOCK_OFFSET => {
+                    assert_eq!(content.len(), 20);
+                    let mut value = [0u8; 8];
+                    value.copy_from_slice(&content[12..]);
+                    assert_eq!(f64::from_le_bytes(value), 0.0);
+                }
+                TAG_STREAM_FOOTER => {
+                    let footer = String::from_utf8_lossy(&content[4..]).into_owned();
+                    let count = xml_field(&footer, "sample_count").parse().unwrap();
+                    footer_counts.insert(id, count);
+                }
+                _ => panic!("Unexpected chunk tag {}", tag),
+            }
+        }
+
+        let names: Vec<&str> = headers
+            .values()
+            .map(|header| xml_field(header, "name"))
+            .collect();
+        assert_eq!(names, ["Muse EEG", "Muse Alpha", "Meme Machine Markers"]);
+        let expected: BTreeMap<u32, usize> = vec![(1, 300), (2, 10), (3, 2)].into_iter().collect();
+        assert_eq!(sample_counts, expected);
+        assert_eq!(footer_counts, expected);
+        assert_eq!(markers, ["blink", "marker onset cat"]);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::derived_metrics::DerivedMetrics;
+use crate::muse_packet::Band;
+use crate::synchrony::SynchronySample;
+use nannou_osc as osc;
+use std::env;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::io;
+use std::net::SocketAddr;
+
+/// Comma separated `host:port` list of OSC destinations, e.g. "127.0.0.1:7000,10.0.0.5:9000"
+pub const OSC_RELAY_ENV: &str = "MEME_MACHINE_OSC_RELAY";
+
+const METRICS_PREFIX: &str = "/meme_machine";
+
+/// Re-sends received Muse packets and derived metrics to other OSC listeners
+pub struct OscRelay {
+    sender: osc::Sender,
+    destinations: Vec<SocketAddr>,
+}
+
+impl Debug for OscRelay {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<OscRelay: {:?}>", self.destinations)
+    }
+}
+
+impl OscRelay {
+    pub fn new(destinations: Vec<SocketAddr>) -> io::Result<OscRelay> {
+        let sender = osc::sender()?;
+
+        Ok(OscRelay {
+            sender: sender,
+            destinations: destinations,
+        })
+    }
+
+    /// A relay to the destinations in `MEME_MACHINE_OSC_RELAY`, if it is set and the relay
+    /// socket can be bound
+    pub fn from_env() -> Option<OscRelay> {
+        let list = env::var(OSC_RELAY_ENV).ok()?;
+        let destinations: Vec<SocketAddr> = list
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| match s.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => {
+                    eprintln!("Ignoring OSC relay destination {:?}", s);
+                    None
+                }
+            })
+            .collect();
+
+        if destinations.is_empty() {
+            return None;
+        }
+
+        match OscRelay::new(destinations) {
+            Ok(osc_relay) => Some(osc_relay),
+            Err(e) => {
+                eprintln!("Could not bind OSC relay socket: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Pass an incoming packet through unchanged
+    pub fn forward(&self, packet: &osc::Packet) {
+        for destination in self.destinations.iter() {
+            if let Err(e) = self.sender.send(packet.clone(), destination) {
+                eprintln!("Could not relay OSC packet to {}: {}", destination, e);
+            }
+        }
+    }
+
+    pub fn send_metrics(&self, metrics: &DerivedMetrics) {
+        for band in Band::ALL.iter() {
+            let values = metrics.smoothed_band(*band);
+            self.send(
+                &format!("{}/smoothed/{}", METRICS_PREFIX, band.stream_name()),
+                values.iter().map(|&v| osc::Type::Float(v)).collect(),
+            );
+        }
+
+        self.send(
+            &format!("{}/asymmetry", METRICS_PREFIX),
+            vec![osc::Type::Float(metrics.asymmetry)],
+        );
+        self.send(
+            &format!("{}/drowsiness", METRICS_PREFIX),
+            vec![osc::Type::Float(metrics.drowsiness)],
+        );
+        self.send(
+            &format!("{}/quality", METRICS_PREFIX),
+            vec![osc::Type::Float(metrics.quality)],
+        );
+    }
+
+    /// Synchrony of the compared pair per band. Values that could not be computed are skipped.
+    pub fn send_synchrony(&self, sample: &SynchronySample) {
+        for band in Band::ALL.iter() {
+            let values = [
+                ("correlation", sample.correlation[band.index()]),
+                ("plv", sample.plv[band.index()]),
+            ];
+            for &(kind, value) in values.iter() {
+                if let Some(value) = value {
+                    self.send(
+                        &format!(
+                            "{}/synchrony/{}/{}",
+                            METRICS_PREFIX,
+                            kind,
+                            band.stream_name()
+                        ),
+                        vec![osc::Type::Float(value)],
+                    );
+                }
+            }
+        }
+    }
+
+    fn send(&self, addr: &str, args: Vec<osc::Type>) {
+        let packet = osc::Packet::Message(osc::Message {
+            addr: addr.to_string(),
+            args: Some(args),
+        });
+        self.forward(&packet);
+    }
+}
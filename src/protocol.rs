@@ -0,0 +1,444 @@
+use crate::rating::RatingScale;
+use crate::theme::Theme;
+use nannou::prelude::*;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Path of a JSON protocol file to run with Return
+pub const PROTOCOL_ENV: &str = "MEME_MACHINE_PROTOCOL";
+
+const TEXT_WIDTH: f32 = 900.0;
+const TEXT_FONT_SIZE: u32 = 36;
+const STATUS_FONT_SIZE: u32 = 16;
+const STATUS_MARGIN: f32 = 30.0;
+
+/// What a protocol step does besides showing its text and view
+#[derive(Clone, Debug, PartialEq)]
+pub enum StepKind {
+    /// Text on a blank screen, such as a welcome or a break
+    Instructions,
+    /// A baseline recording such as eyes open or eyes closed
+    Calibration,
+    Rest,
+    /// Present memes, all of them or the first `count`. Ends when the presentation does.
+    Stimuli {
+        count: Option<usize>,
+    },
+    /// Questions shown one at a time on a blank screen, each answered out loud and
+    /// confirmed with Return
+    Questionnaire {
+        questions: Vec<String>,
+    },
+    /// Rating scales answered on screen, by name or as scale objects. Ends when all are answered.
+    Rating {
+        scales: Vec<RatingScale>,
+    },
+}
+
+/// One step of a protocol, as written in the protocol file:
+///
+/// `{"type": "calibration", "label": "eyes_closed", "text": "Close your eyes", "duration": 60, "view": "History"}`
+///
+/// `label` names the step's start and end markers and defaults to the type.
+/// Without a `duration` the step waits for Return.
+#[derive(Clone, Debug)]
+pub struct ProtocolStep {
+    pub kind: StepKind,
+    pub label: String,
+    pub text: Option<String>,
+    pub view: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+impl ProtocolStep {
+    fn from_json(value: &Value) -> Result<ProtocolStep, String> {
+        let step_type = value["type"]
+            .as_str()
+            .ok_or_else(|| String::from("step without a \"type\""))?;
+        let kind = match step_type {
+            "instructions" => StepKind::Instructions,
+            "calibration" => StepKind::Calibration,
+            "rest" => StepKind::Rest,
+            "stimuli" => StepKind::Stimuli {
+                count: value["count"].as_u64().map(|count| count as usize),
+            },
+            "questionnaire" => StepKind::Questionnaire {
+                questions: value["questions"]
+                    .as_array()
+                    .map(|questions| {
+                        questions
+                            .iter()
+                            .filter_map(|q| q.as_str().map(String::from))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            },
+            // With no scales the rating screen would open already finished and never close
+            "rating" => StepKind::Rating {
+                scales: value["scales"]
+                    .as_array()
+                    .filter(|scales| !scales.is_empty())
+                    .ok_or_else(|| String::from("rating step without a \"scales\" list"))?
+                    .iter()
+                    .map(RatingScale::from_json)
+                    .collect::<Result<Vec<RatingScale>, String>>()?,
+            },
+            _ => return Err(format!("unknown step type {:?}", step_type)),
+        };
+        let duration = match value["duration"].as_f64() {
+            Some(seconds) if seconds >= 0.0 => Some(Duration::from_secs_f64(seconds)),
+            Some(_) => return Err(format!("negative duration in {} step", step_type)),
+            None => None,
+        };
+
+        Ok(ProtocolStep {
+            kind: kind,
+            label: value["label"].as_str().unwrap_or(step_type).to_string(),
+            text: value["text"].as_str().map(String::from),
+            view: value["view"].as_str().map(String::from),
+            duration: duration,
+        })
+    }
+
+    /// Instruction and question steps replace the view with their text
+    fn hides_view(&self) -> bool {
+        match self.kind {
+            StepKind::Instructions | StepKind::Questionnaire { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Ends whatever the step left running when it is left early
+    fn closing_action(&self) -> Option<ProtocolAction> {
+        match self.kind {
+            StepKind::Stimuli { .. } => Some(ProtocolAction::StopStimuli),
+            StepKind::Rating { .. } => Some(ProtocolAction::CloseRatings),
+            _ => None,
+        }
+    }
+}
+
+/// A scripted session, e.g.
+///
+/// ```json
+/// {"name": "Meme study", "record": true, "steps": [
+///     {"type": "instructions", "text": "Sit comfortably and relax"},
+///     {"type": "calibration", "label": "eyes_open", "text": "Keep your eyes open", "duration": 60},
+///     {"type": "calibration", "label": "eyes_closed", "text": "Close your eyes", "duration": 60},
+///     {"type": "stimuli", "count": 10},
+///     {"type": "rest", "text": "Relax", "duration": 30, "view": "History"},
+///     {"type": "questionnaire", "questions": ["Which meme was the funniest?"]},
+///     {"type": "rating", "label": "mood", "scales": ["valence", "arousal"]}
+/// ]}
+/// ```
+#[derive(Clone, Debug)]
+pub struct Protocol {
+    pub name: String,
+    /// Record one session from the first step to the last
+    pub record: bool,
+    pub steps: Vec<ProtocolStep>,
+}
+
+impl Protocol {
+    pub fn load(path: &Path) -> Result<Protocol, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let value: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let steps = value["steps"]
+            .as_array()
+            .ok_or_else(|| String::from("no \"steps\" list"))?
+            .iter()
+            .map(ProtocolStep::from_json)
+            .collect::<Result<Vec<ProtocolStep>, String>>()?;
+        if steps.is_empty() {
+            return Err(String::from("no steps"));
+        }
+
+        Ok(Protocol {
+            name: value["name"].as_str().unwrap_or("Protocol").to_string(),
+            record: value["record"].as_bool().unwrap_or(true),
+            steps: steps,
+        })
+    }
+}
+
+/// Something the protocol needs done outside itself, applied by the caller in order
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtocolAction {
+    Marker(String),
+    SelectView(String),
+    StartRecording,
+    StopRecording,
+    PresentStimuli(Option<usize>),
+    StopStimuli,
+    ShowRatings(Vec<RatingScale>),
+    CloseRatings,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RunState {
+    Idle,
+    Running {
+        step: usize,
+        started: Duration,
+        question: usize,
+    },
+}
+
+/// Steps through a protocol, started and advanced with Return and stopped with Backspace
+#[derive(Debug)]
+pub struct ProtocolRunner {
+    protocol: Protocol,
+    state: RunState,
+}
+
+impl ProtocolRunner {
+    pub fn new(protocol: Protocol) -> ProtocolRunner {
+        ProtocolRunner {
+            protocol: protocol,
+            state: RunState::Idle,
+        }
+    }
+
+    /// A runner for the protocol file in `MEME_MACHINE_PROTOCOL`, if it is set and valid
+    pub fn from_env() -> Option<ProtocolRunner> {
+        let path = env::var(PROTOCOL_ENV).ok()?;
+        match Protocol::load(Path::new(&path)) {
+            Ok(protocol) => {
+                println!(
+                    "Loaded protocol {} with {} steps, press Return to start",
+                    protocol.name,
+                    protocol.steps.len()
+                );
+                Some(ProtocolRunner::new(protocol))
+            }
+            Err(e) => {
+                eprintln!("Could not load protocol {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state != RunState::Idle
+    }
+
+    /// True while the current step shows only its text
+    pub fn hides_view(&self) -> bool {
+        self.current_step().map_or(false, ProtocolStep::hides_view)
+    }
+
+    pub fn start(&mut self, now: Duration) -> Vec<ProtocolAction> {
+        let mut actions = vec![ProtocolAction::Marker(format!(
+            "protocol {} start",
+            self.protocol.name
+        ))];
+        if self.protocol.record {
+            actions.insert(0, ProtocolAction::StartRecording);
+        }
+        self.enter_step(0, now, &mut actions);
+
+        actions
+    }
+
+    /// End the current step when its time is up, a stimulus step when the presentation ends,
+    /// or a rating step when its ratings are answered
+    pub fn update(
+        &mut self,
+        now: Duration,
+        presenting: bool,
+        rating_open: bool,
+    ) -> Vec<ProtocolAction> {
+        let mut actions = Vec::new();
+        let (step, started) = match self.state {
+            RunState::Running { step, started, .. } => (step, started),
+            RunState::Idle => return actions,
+        };
+        let current = &self.protocol.steps[step];
+
+        let timed_out = current
+            .duration
+            .map_or(false, |duration| now >= started + duration);
+        let presentation_over = match current.kind {
+            StepKind::Stimuli { .. } => !presenting && now > started,
+            StepKind::Rating { .. } => !rating_open && now > started,
+            _ => false,
+        };
+        if timed_out || presentation_over {
+            self.next_step(now, &mut actions);
+        }
+
+        actions
+    }
+
+    /// Return: the next question of a questionnaire, otherwise the next step
+    pub fn advance(&mut self, now: Duration) -> Vec<ProtocolAction> {
+        let mut actions = Vec::new();
+        if let RunState::Running {
+            step,
+            started,
+            question,
+        } = self.state
+        {
+            if let StepKind::Questionnaire { ref questions } = self.protocol.steps[step].kind {
+                if question + 1 < questions.len() {
+                    actions.push(ProtocolAction::Marker(format!(
+                        "{} question {}",
+                        self.protocol.steps[step].label,
+                        question + 2
+                    )));
+                    self.state = RunState::Running {
+                        step: step,
+                        started: started,
+                        question: question + 1,
+                    };
+                    return actions;
+                }
+            }
+            self.next_step(now, &mut actions);
+        }
+
+        actions
+    }
+
+    /// Backspace: abandon the protocol, ending the current step and the recording
+    pub fn stop(&mut self) -> Vec<ProtocolAction> {
+        let mut actions = Vec::new();
+        if let Some(step) = self.current_step() {
+            actions.extend(step.closing_action());
+            actions.push(ProtocolAction::Marker(format!("{} end", step.label)));
+            actions.push(ProtocolAction::Marker(format!(
+                "protocol {} stopped",
+                self.protocol.name
+            )));
+            if self.protocol.record {
+                actions.push(ProtocolAction::StopRecording);
+            }
+        }
+        self.state = RunState::Idle;
+
+        actions
+    }
+
+    fn current_step(&self) -> Option<&ProtocolStep> {
+        match self.state {
+            RunState::Running { step, .. } => self.protocol.steps.get(step),
+            RunState::Idle => None,
+        }
+    }
+
+    fn next_step(&mut self, now: Duration, actions: &mut Vec<ProtocolAction>) {
+        let step = match self.state {
+            RunState::Running { step, .. } => step,
+            RunState::Idle => return,
+        };
+        let current = &self.protocol.steps[step];
+        actions.extend(current.closing_action());
+        actions.push(ProtocolAction::Marker(format!("{} end", current.label)));
+
+        self.enter_step(step + 1, now, actions);
+    }
+
+    fn enter_step(&mut self, step: usize, now: Duration, actions: &mut Vec<ProtocolAction>) {
+        let next = match self.protocol.steps.get(step) {
+            Some(next) => next,
+            None => {
+                actions.push(ProtocolAction::Marker(format!(
+                    "protocol {} end",
+                    self.protocol.name
+                )));
+                if self.protocol.record {
+                    actions.push(ProtocolAction::StopRecording);
+                }
+                println!("Finished protocol {}", self.protocol.name);
+                self.state = RunState::Idle;
+                return;
+            }
+        };
+
+        if let Some(ref view) = next.view {
+            actions.push(ProtocolAction::SelectView(view.clone()));
+        }
+        actions.push(ProtocolAction::Marker(format!("{} start", next.label)));
+        match next.kind {
+            StepKind::Stimuli { count } => actions.push(ProtocolAction::PresentStimuli(count)),
+            StepKind::Rating { ref scales } => {
+                actions.push(ProtocolAction::ShowRatings(scales.clone()))
+            }
+            _ => {}
+        }
+        self.state = RunState::Running {
+            step: step,
+            started: now,
+            question: 0,
+        };
+    }
+
+    /// The step's text or question, and a status line with progress and what ends the step
+    pub fn draw(&self, app: &App, draw: &nannou::app::Draw, now: Duration, theme: &Theme) {
+        let (step, started, question) = match self.state {
+            RunState::Running {
+                step,
+                started,
+                question,
+            } => (step, started, question),
+            RunState::Idle => return,
+        };
+        let current = &self.protocol.steps[step];
+        let win = app.window_rect();
+
+        if current.hides_view() {
+            draw.background().color(theme.background);
+        }
+
+        let text = match current.kind {
+            StepKind::Questionnaire { ref questions } => {
+                questions.get(question).or(current.text.as_ref())
+            }
+            _ => current.text.as_ref(),
+        };
+        if let Some(text) = text {
+            let y = if current.hides_view() {
+                0.0
+            } else {
+                win.top() - TEXT_FONT_SIZE as f32 * 2.0
+            };
+            theme
+                .text(draw, text)
+                .x_y(0.0, y)
+                .w(TEXT_WIDTH.min(win.w()))
+                .font_size(TEXT_FONT_SIZE)
+                .color(theme.foreground);
+        }
+
+        let until = match current.duration {
+            Some(duration) => {
+                let remaining = (started + duration)
+                    .checked_sub(now)
+                    .unwrap_or(Duration::from_secs(0));
+                format!("{} s left", remaining.as_secs() + 1)
+            }
+            None => match current.kind {
+                StepKind::Stimuli { .. } => String::from("until the memes end"),
+                StepKind::Rating { .. } => String::from("until rated"),
+                _ => String::from("Return to continue"),
+            },
+        };
+        let status = format!(
+            "{}  step {}/{} {}  {}  (Backspace to stop)",
+            self.protocol.name,
+            step + 1,
+            self.protocol.steps.len(),
+            current.label,
+            until
+        );
+        theme
+            .text(draw, &status)
+            .x_y(0.0, win.bottom() + STATUS_MARGIN)
+            .w(win.w())
+            .font_size(STATUS_FONT_SIZE)
+            .color(theme.accent);
+    }
+}
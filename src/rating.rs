@@ -0,0 +1,240 @@
+use crate::muse_storage::SessionEvent;
+use crate::theme::Theme;
+use nannou::prelude::*;
+use serde_json::Value;
+use std::env;
+
+/// Comma separated rating scales shown after each meme, e.g. "valence,arousal,funny"
+pub const MEME_RATINGS_ENV: &str = "MEME_MACHINE_MEME_RATINGS";
+
+/// Largest value on a scale, so every value has a number key
+const MAX_VALUE: u8 = 9;
+
+const PROMPT_FONT_SIZE: u32 = 36;
+const LABEL_FONT_SIZE: u32 = 20;
+const BOX_SIZE: f32 = 70.0;
+const BOX_SPACING: f32 = 20.0;
+
+/// A row of numbered boxes from `min` to `max` with words at both ends
+#[derive(Clone, Debug, PartialEq)]
+pub struct RatingScale {
+    /// Short name without spaces, used in the stored event
+    pub name: String,
+    pub prompt: String,
+    pub min: u8,
+    pub max: u8,
+    pub low: String,
+    pub high: String,
+}
+
+impl RatingScale {
+    pub fn new(name: &str, prompt: &str, min: u8, max: u8, low: &str, high: &str) -> RatingScale {
+        RatingScale {
+            name: String::from(name),
+            prompt: String::from(prompt),
+            min: min,
+            max: max,
+            low: String::from(low),
+            high: String::from(high),
+        }
+    }
+
+    /// The Self-Assessment Manikin valence and arousal scales, and "funny"
+    pub fn builtin(name: &str) -> Option<RatingScale> {
+        match name {
+            "valence" => Some(RatingScale::new(
+                "valence",
+                "How did that make you feel?",
+                1,
+                9,
+                "unhappy",
+                "happy",
+            )),
+            "arousal" => Some(RatingScale::new(
+                "arousal",
+                "How calm or excited do you feel?",
+                1,
+                9,
+                "calm",
+                "excited",
+            )),
+            "funny" => Some(RatingScale::new(
+                "funny",
+                "Was it funny?",
+                1,
+                5,
+                "not at all",
+                "hilarious",
+            )),
+            _ => None,
+        }
+    }
+
+    /// A built-in scale by name, or a scale object such as
+    /// `{"name": "cringe", "prompt": "How cringe?", "min": 1, "max": 7, "low": "not", "high": "very"}`
+    pub fn from_json(value: &Value) -> Result<RatingScale, String> {
+        if let Some(name) = value.as_str() {
+            return RatingScale::builtin(name).ok_or_else(|| format!("unknown scale {:?}", name));
+        }
+
+        let name = value["name"]
+            .as_str()
+            .ok_or_else(|| String::from("scale without a \"name\""))?;
+        let min = value["min"].as_u64().unwrap_or(1);
+        let max = value["max"].as_u64().unwrap_or(u64::from(MAX_VALUE));
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("scale name {:?} must be one word", name));
+        }
+        if min >= max || max > u64::from(MAX_VALUE) {
+            return Err(format!(
+                "scale {} must have min < max <= {}",
+                name, MAX_VALUE
+            ));
+        }
+
+        Ok(RatingScale::new(
+            name,
+            value["prompt"].as_str().unwrap_or(name),
+            min as u8,
+            max as u8,
+            value["low"].as_str().unwrap_or(""),
+            value["high"].as_str().unwrap_or(""),
+        ))
+    }
+
+    /// The built-in scales named in `MEME_MACHINE_MEME_RATINGS`
+    pub fn from_env() -> Vec<RatingScale> {
+        let names = match env::var(MEME_RATINGS_ENV) {
+            Ok(names) => names,
+            Err(_) => return Vec::new(),
+        };
+
+        names
+            .split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| match RatingScale::builtin(name) {
+                Some(scale) => Some(scale),
+                None => {
+                    eprintln!("Ignoring unknown rating scale {:?}", name);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rating scales asked one after another, answered with a click or a number key
+#[derive(Debug)]
+pub struct RatingScreen {
+    scales: Vec<RatingScale>,
+    current: usize,
+    /// The meme being rated, stored with each answer
+    stimulus: Option<String>,
+}
+
+impl RatingScreen {
+    pub fn new(scales: Vec<RatingScale>, stimulus: Option<String>) -> RatingScreen {
+        RatingScreen {
+            scales: scales,
+            current: 0,
+            stimulus: stimulus,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.current >= self.scales.len()
+    }
+
+    /// Answer the current scale and move to the next. Values off the scale are ignored.
+    pub fn answer(&mut self, value: u8) -> Option<SessionEvent> {
+        let scale = self.scales.get(self.current)?;
+        if value < scale.min || value > scale.max {
+            return None;
+        }
+        self.current += 1;
+
+        Some(SessionEvent::Rating {
+            scale: scale.name.clone(),
+            value: value,
+            stimulus: self.stimulus.clone(),
+        })
+    }
+
+    /// The value of the box under a window point
+    pub fn value_at(&self, point: Point2) -> Option<u8> {
+        let scale = self.scales.get(self.current)?;
+        (scale.min..=scale.max).find(|&value| {
+            let (x, y) = box_position(scale, value);
+            (point.x - x).abs() <= BOX_SIZE / 2.0 && (point.y - y).abs() <= BOX_SIZE / 2.0
+        })
+    }
+
+    pub fn draw(&self, app: &App, draw: &nannou::app::Draw, theme: &Theme) {
+        let scale = match self.scales.get(self.current) {
+            Some(scale) => scale,
+            None => return,
+        };
+        let win = app.window_rect();
+        let hovered = self.value_at(app.mouse.position());
+
+        theme
+            .text(draw, &scale.prompt)
+            .x_y(0.0, BOX_SIZE * 2.5)
+            .w(win.w())
+            .font_size(PROMPT_FONT_SIZE)
+            .color(theme.foreground);
+
+        for value in scale.min..=scale.max {
+            let (x, y) = box_position(scale, value);
+            if hovered == Some(value) {
+                draw.rect()
+                    .x_y(x, y)
+                    .w_h(BOX_SIZE, BOX_SIZE)
+                    .color(theme.accent);
+            }
+            draw.rect()
+                .x_y(x, y)
+                .w_h(BOX_SIZE, BOX_SIZE)
+                .no_fill()
+                .stroke(theme.foreground)
+                .stroke_weight(2.0);
+            theme
+                .text(draw, &value.to_string())
+                .x_y(x, y)
+                .font_size(PROMPT_FONT_SIZE)
+                .color(theme.foreground);
+        }
+
+        let (low_x, y) = box_position(scale, scale.min);
+        let (high_x, _) = box_position(scale, scale.max);
+        for &(x, label) in [(low_x, &scale.low), (high_x, &scale.high)].iter() {
+            theme
+                .text(draw, label)
+                .x_y(x, y - BOX_SIZE)
+                .w(BOX_SIZE * 3.0)
+                .font_size(LABEL_FONT_SIZE)
+                .color(theme.foreground);
+        }
+
+        if self.scales.len() > 1 {
+            theme
+                .text(
+                    draw,
+                    &format!("{} / {}", self.current + 1, self.scales.len()),
+                )
+                .x_y(0.0, y - BOX_SIZE * 2.0)
+                .font_size(LABEL_FONT_SIZE)
+                .color(theme.foreground);
+        }
+    }
+}
+
+/// Centre of the box for a value, with the row centred in the window
+fn box_position(scale: &RatingScale, value: u8) -> (f32, f32) {
+    let count = (scale.max - scale.min + 1) as f32;
+    let width = count * BOX_SIZE + (count - 1.0) * BOX_SPACING;
+    let x = -width / 2.0 + BOX_SIZE / 2.0 + (value - scale.min) as f32 * (BOX_SIZE + BOX_SPACING);
+
+    (x, 0.0)
+}
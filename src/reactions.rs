@@ -0,0 +1,270 @@
+use crate::muse_packet::{Band, MuseMessageType};
+use crate::muse_storage::{MuseSession, SessionEvent};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Window before each onset that the response is measured against, the blank between memes.
+/// It is cut short at the offset of the previous meme when the blank is shorter.
+const BASELINE_DURATION: Duration = Duration::from_secs(2);
+
+/// Electrode indices of AF7 and AF8, the frontal pair used for alpha asymmetry
+const LEFT_FRONTAL: usize = 1;
+const RIGHT_FRONTAL: usize = 2;
+
+/// Column names of `StimulusReaction::metrics`, in order
+pub const METRIC_NAMES: [&str; 6] = [
+    "score",
+    "valence",
+    "arousal",
+    "engagement",
+    "blink_rate",
+    "jaw_clenches",
+];
+
+/// How one presentation of a meme changed the signals compared with the blank before it
+#[derive(Clone, Debug)]
+pub struct StimulusReaction {
+    pub name: String,
+    pub onset: Duration,
+    pub offset: Duration,
+    /// Change in frontal alpha asymmetry, AF8 minus AF7 alpha in bels. Positive is approach.
+    pub valence: f32,
+    /// Change in mean beta over alpha, in bels
+    pub arousal: f32,
+    /// Change in beta / (alpha + theta), in bels
+    pub engagement: f32,
+    /// Blinks per minute while shown, minus the rate in the baseline
+    pub blink_rate: f32,
+    pub jaw_clenches: usize,
+}
+
+impl StimulusReaction {
+    /// Overall response strength, the length of the valence, arousal and engagement changes
+    pub fn score(&self) -> f32 {
+        (self.valence * self.valence
+            + self.arousal * self.arousal
+            + self.engagement * self.engagement)
+            .sqrt()
+    }
+
+    /// Values in `METRIC_NAMES` order
+    pub fn metrics(&self) -> [f32; 6] {
+        [
+            self.score(),
+            self.valence,
+            self.arousal,
+            self.engagement,
+            self.blink_rate,
+            self.jaw_clenches as f32,
+        ]
+    }
+}
+
+/// Reactions to every meme shown in a session, from its onset and offset markers,
+/// strongest first. Presentations without band data around them are left out.
+pub fn score_session(session: &MuseSession) -> Vec<StimulusReaction> {
+    let mut reactions = Vec::new();
+
+    for (i, &(onset, ref event)) in session.events.iter().enumerate() {
+        let name = match stimulus_name(event, "onset ") {
+            Some(name) => name,
+            None => continue,
+        };
+        let offset = session.events[i + 1..]
+            .iter()
+            .find(|&&(_, ref event)| stimulus_name(event, "offset ") == Some(name))
+            .map(|&(time, _)| time);
+        let offset = match offset {
+            Some(offset) => offset,
+            None => continue,
+        };
+
+        let previous_offset = session.events[..i]
+            .iter()
+            .rev()
+            .find(|&&(_, ref event)| stimulus_name(event, "offset ").is_some())
+            .map(|&(time, _)| time);
+        let baseline_start = onset
+            .checked_sub(BASELINE_DURATION)
+            .unwrap_or(session.start_time)
+            .max(previous_offset.unwrap_or(session.start_time));
+        match score_stimulus(session, name, baseline_start, onset, offset) {
+            Some(reaction) => reactions.push(reaction),
+            None => eprintln!("No band data around {} at {:?}", name, onset),
+        }
+    }
+
+    rank(&mut reactions, 0);
+    reactions
+}
+
+/// Sort reactions by one of `METRIC_NAMES`, highest first
+pub fn rank(reactions: &mut [StimulusReaction], metric: usize) {
+    reactions.sort_by(|a, b| {
+        b.metrics()[metric]
+            .partial_cmp(&a.metrics()[metric])
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+}
+
+/// Write reactions as CSV, one row per presentation with times in seconds since the UNIX epoch
+pub fn write_reactions_csv(path: &Path, reactions: &[StimulusReaction]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "rank,name,onset,offset,{}", METRIC_NAMES.join(","))?;
+
+    for (i, reaction) in reactions.iter().enumerate() {
+        let metrics: Vec<String> = reaction
+            .metrics()
+            .iter()
+            .map(|value| value.to_string())
+            .collect();
+        writeln!(
+            w,
+            "{},\"{}\",{:.3},{:.3},{}",
+            i + 1,
+            reaction.name.replace('"', "\"\""),
+            reaction.onset.as_secs_f64(),
+            reaction.offset.as_secs_f64(),
+            metrics.join(",")
+        )?;
+    }
+
+    w.flush()
+}
+
+/// The meme name of an onset or offset marker
+fn stimulus_name<'a>(event: &'a SessionEvent, prefix: &str) -> Option<&'a str> {
+    match *event {
+        SessionEvent::Marker { ref label } => label.strip_prefix(prefix),
+        _ => None,
+    }
+}
+
+fn score_stimulus(
+    session: &MuseSession,
+    name: &str,
+    baseline_start: Duration,
+    onset: Duration,
+    offset: Duration,
+) -> Option<StimulusReaction> {
+    let baseline_messages = messages_between(session, baseline_start, onset);
+    let stimulus_messages = messages_between(session, onset, offset);
+    let baseline = WindowMeans::of(baseline_messages)?;
+    let stimulus = WindowMeans::of(stimulus_messages)?;
+
+    let baseline_blinks = count(baseline_messages, is_blink);
+    let stimulus_blinks = count(stimulus_messages, is_blink);
+
+    Some(StimulusReaction {
+        name: String::from(name),
+        onset: onset,
+        offset: offset,
+        valence: stimulus.valence() - baseline.valence(),
+        arousal: stimulus.arousal() - baseline.arousal(),
+        engagement: stimulus.engagement() - baseline.engagement(),
+        blink_rate: per_minute(stimulus_blinks, offset - onset)
+            - per_minute(baseline_blinks, onset - baseline_start),
+        jaw_clenches: count(stimulus_messages, is_jaw_clench),
+    })
+}
+
+/// Mean band powers per electrode over a window, in bels as the Muse sends them
+struct WindowMeans {
+    bands: [[f32; 4]; 5],
+}
+
+impl WindowMeans {
+    /// None unless every band has at least one value in the window
+    fn of(messages: &[(Duration, MuseMessageType)]) -> Option<WindowMeans> {
+        let mut sums = [[0.0f32; 4]; 5];
+        let mut counts = [0usize; 5];
+
+        for &(_, ref muse_message_type) in messages {
+            if let Some((band, values)) = muse_message_type.band() {
+                for electrode in 0..4 {
+                    sums[band.index()][electrode] += values[electrode];
+                }
+                counts[band.index()] += 1;
+            }
+        }
+
+        if counts.contains(&0) {
+            return None;
+        }
+        for band in 0..5 {
+            for electrode in 0..4 {
+                sums[band][electrode] /= counts[band] as f32;
+            }
+        }
+
+        Some(WindowMeans { bands: sums })
+    }
+
+    fn band(&self, band: Band) -> &[f32; 4] {
+        &self.bands[band.index()]
+    }
+
+    /// Mean over the electrodes, as linear power
+    fn power(&self, band: Band) -> f32 {
+        self.band(band).iter().map(|b| 10f32.powf(*b)).sum::<f32>() / 4.0
+    }
+
+    fn valence(&self) -> f32 {
+        let alpha = self.band(Band::Alpha);
+        alpha[RIGHT_FRONTAL] - alpha[LEFT_FRONTAL]
+    }
+
+    fn arousal(&self) -> f32 {
+        (self.power(Band::Beta) / self.power(Band::Alpha)).log10()
+    }
+
+    fn engagement(&self) -> f32 {
+        (self.power(Band::Beta) / (self.power(Band::Alpha) + self.power(Band::Theta))).log10()
+    }
+}
+
+/// Messages received in `start..end`, found by binary search since messages are
+/// recorded in arrival order
+fn messages_between(
+    session: &MuseSession,
+    start: Duration,
+    end: Duration,
+) -> &[(Duration, MuseMessageType)] {
+    let messages = &session.messages[..];
+    let first = messages.partition_point(|&(time, _)| time < start);
+    let last = messages.partition_point(|&(time, _)| time < end).max(first);
+
+    &messages[first..last]
+}
+
+fn count(messages: &[(Duration, MuseMessageType)], matches: fn(&MuseMessageType) -> bool) -> usize {
+    messages
+        .iter()
+        .filter(|&&(_, ref muse_message_type)| matches(muse_message_type))
+        .count()
+}
+
+fn is_blink(muse_message_type: &MuseMessageType) -> bool {
+    match *muse_message_type {
+        MuseMessageType::Blink { blink } => blink,
+        _ => false,
+    }
+}
+
+fn is_jaw_clench(muse_message_type: &MuseMessageType) -> bool {
+    match *muse_message_type {
+        MuseMessageType::JawClench { clench } => clench,
+        _ => false,
+    }
+}
+
+fn per_minute(count: usize, window: Duration) -> f32 {
+    if window.as_secs_f32() > 0.0 {
+        count as f32 * 60.0 / window.as_secs_f32()
+    } else {
+        0.0
+    }
+}
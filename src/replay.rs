@@ -0,0 +1,410 @@
+use crate::muse_packet::MuseMessage;
+use crate::muse_storage::session_file::SessionFileReader;
+use crate::muse_storage::{MuseSession, SessionEvent};
+use crate::theme::Theme;
+use nannou::prelude::*;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Playback speeds selectable with , and .
+const SPEEDS: [f32; 7] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0, 16.0];
+const NORMAL_SPEED: usize = 2;
+
+/// Messages this far before a seek position are replayed at once so every stream has a value
+const SEEK_CATCH_UP: Duration = Duration::from_secs(2);
+
+/// How much of the session is read into memory at a time
+const REPLAY_WINDOW: Duration = Duration::from_secs(10);
+
+const TIMELINE_MARGIN: f32 = 20.0;
+const TIMELINE_HEIGHT: f32 = 12.0;
+const TIMELINE_LABEL_WIDTH: f32 = 220.0;
+const TIMELINE_FONT_SIZE: u32 = 14;
+
+/// Where replayed messages are read from
+#[derive(Debug)]
+enum ReplaySource {
+    /// A session file, read through its block index
+    File(SessionFileReader),
+    /// A session whose file could not be written
+    Memory(MuseSession),
+}
+
+impl ReplaySource {
+    /// Messages and events from `start` to `end` inclusive
+    fn read_between(&mut self, start: Duration, end: Duration) -> io::Result<MuseSession> {
+        let session = match *self {
+            ReplaySource::File(ref mut reader) => return reader.read_between(start, end),
+            ReplaySource::Memory(ref session) => session,
+        };
+
+        let mut window = MuseSession::new(session.start_time);
+        window.device = session.device;
+        let first = session.messages.partition_point(|&(time, _)| time < start);
+        window.messages.extend(
+            session.messages[first..]
+                .iter()
+                .take_while(|&&(time, _)| time <= end)
+                .cloned(),
+        );
+        let first = session.events.partition_point(|&(time, _)| time < start);
+        window.events.extend(
+            session.events[first..]
+                .iter()
+                .take_while(|&&(time, _)| time <= end)
+                .cloned(),
+        );
+
+        Ok(window)
+    }
+}
+
+/// Plays a recorded session back through the live message path, reading it a window at a time
+#[derive(Debug)]
+pub struct Replay {
+    source: ReplaySource,
+    start_time: Duration,
+    duration: Duration,
+    /// Playback position from the start of the session
+    position: Duration,
+    /// The part of the session read so far, up to `window_end`
+    window: MuseSession,
+    window_end: Duration,
+    /// Index of the first message in the window not yet replayed
+    next_message: usize,
+    /// Index of the first event in the window not yet replayed
+    next_event: usize,
+    playing: bool,
+    speed_index: usize,
+    /// Live time of the previous `advance`
+    last_tick: Option<Duration>,
+    /// True while the timeline is being dragged
+    pub scrubbing: bool,
+}
+
+impl Replay {
+    /// Replay a session file, seeking through its block index
+    pub fn open(path: &Path) -> io::Result<Replay> {
+        let reader = SessionFileReader::open(path)?;
+        let (start_time, end_time) = (reader.header.start_time, reader.end_time());
+
+        Ok(Replay::new(
+            ReplaySource::File(reader),
+            start_time,
+            end_time,
+        ))
+    }
+
+    /// Replay a session held only in memory
+    pub fn from_session(session: MuseSession) -> Replay {
+        let (start_time, end_time) = (session.start_time, session.end_time());
+
+        Replay::new(ReplaySource::Memory(session), start_time, end_time)
+    }
+
+    fn new(source: ReplaySource, start_time: Duration, end_time: Duration) -> Replay {
+        let mut replay = Replay {
+            source: source,
+            start_time: start_time,
+            duration: end_time.checked_sub(start_time).unwrap_or_default(),
+            position: Duration::from_secs(0),
+            window: MuseSession::new(start_time),
+            window_end: start_time,
+            next_message: 0,
+            next_event: 0,
+            playing: true,
+            speed_index: NORMAL_SPEED,
+            last_tick: None,
+            scrubbing: false,
+        };
+        replay.seek(Duration::from_secs(0));
+
+        replay
+    }
+
+    pub fn start_time(&self) -> Duration {
+        self.start_time
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    pub fn speed(&self) -> f32 {
+        SPEEDS[self.speed_index]
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Play or pause. Playing from the end starts again from the beginning.
+    fn toggle_playing(&mut self) {
+        if !self.playing && self.position >= self.duration() {
+            self.seek(Duration::from_secs(0));
+        }
+        self.playing = !self.playing;
+    }
+
+    fn faster(&mut self) {
+        self.speed_index = (self.speed_index + 1).min(SPEEDS.len() - 1);
+    }
+
+    fn slower(&mut self) {
+        self.speed_index = self.speed_index.saturating_sub(1);
+    }
+
+    /// Jump to a position, replaying the few seconds before it on the next `advance`
+    pub fn seek(&mut self, position: Duration) {
+        self.position = position.min(self.duration());
+        let catch_up_from = (self.start_time + self.position)
+            .checked_sub(SEEK_CATCH_UP)
+            .unwrap_or(self.start_time)
+            .max(self.start_time);
+        self.load_window(catch_up_from, false);
+    }
+
+    /// Read the window of the session from `from`, leaving out what is at `from` itself when
+    /// it continues the previous window. A read error pauses the replay and returns false.
+    fn load_window(&mut self, from: Duration, after_from: bool) -> bool {
+        let end = from + REPLAY_WINDOW;
+        self.next_message = 0;
+        self.next_event = 0;
+        self.window_end = end;
+
+        match self.source.read_between(from, end) {
+            Ok(mut window) => {
+                if after_from {
+                    window.messages.retain(|&(time, _)| time > from);
+                    window.events.retain(|&(time, _)| time > from);
+                }
+                self.window = window;
+                true
+            }
+            Err(e) => {
+                eprintln!("Could not read the replayed session: {}", e);
+                self.window = MuseSession::new(self.start_time);
+                self.playing = false;
+                false
+            }
+        }
+    }
+
+    /// Move the playback position on by the live time passed and return the messages and
+    /// marker labels it crossed, retimed onto the live clock so views treat them as just received
+    pub fn advance(&mut self, now: Duration) -> (Vec<MuseMessage>, Vec<(Duration, String)>) {
+        let elapsed = match self.last_tick {
+            Some(last_tick) => now.checked_sub(last_tick).unwrap_or(Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        };
+        self.last_tick = Some(now);
+
+        if self.playing && !self.scrubbing {
+            self.position += elapsed.mul_f32(self.speed());
+            if self.position >= self.duration() {
+                self.position = self.duration();
+                self.playing = false;
+            }
+        }
+
+        let cursor = self.start_time + self.position;
+        // Sessions from before devices were stored replay from a placeholder address
+        let ip_address = self
+            .window
+            .device
+            .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 0)));
+        let mut messages = Vec::new();
+        let mut markers = Vec::new();
+        loop {
+            while let Some(&(time, ref muse_message_type)) =
+                self.window.messages.get(self.next_message)
+            {
+                if time > cursor {
+                    break;
+                }
+                messages.push(MuseMessage {
+                    time: now.checked_sub(cursor - time).unwrap_or(now),
+                    ip_address: ip_address,
+                    muse_message_type: muse_message_type.clone(),
+                });
+                self.next_message += 1;
+            }
+
+            while let Some(&(time, ref event)) = self.window.events.get(self.next_event) {
+                if time > cursor {
+                    break;
+                }
+                if let SessionEvent::Marker { ref label } = *event {
+                    markers.push((now.checked_sub(cursor - time).unwrap_or(now), label.clone()));
+                }
+                self.next_event += 1;
+            }
+
+            if cursor <= self.window_end || self.window_end >= self.start_time + self.duration {
+                break;
+            }
+            let from = self.window_end;
+            if !self.load_window(from, true) {
+                break;
+            }
+        }
+
+        (messages, markers)
+    }
+
+    /// K plays or pauses, comma and period change speed. Returns true if the key was used.
+    pub fn key_pressed(&mut self, key: Key) -> bool {
+        match key {
+            Key::K => self.toggle_playing(),
+            Key::Comma => self.slower(),
+            Key::Period => self.faster(),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// The playback position under a window x coordinate, clamped to the timeline
+    pub fn position_at(&self, app: &App, x: f32) -> Duration {
+        let (left, right, _) = timeline_bar(app);
+        let fraction = ((x - left) / (right - left)).max(0.0).min(1.0);
+
+        self.duration().mul_f32(fraction)
+    }
+
+    /// True if a window point is on or near the timeline bar
+    pub fn is_on_timeline(&self, app: &App, point: Point2) -> bool {
+        let (left, right, y) = timeline_bar(app);
+
+        point.x >= left - TIMELINE_MARGIN / 2.0
+            && point.x <= right + TIMELINE_MARGIN / 2.0
+            && (point.y - y).abs() <= TIMELINE_MARGIN
+    }
+}
+
+/// Left and right ends and the height of the timeline bar in window coordinates
+fn timeline_bar(app: &App) -> (f32, f32, f32) {
+    let win = app.window_rect();
+
+    (
+        win.left() + TIMELINE_MARGIN + TIMELINE_LABEL_WIDTH,
+        win.right() - TIMELINE_MARGIN,
+        win.bottom() + TIMELINE_MARGIN + TIMELINE_HEIGHT / 2.0,
+    )
+}
+
+/// Play state, position and speed with a bar showing progress through the session
+pub fn draw_timeline(app: &App, replay: &Replay, draw: &nannou::app::Draw, theme: &Theme) {
+    let win = app.window_rect();
+    let (left, right, y) = timeline_bar(app);
+    let duration = replay.duration().as_secs_f32();
+    let fraction = if duration > 0.0 {
+        replay.position().as_secs_f32() / duration
+    } else {
+        0.0
+    };
+
+    // Cover old frames, since not every view clears the background each frame
+    draw.rect()
+        .x_y(0.0, y)
+        .w_h(win.w(), TIMELINE_HEIGHT + TIMELINE_MARGIN * 2.0)
+        .color(theme.background);
+
+    let state = if replay.is_playing() {
+        "Playing"
+    } else {
+        "Paused"
+    };
+    let label = format!(
+        "{} {} / {}  {}x",
+        state,
+        clock(replay.position()),
+        clock(replay.duration()),
+        replay.speed()
+    );
+    theme
+        .text(draw, &label)
+        .x_y(win.left() + TIMELINE_MARGIN + TIMELINE_LABEL_WIDTH / 2.0, y)
+        .w_h(TIMELINE_LABEL_WIDTH, TIMELINE_HEIGHT * 2.0)
+        .font_size(TIMELINE_FONT_SIZE)
+        .color(theme.foreground);
+
+    draw.rect()
+        .x_y((left + right) / 2.0, y)
+        .w_h(right - left, TIMELINE_HEIGHT)
+        .color(theme.foreground);
+    let played = (right - left) * fraction;
+    draw.rect()
+        .x_y(left + played / 2.0, y)
+        .w_h(played, TIMELINE_HEIGHT)
+        .color(theme.accent);
+    draw.ellipse()
+        .x_y(left + played, y)
+        .w_h(TIMELINE_HEIGHT * 1.5, TIMELINE_HEIGHT * 1.5)
+        .color(WHITE);
+}
+
+/// A duration as minutes and seconds, e.g. "12:05"
+fn clock(time: Duration) -> String {
+    let seconds = time.as_secs();
+
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_packet::MuseMessageType;
+
+    const START: Duration = Duration::from_secs(1_575_473_412);
+    const NOW: Duration = Duration::from_secs(1_600_000_000);
+
+    /// A batt message every 100 ms for 25 seconds, valued in tenths of a second
+    fn session() -> MuseSession {
+        let mut session = MuseSession::new(START);
+        for tenth in 0..250 {
+            session.messages.push((
+                START + Duration::from_millis(tenth * 100),
+                MuseMessageType::Batt { batt: tenth as i32 },
+            ));
+        }
+
+        session
+    }
+
+    fn tenths(messages: &[MuseMessage]) -> Vec<i32> {
+        messages
+            .iter()
+            .map(|message| match message.muse_message_type {
+                MuseMessageType::Batt { batt } => batt,
+                _ => panic!("expected batt"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seek_and_play_across_windows() {
+        let mut replay = Replay::from_session(session());
+        assert_eq!(replay.duration(), Duration::from_millis(24_900));
+
+        // A seek replays the two seconds before the position at once
+        replay.seek(Duration::from_secs(12));
+        let (messages, _) = replay.advance(NOW);
+        assert_eq!(tenths(&messages), (100..=120).collect::<Vec<i32>>());
+
+        // Playing on crosses into the next window without repeating or skipping
+        let (messages, _) = replay.advance(NOW + Duration::from_secs(10));
+        assert_eq!(tenths(&messages), (121..=220).collect::<Vec<i32>>());
+        assert_eq!(replay.position(), Duration::from_secs(22));
+
+        let (messages, _) = replay.advance(NOW + Duration::from_secs(20));
+        assert_eq!(tenths(&messages), (221..250).collect::<Vec<i32>>());
+        assert!(!replay.is_playing());
+    }
+}
@@ -0,0 +1,467 @@
+use crate::muse_packet::{Band, MuseMessageType};
+use crate::muse_storage::{MuseSession, SessionEvent};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::env;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+#[cfg(feature = "audio")]
+use std::sync::mpsc;
+#[cfg(feature = "audio")]
+use std::sync::mpsc::Sender;
+#[cfg(feature = "audio")]
+use std::thread;
+use std::time::Duration;
+
+/// Set to play the brain signals as sound on the default audio device
+pub const SONIFY_ENV: &str = "MEME_MACHINE_SONIFY";
+
+/// Path of a WAV file to write the sound to, with or without an audio device
+pub const SONIFY_WAV_ENV: &str = "MEME_MACHINE_SONIFY_WAV";
+
+/// Sample rate of written WAV files
+pub const WAV_SAMPLE_RATE: u32 = 44_100;
+
+/// Drone pitch at or below `MIN_ALPHA`, rising `DRONE_OCTAVES` up to `MAX_ALPHA`
+const DRONE_BASE_HZ: f32 = 110.0;
+const DRONE_OCTAVES: f32 = 2.0;
+const DRONE_VOLUME: f32 = 0.2;
+
+/// Mean alpha and beta in bels mapped onto the drone
+const MIN_ALPHA: f32 = 0.0;
+const MAX_ALPHA: f32 = 1.5;
+const MIN_BETA: f32 = 0.0;
+const MAX_BETA: f32 = 1.0;
+
+/// Time for the drone to move most of the way to a new pitch, so band updates at 10 Hz glide
+const GLIDE_SECONDS: f32 = 0.3;
+
+/// The WAV header is rewritten this often so the file stays playable if the app is closed
+const WAV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A sound played once for an event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SoundEvent {
+    /// A short click
+    Blink,
+    /// A low percussive hit
+    JawClench,
+    /// A bell, for markers and meme onsets
+    Marker,
+}
+
+impl SoundEvent {
+    fn length(self) -> f32 {
+        match self {
+            SoundEvent::Blink => 0.03,
+            SoundEvent::JawClench => 0.5,
+            SoundEvent::Marker => 1.5,
+        }
+    }
+
+    /// The sound `t` seconds after it started, given a white noise sample
+    fn sample(self, t: f32, noise: f32) -> f32 {
+        match self {
+            SoundEvent::Blink => {
+                0.4 * (2.0 * PI * 1500.0 * t).sin() * (-t / 0.005).exp()
+                    + 0.3 * noise * (-t / 0.002).exp()
+            }
+            SoundEvent::JawClench => {
+                // The pitch falls from 150 Hz to 50 Hz like a kick drum
+                let phase = 2.0 * PI * (50.0 * t + 100.0 * 0.03 * (1.0 - (-t / 0.03).exp()));
+                0.8 * phase.sin() * (-t / 0.15).exp() + 0.2 * noise * (-t / 0.02).exp()
+            }
+            SoundEvent::Marker => {
+                let partials = (2.0 * PI * 880.0 * t).sin() + 0.5 * (2.0 * PI * 1320.0 * t).sin();
+                0.25 * partials * (-t / 0.4).exp()
+            }
+        }
+    }
+}
+
+/// A change to the sound, sent to wherever a `Synth` runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SynthMessage {
+    /// Mean alpha over the electrodes, in bels
+    Alpha(f32),
+    /// Mean beta over the electrodes, in bels
+    Beta(f32),
+    Play(SoundEvent),
+}
+
+/// A drone with its pitch set by alpha and its overtones by beta, and event sounds over it
+#[derive(Clone, Debug)]
+pub struct Synth {
+    sample_rate: f32,
+    target_frequency: f32,
+    target_brightness: f32,
+    frequency: f32,
+    brightness: f32,
+    phase: f32,
+    /// Event sounds still playing and their age in samples
+    voices: Vec<(SoundEvent, u32)>,
+    /// Xorshift state for the noise in event sounds
+    noise: u32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Synth {
+        Synth {
+            sample_rate: sample_rate as f32,
+            target_frequency: DRONE_BASE_HZ,
+            target_brightness: 0.0,
+            frequency: DRONE_BASE_HZ,
+            brightness: 0.0,
+            phase: 0.0,
+            voices: Vec::new(),
+            noise: 0x1234_5678,
+        }
+    }
+
+    pub fn apply(&mut self, message: SynthMessage) {
+        match message {
+            SynthMessage::Alpha(alpha) => {
+                let level = unit_range(alpha, MIN_ALPHA, MAX_ALPHA);
+                self.target_frequency = DRONE_BASE_HZ * 2f32.powf(level * DRONE_OCTAVES);
+            }
+            SynthMessage::Beta(beta) => {
+                self.target_brightness = unit_range(beta, MIN_BETA, MAX_BETA);
+            }
+            SynthMessage::Play(event) => self.voices.push((event, 0)),
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let glide = 1.0 - (-1.0 / (GLIDE_SECONDS * self.sample_rate)).exp();
+        self.frequency += (self.target_frequency - self.frequency) * glide;
+        self.brightness += (self.target_brightness - self.brightness) * glide;
+        self.phase = (self.phase + self.frequency / self.sample_rate) % 1.0;
+
+        let angle = 2.0 * PI * self.phase;
+        let drone = angle.sin()
+            + self.brightness * (0.5 * (2.0 * angle).sin() + 0.25 * (3.0 * angle).sin());
+        let mut value = DRONE_VOLUME * drone;
+
+        let noise = self.next_noise();
+        let sample_rate = self.sample_rate;
+        for voice in self.voices.iter_mut() {
+            let t = voice.1 as f32 / sample_rate;
+            value += voice.0.sample(t, noise);
+            voice.1 += 1;
+        }
+        self.voices
+            .retain(|&(event, age)| (age as f32 / sample_rate) < event.length());
+
+        // Soft clipping keeps overlapping sounds from distorting harshly
+        value.tanh()
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// `value` from `min..max` scaled to `0.0..1.0`, clamped
+fn unit_range(value: f32, min: f32, max: f32) -> f32 {
+    ((value - min) / (max - min)).clamp(0.0, 1.0)
+}
+
+/// A synth rendering into a WAV file as time passes
+struct WavOutput {
+    synth: Synth,
+    writer: WavWriter<BufWriter<File>>,
+    samples: u64,
+    last_flush: Duration,
+}
+
+/// Turns Muse messages into sound on the audio device and in a WAV file
+pub struct Sonifier {
+    start: Duration,
+    blinking: bool,
+    clenching: bool,
+    wav: Option<WavOutput>,
+    #[cfg(feature = "audio")]
+    device: Option<Sender<SynthMessage>>,
+}
+
+impl Debug for Sonifier {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<Sonifier>")
+    }
+}
+
+impl Sonifier {
+    /// Sound from `start` on, written to `wav_path` if given and played if `play` is set
+    pub fn new(start: Duration, wav_path: Option<&Path>, play: bool) -> hound::Result<Sonifier> {
+        let wav = match wav_path {
+            Some(path) => {
+                let spec = WavSpec {
+                    channels: 1,
+                    sample_rate: WAV_SAMPLE_RATE,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                };
+                Some(WavOutput {
+                    synth: Synth::new(WAV_SAMPLE_RATE),
+                    writer: WavWriter::create(path, spec)?,
+                    samples: 0,
+                    last_flush: start,
+                })
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "audio")]
+        let device = if play {
+            match play_on_device() {
+                Ok(device) => Some(device),
+                Err(e) => {
+                    eprintln!("Could not open the audio device: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(feature = "audio"))]
+        {
+            if play {
+                eprintln!(
+                    "Build with --features audio to play sound, or set {} to write it to a file",
+                    SONIFY_WAV_ENV
+                );
+            }
+        }
+
+        Ok(Sonifier {
+            start: start,
+            blinking: false,
+            clenching: false,
+            wav: wav,
+            #[cfg(feature = "audio")]
+            device: device,
+        })
+    }
+
+    /// Sound from now on as set by `MEME_MACHINE_SONIFY` and `MEME_MACHINE_SONIFY_WAV`, if either is
+    pub fn from_env(now: Duration) -> Option<Sonifier> {
+        let play = env::var(SONIFY_ENV).is_ok();
+        let wav_path = env::var(SONIFY_WAV_ENV).ok();
+        if !play && wav_path.is_none() {
+            return None;
+        }
+
+        match Sonifier::new(now, wav_path.as_ref().map(Path::new), play) {
+            Ok(sonifier) => {
+                if let Some(path) = wav_path {
+                    println!("Writing sound to {}", path);
+                }
+                Some(sonifier)
+            }
+            Err(e) => {
+                eprintln!("Could not start sound: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Band powers change the drone, and the start of a blink or jaw clench plays its sound
+    pub fn handle_message(&mut self, time: Duration, muse_message_type: &MuseMessageType) {
+        let message = match *muse_message_type {
+            MuseMessageType::Blink { blink } => {
+                let started = blink && !self.blinking;
+                self.blinking = blink;
+                if !started {
+                    return;
+                }
+                SynthMessage::Play(SoundEvent::Blink)
+            }
+            MuseMessageType::JawClench { clench } => {
+                let started = clench && !self.clenching;
+                self.clenching = clench;
+                if !started {
+                    return;
+                }
+                SynthMessage::Play(SoundEvent::JawClench)
+            }
+            _ => match muse_message_type.band() {
+                Some((Band::Alpha, values)) => SynthMessage::Alpha(mean(&values)),
+                Some((Band::Beta, values)) => SynthMessage::Beta(mean(&values)),
+                _ => return,
+            },
+        };
+
+        self.send(time, message);
+    }
+
+    pub fn marker(&mut self, time: Duration) {
+        self.send(time, SynthMessage::Play(SoundEvent::Marker));
+    }
+
+    /// Write the WAV file up to `time`
+    pub fn render_until(&mut self, time: Duration) {
+        let wav = match self.wav.as_mut() {
+            Some(wav) => wav,
+            None => return,
+        };
+        let elapsed = match time.checked_sub(self.start) {
+            Some(elapsed) => elapsed,
+            None => return,
+        };
+
+        let due = (elapsed.as_secs_f64() * f64::from(WAV_SAMPLE_RATE)) as u64;
+        while wav.samples < due {
+            let value = wav.synth.next_sample();
+            if let Err(e) = wav
+                .writer
+                .write_sample((value * f32::from(i16::MAX)) as i16)
+            {
+                eprintln!("Could not write sound, stopping the WAV file: {}", e);
+                self.wav = None;
+                return;
+            }
+            wav.samples += 1;
+        }
+
+        if time >= wav.last_flush + WAV_FLUSH_INTERVAL {
+            wav.last_flush = time;
+            if let Err(e) = wav.writer.flush() {
+                eprintln!("Could not write sound: {}", e);
+            }
+        }
+    }
+
+    /// Complete the WAV file
+    pub fn finish(self) -> hound::Result<()> {
+        match self.wav {
+            Some(wav) => wav.writer.finalize(),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&mut self, time: Duration, message: SynthMessage) {
+        self.render_until(time);
+        if let Some(wav) = self.wav.as_mut() {
+            wav.synth.apply(message);
+        }
+        #[cfg(feature = "audio")]
+        {
+            if let Some(device) = self.device.as_ref() {
+                if device.send(message).is_err() {
+                    self.device = None;
+                }
+            }
+        }
+    }
+}
+
+fn mean(values: &[f32; 4]) -> f32 {
+    values.iter().sum::<f32>() / 4.0
+}
+
+/// Render a recorded session to a WAV file, for listening without a window or audio device
+pub fn write_session_wav(path: &Path, session: &MuseSession) -> hound::Result<()> {
+    let mut timeline: Vec<(Duration, Option<&MuseMessageType>)> = session
+        .messages
+        .iter()
+        .map(|&(time, ref muse_message_type)| (time, Some(muse_message_type)))
+        .chain(
+            session
+                .events
+                .iter()
+                .filter(|&&(_, ref event)| match *event {
+                    SessionEvent::Marker { .. } => true,
+                    _ => false,
+                })
+                .map(|&(time, _)| (time, None)),
+        )
+        .collect();
+    timeline.sort_by_key(|&(time, _)| time);
+
+    let mut sonifier = Sonifier::new(session.start_time, Some(path), false)?;
+    for (time, muse_message_type) in timeline {
+        match muse_message_type {
+            Some(muse_message_type) => sonifier.handle_message(time, muse_message_type),
+            None => sonifier.marker(time),
+        }
+    }
+    sonifier.render_until(session.end_time());
+
+    sonifier.finish()
+}
+
+/// Run a synth on the default output device, returning where to send it changes
+#[cfg(feature = "audio")]
+fn play_on_device() -> Result<Sender<SynthMessage>, String> {
+    use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
+    use cpal::{StreamData, UnknownTypeOutputBuffer};
+
+    let host = cpal::default_host();
+    let device = host
+        .default_output_device()
+        .ok_or_else(|| String::from("no output device"))?;
+    let format = device.default_output_format().map_err(|e| e.to_string())?;
+    let event_loop = host.event_loop();
+    let stream_id = event_loop
+        .build_output_stream(&device, &format)
+        .map_err(|e| e.to_string())?;
+    event_loop
+        .play_stream(stream_id)
+        .map_err(|e| e.to_string())?;
+
+    let (tx, rx) = mpsc::channel();
+    let channels = format.channels as usize;
+    let mut synth = Synth::new(format.sample_rate.0);
+    thread::spawn(move || {
+        event_loop.run(move |_, result| {
+            for message in rx.try_iter() {
+                synth.apply(message);
+            }
+            match result {
+                Ok(StreamData::Output {
+                    buffer: UnknownTypeOutputBuffer::F32(mut buffer),
+                }) => {
+                    for frame in buffer.chunks_mut(channels) {
+                        let value = synth.next_sample();
+                        for out in frame.iter_mut() {
+                            *out = value;
+                        }
+                    }
+                }
+                Ok(StreamData::Output {
+                    buffer: UnknownTypeOutputBuffer::I16(mut buffer),
+                }) => {
+                    for frame in buffer.chunks_mut(channels) {
+                        let value = (synth.next_sample() * f32::from(i16::MAX)) as i16;
+                        for out in frame.iter_mut() {
+                            *out = value;
+                        }
+                    }
+                }
+                Ok(StreamData::Output {
+                    buffer: UnknownTypeOutputBuffer::U16(mut buffer),
+                }) => {
+                    for frame in buffer.chunks_mut(channels) {
+                        let value =
+                            ((synth.next_sample() * 0.5 + 0.5) * f32::from(u16::MAX)) as u16;
+                        for out in frame.iter_mut() {
+                            *out = value;
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Audio stream error: {}", e),
+            }
+        });
+    });
+
+    Ok(tx)
+}
@@ -0,0 +1,367 @@
+use crate::muse_packet::marker_label;
+use crate::rating::{RatingScale, RatingScreen};
+use crate::theme::Theme;
+use nannou::prelude::*;
+use std::env;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Folder of images to present. A `.txt` file with the same name as an image is its caption.
+pub const STIMULUS_DIR_ENV: &str = "MEME_MACHINE_MEMES";
+
+/// Seconds each image is shown
+pub const STIMULUS_SECONDS_ENV: &str = "MEME_MACHINE_MEME_SECONDS";
+
+/// Seconds of blank screen before each image
+pub const BLANK_SECONDS_ENV: &str = "MEME_MACHINE_BLANK_SECONDS";
+
+/// "random" to shuffle the images for each presentation, otherwise they follow file name order
+pub const STIMULUS_ORDER_ENV: &str = "MEME_MACHINE_MEME_ORDER";
+
+const DEFAULT_STIMULUS_SECONDS: f32 = 5.0;
+const DEFAULT_BLANK_SECONDS: f32 = 2.0;
+
+/// Longest duration accepted from the environment, a day
+const MAX_SECONDS: f32 = 86_400.0;
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "bmp"];
+
+/// Share of the window an image may fill
+const IMAGE_FILL: f32 = 0.9;
+const CAPTION_HEIGHT: f32 = 80.0;
+const CAPTION_FONT_SIZE: u32 = 32;
+
+/// One image to present, with its optional caption
+#[derive(Clone, Debug)]
+pub struct Stimulus {
+    /// File name, used in the onset and offset markers
+    pub name: String,
+    pub path: PathBuf,
+    pub caption: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StimulusOrder {
+    Sequence,
+    Random,
+}
+
+/// Where a presentation is, by position in the presentation order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Idle,
+    Blank {
+        position: usize,
+        until: Duration,
+    },
+    Showing {
+        position: usize,
+        until: Duration,
+    },
+    /// Waiting for the rating screen of the image just shown to be answered
+    Rating {
+        position: usize,
+    },
+}
+
+/// Shows a folder of memes full-screen, one after another with a blank screen between them,
+/// and reports the onset and offset of each so they can be marked in the session
+pub struct StimulusPresenter {
+    stimuli: Vec<Stimulus>,
+    stimulus_duration: Duration,
+    blank_duration: Duration,
+    stimulus_order: StimulusOrder,
+    /// Scales to rate each image on after it is shown
+    rating_scales: Vec<RatingScale>,
+    /// Indices into `stimuli` for the current presentation
+    order: Vec<usize>,
+    phase: Phase,
+    /// Image of the stimulus being shown or coming up after the blank
+    texture: Option<wgpu::Texture>,
+    /// Stimuli whose image could not be loaded, by index into `stimuli`. They are skipped.
+    unloadable: Vec<bool>,
+}
+
+impl Debug for StimulusPresenter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "<StimulusPresenter: {} stimuli, {:?}>",
+            self.stimuli.len(),
+            self.phase
+        )
+    }
+}
+
+impl StimulusPresenter {
+    pub fn new(
+        stimuli: Vec<Stimulus>,
+        stimulus_duration: Duration,
+        blank_duration: Duration,
+        stimulus_order: StimulusOrder,
+        rating_scales: Vec<RatingScale>,
+    ) -> StimulusPresenter {
+        let unloadable = vec![false; stimuli.len()];
+
+        StimulusPresenter {
+            stimuli: stimuli,
+            stimulus_duration: stimulus_duration,
+            blank_duration: blank_duration,
+            stimulus_order: stimulus_order,
+            rating_scales: rating_scales,
+            order: Vec::new(),
+            phase: Phase::Idle,
+            texture: None,
+            unloadable: unloadable,
+        }
+    }
+
+    /// A presenter for the folder in `MEME_MACHINE_MEMES`, if it is set and has images
+    pub fn from_env() -> Option<StimulusPresenter> {
+        let dir = env::var(STIMULUS_DIR_ENV).ok()?;
+        let stimuli = match load_stimuli(Path::new(&dir)) {
+            Ok(stimuli) => stimuli,
+            Err(e) => {
+                eprintln!("Could not read memes from {}: {}", dir, e);
+                return None;
+            }
+        };
+        if stimuli.is_empty() {
+            eprintln!("No images in {}", dir);
+            return None;
+        }
+
+        let stimulus_order = match env::var(STIMULUS_ORDER_ENV) {
+            Ok(ref order) if order == "random" => StimulusOrder::Random,
+            _ => StimulusOrder::Sequence,
+        };
+        println!("Loaded {} memes from {}", stimuli.len(), dir);
+
+        Some(StimulusPresenter::new(
+            stimuli,
+            seconds_from_env(STIMULUS_SECONDS_ENV, DEFAULT_STIMULUS_SECONDS),
+            seconds_from_env(BLANK_SECONDS_ENV, DEFAULT_BLANK_SECONDS),
+            stimulus_order,
+            RatingScale::from_env(),
+        ))
+    }
+
+    pub fn is_presenting(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    /// Begin with the blank before the first image, presenting all images or the first `count`
+    pub fn start(&mut self, now: Duration, count: Option<usize>) {
+        self.order = (0..self.stimuli.len()).collect();
+        if self.stimulus_order == StimulusOrder::Random {
+            // Fisher-Yates
+            for i in (1..self.order.len()).rev() {
+                let j = random_range(0, i + 1);
+                self.order.swap(i, j);
+            }
+        }
+        if let Some(count) = count {
+            self.order.truncate(count.max(1));
+        }
+        self.texture = None;
+        self.phase = Phase::Blank {
+            position: 0,
+            until: now + self.blank_duration,
+        };
+    }
+
+    /// End the presentation early, returning the offset marker of an image still on screen
+    pub fn stop(&mut self) -> Vec<String> {
+        let mut markers = Vec::new();
+        if let Phase::Showing { position, .. } = self.phase {
+            markers.push(format!("offset {}", self.stimulus_at(position).name));
+        }
+        self.phase = Phase::Idle;
+        self.texture = None;
+
+        markers
+    }
+
+    /// A rating screen for the image just shown, while the presentation waits for one
+    pub fn rating_screen(&self) -> Option<RatingScreen> {
+        match self.phase {
+            Phase::Rating { position } => Some(RatingScreen::new(
+                self.rating_scales.clone(),
+                Some(self.stimulus_at(position).name.clone()),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Move through the presentation and return the onset and offset markers of this frame.
+    /// After each image the presentation waits while `rating_open`, if it has rating scales.
+    ///
+    /// An image is loaded during the blank before it, so its onset is not delayed by decoding.
+    /// The onset is reported by the update just before the first frame that shows it. An image
+    /// that cannot be loaded is reported once and skipped, in this and later presentations.
+    pub fn update(&mut self, app: &App, now: Duration, rating_open: bool) -> Vec<String> {
+        let mut markers = Vec::new();
+
+        match self.phase {
+            Phase::Idle => (),
+            Phase::Blank { position, until } => {
+                let index = self.order[position];
+                if self.texture.is_none() && !self.unloadable[index] {
+                    let path = self.stimulus_at(position).path.clone();
+                    match wgpu::Texture::from_path(app, &path) {
+                        Ok(texture) => self.texture = Some(texture),
+                        Err(e) => {
+                            eprintln!("Skipping {}, could not load it: {}", path.display(), e);
+                            self.unloadable[index] = true;
+                        }
+                    }
+                }
+                if self.unloadable[index] {
+                    self.next_stimulus(position, now);
+                } else if now >= until {
+                    markers.push(format!("onset {}", self.stimulus_at(position).name));
+                    self.phase = Phase::Showing {
+                        position: position,
+                        until: now + self.stimulus_duration,
+                    };
+                }
+            }
+            Phase::Showing { position, until } => {
+                if now >= until {
+                    markers.push(format!("offset {}", self.stimulus_at(position).name));
+                    self.texture = None;
+                    if self.rating_scales.is_empty() {
+                        self.next_stimulus(position, now);
+                    } else {
+                        self.phase = Phase::Rating { position: position };
+                    }
+                }
+            }
+            Phase::Rating { position } => {
+                if !rating_open {
+                    self.next_stimulus(position, now);
+                }
+            }
+        }
+
+        markers
+    }
+
+    /// The blank before the image after `position`, or the end of the presentation
+    fn next_stimulus(&mut self, position: usize, now: Duration) {
+        self.phase = if position + 1 < self.order.len() {
+            Phase::Blank {
+                position: position + 1,
+                until: now + self.blank_duration,
+            }
+        } else {
+            println!("Presented {} memes", self.order.len());
+            Phase::Idle
+        };
+    }
+
+    /// The current image scaled to fit the window, with its caption underneath.
+    /// Blanks draw nothing, leaving the cleared background.
+    pub fn draw(&self, app: &App, draw: &nannou::app::Draw, theme: &Theme) {
+        let position = match self.phase {
+            Phase::Showing { position, .. } => position,
+            _ => return,
+        };
+        let stimulus = self.stimulus_at(position);
+        let win = app.window_rect();
+        let caption_height = if stimulus.caption.is_some() {
+            CAPTION_HEIGHT
+        } else {
+            0.0
+        };
+
+        match self.texture {
+            Some(ref texture) => {
+                let [width, height] = texture.size();
+                let scale = (win.w() * IMAGE_FILL / width as f32)
+                    .min((win.h() * IMAGE_FILL - caption_height) / height as f32);
+                draw.texture(texture)
+                    .x_y(0.0, caption_height / 2.0)
+                    .w_h(width as f32 * scale, height as f32 * scale);
+            }
+            None => {
+                theme
+                    .text(draw, &stimulus.name)
+                    .x_y(0.0, 0.0)
+                    .font_size(CAPTION_FONT_SIZE)
+                    .color(theme.foreground);
+            }
+        }
+
+        if let Some(ref caption) = stimulus.caption {
+            theme
+                .text(draw, caption)
+                .x_y(0.0, win.bottom() + caption_height)
+                .w(win.w() * IMAGE_FILL)
+                .font_size(CAPTION_FONT_SIZE)
+                .color(WHITE);
+        }
+    }
+
+    fn stimulus_at(&self, position: usize) -> &Stimulus {
+        &self.stimuli[self.order[position]]
+    }
+}
+
+/// Images in a folder in file name order, each with the caption from a matching `.txt` file
+pub fn load_stimuli(dir: &Path) -> io::Result<Vec<Stimulus>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .map_or(false, |e| {
+                    IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str())
+                })
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            // The whole file name, so cat.png and cat.jpg get different markers
+            let name = path
+                .file_name()
+                .map(|file_name| marker_label(&file_name.to_string_lossy()))
+                .unwrap_or_default();
+            let caption = fs::read_to_string(path.with_extension("txt"))
+                .ok()
+                .map(|caption| String::from(caption.trim()))
+                .filter(|caption| !caption.is_empty());
+            Stimulus {
+                name: name,
+                path: path,
+                caption: caption,
+            }
+        })
+        .collect())
+}
+
+/// A duration in seconds from an environment variable, or the default if unset, not above
+/// zero, not finite or longer than a day. Blanks need a length too, since each meme's
+/// reaction is measured against the blank before it.
+fn seconds_from_env(name: &str, default: f32) -> Duration {
+    let seconds = match env::var(name) {
+        Ok(value) => match value.trim().parse::<f32>() {
+            Ok(seconds) if seconds > 0.0 && seconds <= MAX_SECONDS => seconds,
+            _ => {
+                eprintln!("Ignoring {}={:?}, using {} s", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    };
+
+    Duration::from_secs_f32(seconds)
+}
@@ -0,0 +1,176 @@
+use crate::muse_packet::MuseMessage;
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Timeouts in seconds as "packet=3,eeg=0.5,batt=60". `packet` is the disconnect timeout and
+/// the other names are streams from `DEFAULT_STREAM_TIMEOUTS`.
+pub const TIMEOUTS_ENV: &str = "MEME_MACHINE_TIMEOUTS";
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// With no packet at all for this long the headset is treated as disconnected
+pub const DEFAULT_PACKET_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long each continuous stream may be silent before its values are stale.
+/// Event streams such as blink are only sent on change, so they never go stale.
+pub const DEFAULT_STREAM_TIMEOUTS: [(&str, Duration); 10] = [
+    ("eeg", Duration::from_secs(1)),
+    ("acc", Duration::from_secs(1)),
+    ("gyro", Duration::from_secs(1)),
+    ("alpha", Duration::from_secs(2)),
+    ("beta", Duration::from_secs(2)),
+    ("gamma", Duration::from_secs(2)),
+    ("delta", Duration::from_secs(2)),
+    ("theta", Duration::from_secs(2)),
+    ("horseshoe", Duration::from_secs(2)),
+    ("batt", Duration::from_secs(30)),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionChange {
+    Connected,
+    Disconnected,
+}
+
+/// Where the OSC stream comes from and how fast each message type is arriving
+#[derive(Debug)]
+pub struct StreamStatus {
+    pub sender: Option<SocketAddr>,
+    pub last_packet_time: Option<Duration>,
+    counts: BTreeMap<&'static str, u32>,
+    rates: BTreeMap<&'static str, u32>,
+    window_start: Duration,
+    last_seen: BTreeMap<&'static str, Duration>,
+    connected: bool,
+    pub packet_timeout: Duration,
+    pub stream_timeouts: BTreeMap<&'static str, Duration>,
+}
+
+impl StreamStatus {
+    pub fn new() -> StreamStatus {
+        StreamStatus {
+            sender: None,
+            last_packet_time: None,
+            counts: BTreeMap::new(),
+            rates: BTreeMap::new(),
+            window_start: Duration::from_secs(0),
+            last_seen: BTreeMap::new(),
+            connected: false,
+            packet_timeout: DEFAULT_PACKET_TIMEOUT,
+            stream_timeouts: DEFAULT_STREAM_TIMEOUTS.iter().cloned().collect(),
+        }
+    }
+
+    /// Default timeouts, with any set in `MEME_MACHINE_TIMEOUTS` in their place
+    pub fn from_env() -> StreamStatus {
+        let mut stream_status = StreamStatus::new();
+        let list = match env::var(TIMEOUTS_ENV) {
+            Ok(list) => list,
+            Err(_) => return stream_status,
+        };
+
+        for setting in list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let (name, seconds) = match setting.find('=') {
+                Some(i) => (setting[..i].trim(), setting[i + 1..].trim()),
+                None => (setting, ""),
+            };
+            let timeout = match seconds.parse::<f64>() {
+                Ok(seconds) if seconds > 0.0 && seconds <= 3600.0 => {
+                    Duration::from_secs_f64(seconds)
+                }
+                _ => {
+                    eprintln!("Ignoring timeout {:?} in {}", setting, TIMEOUTS_ENV);
+                    continue;
+                }
+            };
+
+            if name == "packet" {
+                stream_status.packet_timeout = timeout;
+            } else {
+                match stream_status.stream_timeouts.get_mut(name) {
+                    Some(stream_timeout) => *stream_timeout = timeout,
+                    None => eprintln!("Ignoring timeout of unknown stream {:?}", name),
+                }
+            }
+        }
+
+        stream_status
+    }
+
+    pub fn record(&mut self, muse_message: &MuseMessage) {
+        self.sender = Some(muse_message.ip_address);
+        self.last_packet_time = Some(muse_message.time);
+        let name = muse_message.muse_message_type.name();
+        *self.counts.entry(name).or_insert(0) += 1;
+        self.last_seen.insert(name, muse_message.time);
+    }
+
+    /// Roll the per-second counters over once a second has passed
+    pub fn tick(&mut self, now: Duration) {
+        let elapsed = now.checked_sub(self.window_start).unwrap_or(RATE_WINDOW);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        // After a long gap the counts no longer describe a one second window
+        let complete_window = elapsed < RATE_WINDOW * 2;
+        for (name, count) in self.counts.iter_mut() {
+            self.rates
+                .insert(name, if complete_window { *count } else { 0 });
+            *count = 0;
+        }
+        self.window_start = now;
+    }
+
+    /// Messages per second of each type seen so far
+    pub fn rates(&self) -> &BTreeMap<&'static str, u32> {
+        &self.rates
+    }
+
+    pub fn since_last_packet(&self, now: Duration) -> Option<Duration> {
+        self.last_packet_time
+            .map(|time| now.checked_sub(time).unwrap_or(Duration::from_secs(0)))
+    }
+
+    /// True if a continuous stream has not been seen within its timeout
+    pub fn is_stale(&self, name: &str, now: Duration) -> bool {
+        let timeout = match self.stream_timeouts.get(name) {
+            Some(&timeout) => timeout,
+            None => return false,
+        };
+
+        match self.last_seen.get(name) {
+            Some(&time) => now.checked_sub(time).map_or(false, |age| age > timeout),
+            None => true,
+        }
+    }
+
+    /// True if messages from `address` come from the followed headset, or nothing is followed yet
+    pub fn follows(&self, address: SocketAddr) -> bool {
+        self.sender.map_or(true, |sender| sender == address)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Update the connection state, returning the change if there was one
+    pub fn check_connection(&mut self, now: Duration) -> Option<ConnectionChange> {
+        let connected = self
+            .since_last_packet(now)
+            .map_or(false, |since| since <= self.packet_timeout);
+
+        if connected == self.connected {
+            return None;
+        }
+        self.connected = connected;
+
+        if connected {
+            Some(ConnectionChange::Connected)
+        } else {
+            Some(ConnectionChange::Disconnected)
+        }
+    }
+}
@@ -0,0 +1,472 @@
+use crate::crowd::Crowd;
+use crate::erp::EEG_RATE;
+use crate::muse_packet::{Band, MuseMessage, MuseMessageType};
+use std::collections::VecDeque;
+use std::env;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::ops::{Add, Mul, Sub};
+use std::path::Path;
+use std::time::Duration;
+
+/// The two headsets to compare as "address:port,address:port". Without it the first two
+/// headsets in the crowd are compared.
+pub const SYNCHRONY_PAIR_ENV: &str = "MEME_MACHINE_SYNCHRONY_PAIR";
+
+/// How often synchrony is computed and added to the time series
+pub const SYNCHRONY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time series kept outside a recording, as much as the Synchrony view plots
+pub const SERIES_HISTORY: Duration = Duration::from_secs(120);
+
+/// Raw EEG samples per electrode in the phase-locking window, two seconds at 256 Hz.
+/// A power of two for the FFT.
+const PLV_WINDOW: usize = 512;
+
+/// Synchrony between two headsets at one time, indexed by `Band::index()`
+#[derive(Clone, Debug)]
+pub struct SynchronySample {
+    pub time: Duration,
+    pub devices: (SocketAddr, SocketAddr),
+    /// Correlation of band power over the crowd's synchrony window, -1.0 to 1.0
+    pub correlation: [Option<f32>; 5],
+    /// Phase-locking value of the band-filtered raw EEG, averaged over the electrodes,
+    /// 0.0 to 1.0
+    pub plv: [Option<f32>; 5],
+}
+
+/// Raw EEG of each headset and the synchrony time series of the compared pair
+#[derive(Debug)]
+pub struct SynchronyTracker {
+    /// Headsets set in the environment, otherwise the first two in the crowd
+    pair: Option<(SocketAddr, SocketAddr)>,
+    /// The latest raw EEG of each headset, in arrival order
+    eeg: Vec<(SocketAddr, VecDeque<[f32; 4]>)>,
+    next_update: Option<Duration>,
+    series: Vec<SynchronySample>,
+    /// Start of the recording running, whose samples are all kept for its export
+    recording_start: Option<Duration>,
+}
+
+impl SynchronyTracker {
+    pub fn new(pair: Option<(SocketAddr, SocketAddr)>) -> SynchronyTracker {
+        SynchronyTracker {
+            pair: pair,
+            eeg: Vec::new(),
+            next_update: None,
+            series: Vec::new(),
+            recording_start: None,
+        }
+    }
+
+    /// A tracker for the pair in `MEME_MACHINE_SYNCHRONY_PAIR`, or for the first two headsets
+    pub fn from_env() -> SynchronyTracker {
+        let pair = env::var(SYNCHRONY_PAIR_ENV).ok().and_then(|pair| {
+            let addresses: Vec<Option<SocketAddr>> = pair
+                .split(',')
+                .map(|address| address.trim().parse().ok())
+                .collect();
+            match addresses.as_slice() {
+                [Some(a), Some(b)] => Some((*a, *b)),
+                _ => {
+                    eprintln!("Ignoring {}={:?}", SYNCHRONY_PAIR_ENV, pair);
+                    None
+                }
+            }
+        });
+
+        SynchronyTracker::new(pair)
+    }
+
+    /// Keep the raw EEG of every headset for the phase-locking window
+    pub fn push(&mut self, muse_message: &MuseMessage) {
+        let values = match muse_message.muse_message_type {
+            MuseMessageType::Eeg { a, b, c, d } => [a, b, c, d],
+            _ => return,
+        };
+
+        let address = muse_message.ip_address;
+        let index = match self.eeg.iter().position(|&(a, _)| a == address) {
+            Some(index) => index,
+            None => {
+                self.eeg
+                    .push((address, VecDeque::with_capacity(PLV_WINDOW)));
+                self.eeg.len() - 1
+            }
+        };
+        let samples = &mut self.eeg[index].1;
+        samples.push_back(values);
+        while samples.len() > PLV_WINDOW {
+            samples.pop_front();
+        }
+    }
+
+    /// The headsets compared, if both are present
+    pub fn pair(&self, crowd: &Crowd) -> Option<(SocketAddr, SocketAddr)> {
+        match self.pair {
+            Some((a, b)) => match (crowd.position(a), crowd.position(b)) {
+                (Some(_), Some(_)) => Some((a, b)),
+                _ => None,
+            },
+            None => match crowd.participants() {
+                [a, b, ..] => Some((a.address, b.address)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Compute synchrony when it is due, returning the new sample
+    pub fn update(&mut self, now: Duration, crowd: &Crowd) -> Option<&SynchronySample> {
+        if self.next_update.map_or(false, |next| now < next) {
+            return None;
+        }
+        self.next_update = Some(now + SYNCHRONY_INTERVAL);
+
+        // Headsets that left the crowd take their EEG with them
+        self.eeg
+            .retain(|&(address, _)| crowd.position(address).is_some());
+
+        let (a, b) = self.pair(crowd)?;
+        let (index_a, index_b) = (crowd.position(a)?, crowd.position(b)?);
+        let mut correlation = [None; 5];
+        let mut plv = [None; 5];
+        for band in Band::ALL.iter() {
+            correlation[band.index()] = crowd.pair_synchrony(index_a, index_b, *band);
+            plv[band.index()] = self.phase_locking(a, b, *band);
+        }
+
+        self.series.push(SynchronySample {
+            time: now,
+            devices: (a, b),
+            correlation: correlation,
+            plv: plv,
+        });
+
+        let keep_since = now
+            .checked_sub(SERIES_HISTORY)
+            .unwrap_or(Duration::from_secs(0));
+        let keep_since = self
+            .recording_start
+            .map_or(keep_since, |start| start.min(keep_since));
+        let old = self
+            .series
+            .partition_point(|sample| sample.time < keep_since);
+        self.series.drain(..old);
+
+        self.series.last()
+    }
+
+    /// Samples of the last two minutes, or since the recording started if that is earlier,
+    /// oldest first
+    pub fn series(&self) -> &[SynchronySample] {
+        &self.series
+    }
+
+    /// Keep every sample from `now` until the recording stops
+    pub fn start_recording(&mut self, now: Duration) {
+        self.recording_start = Some(now);
+    }
+
+    /// Go back to keeping only the last two minutes
+    pub fn stop_recording(&mut self) {
+        self.recording_start = None;
+    }
+
+    /// Phase-locking value of one band between the same electrodes of two headsets.
+    ///
+    /// The newest samples of each headset are taken as simultaneous, so network jitter between
+    /// the two streams blurs the phase of the faster bands.
+    fn phase_locking(&self, a: SocketAddr, b: SocketAddr, band: Band) -> Option<f32> {
+        let samples = |address: SocketAddr| {
+            self.eeg
+                .iter()
+                .find(|&&(a, _)| a == address)
+                .map(|(_, samples)| samples)
+                .filter(|samples| samples.len() == PLV_WINDOW)
+        };
+        let (a, b) = (samples(a)?, samples(b)?);
+        let (low, high) = band.frequency_range();
+
+        let mut sum = 0.0;
+        for electrode in 0..4 {
+            let signal_a: Vec<f32> = a.iter().map(|values| values[electrode]).collect();
+            let signal_b: Vec<f32> = b.iter().map(|values| values[electrode]).collect();
+            let analytic_a = band_analytic_signal(&signal_a, low, high);
+            let analytic_b = band_analytic_signal(&signal_b, low, high);
+
+            // The edges of the window ring from the filter, so only the middle half is used
+            let mut locking = Complex::new(0.0, 0.0);
+            for i in PLV_WINDOW / 4..PLV_WINDOW * 3 / 4 {
+                let difference = analytic_a[i] * analytic_b[i].conjugate();
+                let magnitude = difference.magnitude();
+                if magnitude > 0.0 {
+                    locking = locking + difference * (1.0 / magnitude);
+                }
+            }
+            sum += locking.magnitude() / (PLV_WINDOW / 2) as f32;
+        }
+
+        Some(sum / 4.0)
+    }
+}
+
+/// Write a synchrony time series as CSV, with times in seconds since the UNIX epoch and empty
+/// cells where a value could not be computed
+pub fn write_synchrony_csv(path: &Path, samples: &[SynchronySample]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let mut columns = vec![String::from("time,device_a,device_b")];
+    for kind in ["correlation", "plv"].iter() {
+        for band in Band::ALL.iter() {
+            columns.push(format!("{}_{}", kind, band.stream_name()));
+        }
+    }
+    writeln!(w, "{}", columns.join(","))?;
+
+    for sample in samples.iter() {
+        let values: Vec<String> = sample
+            .correlation
+            .iter()
+            .chain(sample.plv.iter())
+            .map(|value| value.map_or_else(String::new, |value| value.to_string()))
+            .collect();
+        writeln!(
+            w,
+            "{:.3},{},{},{}",
+            sample.time.as_secs_f64(),
+            sample.devices.0,
+            sample.devices.1,
+            values.join(",")
+        )?;
+    }
+
+    w.flush()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Complex {
+    re: f32,
+    im: f32,
+}
+
+impl Complex {
+    fn new(re: f32, im: f32) -> Complex {
+        Complex { re: re, im: im }
+    }
+
+    fn from_angle(angle: f32) -> Complex {
+        Complex::new(angle.cos(), angle.sin())
+    }
+
+    fn conjugate(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    fn magnitude(self) -> f32 {
+        self.re.hypot(self.im)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Mul<f32> for Complex {
+    type Output = Complex;
+
+    fn mul(self, scale: f32) -> Complex {
+        Complex::new(self.re * scale, self.im * scale)
+    }
+}
+
+/// The analytic signal of `signal` band-passed to `low..high` Hz, whose angle is the phase
+/// within the band. Filtering keeps only those positive frequencies of the spectrum.
+fn band_analytic_signal(signal: &[f32], low: f32, high: f32) -> Vec<Complex> {
+    let n = signal.len();
+    let mean = signal.iter().sum::<f32>() / n as f32;
+    let mut spectrum: Vec<Complex> = signal
+        .iter()
+        .map(|&value| Complex::new(value - mean, 0.0))
+        .collect();
+
+    fft(&mut spectrum, false);
+    for (k, value) in spectrum.iter_mut().enumerate() {
+        let frequency = k as f32 * EEG_RATE / n as f32;
+        *value = if k < n / 2 && frequency >= low && frequency <= high {
+            *value * 2.0
+        } else {
+            Complex::new(0.0, 0.0)
+        };
+    }
+    fft(&mut spectrum, true);
+
+    spectrum
+}
+
+/// In-place radix-2 FFT of a power of two length, scaled by 1/n when `inverse`
+fn fft(values: &mut [Complex], inverse: bool) {
+    let n = values.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let step = Complex::from_angle(sign * 2.0 * PI / length as f32);
+        for start in (0..n).step_by(length) {
+            let mut twiddle = Complex::new(1.0, 0.0);
+            for k in 0..length / 2 {
+                let even = values[start + k];
+                let odd = values[start + k + length / 2] * twiddle;
+                values[start + k] = even + odd;
+                values[start + k + length / 2] = even - odd;
+                twiddle = twiddle * step;
+            }
+        }
+        length <<= 1;
+    }
+
+    if inverse {
+        for value in values.iter_mut() {
+            *value = *value * (1.0 / n as f32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 5], port))
+    }
+
+    /// Fill the phase-locking window of a headset with `signal(sample, electrode)`
+    fn push_signal(
+        tracker: &mut SynchronyTracker,
+        port: u16,
+        signal: impl Fn(usize, usize) -> f32,
+    ) {
+        for i in 0..PLV_WINDOW {
+            tracker.push(&MuseMessage {
+                time: Duration::from_secs_f64(i as f64 / EEG_RATE as f64),
+                ip_address: address(port),
+                muse_message_type: MuseMessageType::Eeg {
+                    a: signal(i, 0),
+                    b: signal(i, 1),
+                    c: signal(i, 2),
+                    d: signal(i, 3),
+                },
+            });
+        }
+    }
+
+    /// Uniform noise in -1..1 from a xorshift generator, so the test is repeatable
+    fn noise(seed: u32) -> impl FnMut() -> f32 {
+        let mut state = seed;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as f32 / u32::MAX as f32 * 2.0 - 1.0
+        }
+    }
+
+    #[test]
+    fn fft_round_trip() {
+        let signal: Vec<Complex> = (0..64)
+            .map(|i| Complex::new((i as f32 * 0.3).sin() + 0.5, (i % 7) as f32))
+            .collect();
+        let mut values = signal.clone();
+        fft(&mut values, false);
+        fft(&mut values, true);
+
+        for (value, original) in values.iter().zip(signal.iter()) {
+            assert!((value.re - original.re).abs() < 1e-4);
+            assert!((value.im - original.im).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn identical_signals_are_phase_locked() {
+        let alpha = |i: usize, electrode: usize| {
+            let t = i as f32 / EEG_RATE;
+            (2.0 * PI * 10.0 * t + electrode as f32).sin() * 20.0
+                + (2.0 * PI * 11.5 * t).sin() * 5.0
+        };
+        let mut tracker = SynchronyTracker::new(None);
+        push_signal(&mut tracker, 5000, alpha);
+        push_signal(&mut tracker, 5001, alpha);
+
+        let locking = tracker
+            .phase_locking(address(5000), address(5001), Band::Alpha)
+            .unwrap();
+        assert!((locking - 1.0).abs() < 1e-3, "{}", locking);
+    }
+
+    #[test]
+    fn independent_noise_is_not_phase_locked() {
+        let mut tracker = SynchronyTracker::new(None);
+        let mut first = noise(1);
+        let samples: Vec<[f32; 4]> = (0..PLV_WINDOW)
+            .map(|_| [first(), first(), first(), first()])
+            .collect();
+        push_signal(&mut tracker, 5000, |i, electrode| samples[i][electrode]);
+        let mut second = noise(2);
+        let samples: Vec<[f32; 4]> = (0..PLV_WINDOW)
+            .map(|_| [second(), second(), second(), second()])
+            .collect();
+        push_signal(&mut tracker, 5001, |i, electrode| samples[i][electrode]);
+
+        let locking = tracker
+            .phase_locking(address(5000), address(5001), Band::Beta)
+            .unwrap();
+        assert!(locking < 0.4, "{}", locking);
+    }
+
+    #[test]
+    fn short_window_has_no_phase_locking() {
+        let mut tracker = SynchronyTracker::new(None);
+        push_signal(&mut tracker, 5000, |_, _| 1.0);
+
+        assert!(tracker
+            .phase_locking(address(5000), address(5001), Band::Alpha)
+            .is_none());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::muse_packet::Band;
+use nannou::draw::primitive::Text;
+use nannou::draw::Drawing;
+use nannou::prelude::*;
+use nannou::text::Font;
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// Comma separated theme files, e.g. "themes/client.json". The first is used at startup and
+/// Y switches through them and the built-in presets.
+pub const THEME_ENV: &str = "MEME_MACHINE_THEME";
+
+const COLOR_NOF1_TURQOISE: Srgb<u8> = Srgb {
+    red: 0,
+    green: 200,
+    blue: 200,
+    standard: PhantomData,
+};
+const COLOR_NOF1_DARK_BLUE: Srgb<u8> = Srgb {
+    red: 31,
+    green: 18,
+    blue: 71,
+    standard: PhantomData,
+};
+const COLOR_NOF1_LIGHT_BLUE: Srgb<u8> = Srgb {
+    red: 189,
+    green: 247,
+    blue: 255,
+    standard: PhantomData,
+};
+
+/// Names of the built-in themes, which are also the `"base"` a theme file can start from
+const PRESETS: [&str; 3] = ["nof1", "high_contrast", "color_blind_safe"];
+
+/// Where the color key of the circle views is drawn, from the center of the window
+#[derive(Clone, Copy, Debug)]
+pub struct Legend {
+    pub x: f32,
+    pub y: f32,
+    /// Distance between rows
+    pub spacing: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    fn from_name(name: &str) -> Option<Corner> {
+        match name {
+            "top_left" => Some(Corner::TopLeft),
+            "top_right" => Some(Corner::TopRight),
+            "bottom_left" => Some(Corner::BottomLeft),
+            "bottom_right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+}
+
+/// An image shown in a corner of every view
+pub struct Logo {
+    texture: wgpu::Texture,
+    corner: Corner,
+    width: f32,
+    margin: f32,
+}
+
+/// Colors, line weights, font, legend placement and logo of everything drawn
+pub struct Theme {
+    pub name: String,
+    pub background: Srgb<u8>,
+    /// Text, axes and outlines
+    pub foreground: Srgb<u8>,
+    /// Highlights such as markers, selections and progress
+    pub accent: Srgb<u8>,
+    /// Indexed by `Band::index()`
+    pub bands: [Srgb<u8>; 5],
+    /// Rings of the circle views
+    pub line_weight: f32,
+    /// Lines of the graph views
+    pub graph_line_weight: f32,
+    pub legend: Legend,
+    /// nannou's built-in font if None
+    pub font: Option<Font>,
+    pub logo: Option<Logo>,
+}
+
+impl Debug for Theme {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<Theme: {}>", self.name)
+    }
+}
+
+impl Theme {
+    /// The N-of-1 brand colors on dark blue
+    pub fn nof1() -> Theme {
+        Theme {
+            name: String::from("N-of-1"),
+            background: COLOR_NOF1_DARK_BLUE,
+            foreground: COLOR_NOF1_LIGHT_BLUE,
+            accent: COLOR_NOF1_TURQOISE,
+            bands: [
+                Srgb::new(178, 178, 255),
+                Srgb::new(178, 255, 178),
+                Srgb::new(255, 178, 178),
+                Srgb::new(178, 255, 255),
+                Srgb::new(255, 178, 255),
+            ],
+            line_weight: 10.0,
+            graph_line_weight: 2.0,
+            legend: Legend {
+                x: 600.0,
+                y: -100.0,
+                spacing: 30.0,
+            },
+            font: None,
+            logo: None,
+        }
+    }
+
+    /// Saturated colors and heavier lines on black, for projectors and low vision
+    pub fn high_contrast() -> Theme {
+        Theme {
+            name: String::from("High contrast"),
+            background: Srgb::new(0, 0, 0),
+            foreground: Srgb::new(255, 255, 255),
+            accent: Srgb::new(255, 255, 0),
+            bands: [
+                Srgb::new(0, 200, 255),
+                Srgb::new(0, 255, 0),
+                Srgb::new(255, 60, 60),
+                Srgb::new(255, 160, 0),
+                Srgb::new(255, 0, 255),
+            ],
+            line_weight: 14.0,
+            graph_line_weight: 3.0,
+            ..Theme::nof1()
+        }
+    }
+
+    /// The Okabe-Ito palette, which stays distinct with every common kind of color blindness
+    pub fn color_blind_safe() -> Theme {
+        Theme {
+            name: String::from("Color-blind safe"),
+            background: Srgb::new(0, 0, 0),
+            foreground: Srgb::new(255, 255, 255),
+            accent: Srgb::new(240, 228, 66),
+            bands: [
+                Srgb::new(86, 180, 233),
+                Srgb::new(0, 158, 115),
+                Srgb::new(213, 94, 0),
+                Srgb::new(204, 121, 167),
+                Srgb::new(230, 159, 0),
+            ],
+            ..Theme::nof1()
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Theme> {
+        match name {
+            "nof1" => Some(Theme::nof1()),
+            "high_contrast" => Some(Theme::high_contrast()),
+            "color_blind_safe" => Some(Theme::color_blind_safe()),
+            _ => None,
+        }
+    }
+
+    /// Load a theme file such as
+    ///
+    /// `{"name": "Client", "base": "high_contrast", "background": "#101010", "bands": {"alpha": "#3366ff"},
+    /// "legend": {"x": 600, "y": -100, "spacing": 30}, "font": "brand.ttf",
+    /// "logo": {"path": "logo.png", "corner": "top_right", "width": 160, "margin": 30}}`
+    ///
+    /// Anything left out comes from the `base` preset, N-of-1 by default. Font and logo paths are
+    /// relative to the theme file.
+    pub fn load(app: &App, path: &Path) -> Result<Theme, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let value: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let base = value["base"].as_str().unwrap_or("nof1");
+        let mut theme = Theme::preset(base).ok_or_else(|| format!("unknown base {:?}", base))?;
+        theme.name = value["name"]
+            .as_str()
+            .map(String::from)
+            .unwrap_or_else(|| path.display().to_string());
+
+        if let Some(color) = color_field(&value, "background")? {
+            theme.background = color;
+        }
+        if let Some(color) = color_field(&value, "foreground")? {
+            theme.foreground = color;
+        }
+        if let Some(color) = color_field(&value, "accent")? {
+            theme.accent = color;
+        }
+        for band in Band::ALL.iter() {
+            if let Some(color) = color_field(&value["bands"], band.stream_name())? {
+                theme.bands[band.index()] = color;
+            }
+        }
+
+        if let Some(weight) = value["line_weight"].as_f64() {
+            theme.line_weight = weight as f32;
+        }
+        if let Some(weight) = value["graph_line_weight"].as_f64() {
+            theme.graph_line_weight = weight as f32;
+        }
+
+        let legend = &value["legend"];
+        if let Some(x) = legend["x"].as_f64() {
+            theme.legend.x = x as f32;
+        }
+        if let Some(y) = legend["y"].as_f64() {
+            theme.legend.y = y as f32;
+        }
+        if let Some(spacing) = legend["spacing"].as_f64() {
+            theme.legend.spacing = spacing as f32;
+        }
+
+        if let Some(font) = value["font"].as_str() {
+            let font_path = directory.join(font);
+            let font = nannou::text::font::from_file(&font_path)
+                .map_err(|e| format!("could not load font {}: {}", font_path.display(), e))?;
+            theme.font = Some(font);
+        }
+
+        let logo = &value["logo"];
+        if let Some(logo_path) = logo["path"].as_str() {
+            let logo_path = directory.join(logo_path);
+            let texture = wgpu::Texture::from_path(app, &logo_path)
+                .map_err(|e| format!("could not load logo {}: {}", logo_path.display(), e))?;
+            let corner = match logo["corner"].as_str() {
+                Some(name) => {
+                    Corner::from_name(name).ok_or_else(|| format!("unknown corner {:?}", name))?
+                }
+                None => Corner::TopRight,
+            };
+            theme.logo = Some(Logo {
+                texture: texture,
+                corner: corner,
+                width: logo["width"].as_f64().unwrap_or(160.0) as f32,
+                margin: logo["margin"].as_f64().unwrap_or(30.0) as f32,
+            });
+        }
+
+        Ok(theme)
+    }
+
+    pub fn band_color(&self, band: Band) -> Srgb<u8> {
+        self.bands[band.index()]
+    }
+
+    /// Text in the theme's font
+    pub fn text<'a>(&self, draw: &'a nannou::app::Draw, text: &str) -> Drawing<'a, Text> {
+        let drawing = draw.text(text);
+
+        match self.font {
+            Some(ref font) => drawing.font(font),
+            None => drawing,
+        }
+    }
+
+    /// The logo in its corner, if the theme has one
+    pub fn draw_logo(&self, app: &App, draw: &nannou::app::Draw) {
+        let logo = match self.logo {
+            Some(ref logo) => logo,
+            None => return,
+        };
+        let win = app.window_rect();
+        let [width, height] = logo.texture.size();
+        let height = logo.width * height as f32 / width.max(1) as f32;
+
+        let x = match logo.corner {
+            Corner::TopLeft | Corner::BottomLeft => win.left() + logo.margin + logo.width / 2.0,
+            Corner::TopRight | Corner::BottomRight => win.right() - logo.margin - logo.width / 2.0,
+        };
+        let y = match logo.corner {
+            Corner::TopLeft | Corner::TopRight => win.top() - logo.margin - height / 2.0,
+            Corner::BottomLeft | Corner::BottomRight => win.bottom() + logo.margin + height / 2.0,
+        };
+        draw.texture(&logo.texture)
+            .x_y(x, y)
+            .w_h(logo.width, height);
+    }
+}
+
+/// A `"#rrggbb"` color, None if the key is missing
+fn color_field(value: &Value, key: &str) -> Result<Option<Srgb<u8>>, String> {
+    let text = match value[key].as_str() {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let hex = text.trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+    };
+
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(red), Some(green), Some(blue)) => Ok(Some(Srgb::new(red, green, blue))),
+        _ => Err(format!("{} is not a #rrggbb color: {:?}", key, text)),
+    }
+}
+
+/// The theme files from `MEME_MACHINE_THEME` followed by the built-in presets, switched with Y
+#[derive(Debug)]
+pub struct ThemeList {
+    files: Vec<PathBuf>,
+    index: usize,
+}
+
+impl ThemeList {
+    pub fn from_env() -> ThemeList {
+        let files = env::var(THEME_ENV)
+            .map(|list| {
+                list.split(',')
+                    .map(|path| path.trim())
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        ThemeList {
+            files: files,
+            index: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.files.len() + PRESETS.len()
+    }
+
+    /// The theme at the current position, or the next one that loads
+    pub fn current(&mut self, app: &App) -> Theme {
+        for _ in 0..self.len() {
+            if self.index < self.files.len() {
+                let path = &self.files[self.index];
+                match Theme::load(app, path) {
+                    Ok(theme) => return theme,
+                    Err(e) => eprintln!("Could not load theme {}: {}", path.display(), e),
+                }
+                self.index = (self.index + 1) % self.len();
+            } else {
+                let name = PRESETS[self.index - self.files.len()];
+                return Theme::preset(name).expect("Unknown preset");
+            }
+        }
+
+        Theme::nof1()
+    }
+
+    /// The next theme. Theme files are read again, so edits show without a restart.
+    pub fn next(&mut self, app: &App) -> Theme {
+        self.index = (self.index + 1) % self.len();
+
+        self.current(app)
+    }
+}
@@ -1,131 +1,199 @@
-use crate::DisplayType;
+use crate::derived_metrics::{asymmetry, average_from_four_electrodes, slow_waves};
+use crate::hud;
+use crate::muse_packet::Band;
+use crate::replay;
+use crate::theme::Theme;
+use crate::view_crowd;
+use crate::view_erp;
+use crate::view_history;
+use crate::view_leaderboard;
+use crate::view_neurofeedback;
+use crate::view_synchrony;
+use crate::view_topomap;
+use crate::visualization::{Visualization, VisualizationRegistry};
 use crate::Model;
 use nannou::prelude::*;
-use std::marker::PhantomData;
-
-const LINE_WEIGHT: f32 = 10.0;
-const KEY_X: f32 = 600.0;
-const KEY_Y: f32 = -100.0;
-const KEY_VERT_SPACING: f32 = 30.0;
-
-pub const _COLOR_NOF1_TURQOISE: Srgb<u8> = Srgb {
-    red: 0,
-    green: 200,
-    blue: 200,
-    standard: PhantomData,
-};
-pub const COLOR_NOF1_DARK_BLUE: Srgb<u8> = Srgb {
-    red: 31,
-    green: 18,
-    blue: 71,
-    standard: PhantomData,
-};
-pub const COLOR_NOF1_LIGHT_BLUE: Srgb<u8> = Srgb {
-    red: 189,
-    green: 247,
-    blue: 255,
-    standard: PhantomData,
-};
-pub const COLOR_BACKGROUND: Srgb<u8> = COLOR_NOF1_DARK_BLUE;
-pub const COLOR_ALPHA: Srgb<u8> = Srgb {
-    red: 178,
-    green: 178,
-    blue: 255,
-    standard: PhantomData,
-};
-pub const COLOR_BETA: Srgb<u8> = Srgb {
-    red: 178,
-    green: 255,
-    blue: 178,
-    standard: PhantomData,
-};
-pub const COLOR_GAMMA: Srgb<u8> = Srgb {
-    red: 255,
-    green: 178,
-    blue: 178,
-    standard: PhantomData,
-};
-pub const COLOR_DELTA: Srgb<u8> = Srgb {
-    red: 178,
-    green: 255,
-    blue: 255,
-    standard: PhantomData,
-};
-pub const COLOR_THETA: Srgb<u8> = Srgb {
-    red: 255,
-    green: 178,
-    blue: 255,
-    standard: PhantomData,
-};
-
-/// Render concenctric circules associated with alpha, beta, gamma..
+
+pub struct FourCirclesView;
+pub struct DrowsinessView;
+pub struct EmotionView;
+
+impl Visualization for FourCirclesView {
+    fn name(&self) -> &'static str {
+        "Four Circles"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_four_circles_view(app, model, draw);
+    }
+}
+
+impl Visualization for DrowsinessView {
+    fn name(&self) -> &'static str {
+        "Drowsiness"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_drowsiness_view(app, model, draw);
+    }
+}
+
+impl Visualization for EmotionView {
+    fn name(&self) -> &'static str {
+        "Emotion"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_emotion_view(app, model, draw);
+    }
+}
+
+/// Render the currently selected visualization
 pub fn view(app: &App, model: &Model, frame: &Frame) {
     let draw = &app.draw();
+    let theme = &model.theme;
+
+    if let Some(ref capture) = model.capture {
+        capture.capture(app);
+    }
+
+    // Rating screens wait for an answer on their own, like the memes they follow
+    if let Some(ref rating) = model.rating {
+        draw.background().color(theme.background);
+        rating.draw(app, draw, theme);
+        draw.to_frame(app, &frame).unwrap();
+        return;
+    }
+
+    // Memes are shown on their own, full-screen
+    if let Some(ref stimuli) = model.stimuli {
+        if stimuli.is_presenting() {
+            draw.background().color(theme.background);
+            stimuli.draw(app, draw, theme);
+            draw.to_frame(app, &frame).unwrap();
+            return;
+        }
+    }
+
+    let visualization = model.visualizations.current();
+
+    if (app.elapsed_frames() % 10) == 1
+        || model.clear_background
+        || visualization.config().clear_every_frame
+    {
+        draw.background().color(theme.background);
+    }
+
+    // Instruction and question steps of a protocol replace the view
+    let protocol_hides_view = model
+        .protocol
+        .as_ref()
+        .map_or(false, |protocol| protocol.hides_view());
+    if !protocol_hides_view {
+        visualization.draw(app, model, draw);
+    }
+
+    if model.visualizations.show_list {
+        model.visualizations.draw_list(draw, theme);
+    }
 
-    if (app.elapsed_frames() % 10) == 1 || model.clear_background {
-        draw.background().color(COLOR_BACKGROUND);
+    if let Some(ref protocol) = model.protocol {
+        protocol.draw(app, draw, model.now(), theme);
     }
 
-    match model.display_type {
-        DisplayType::FourCircles => draw_four_circles_view(app, model, draw),
-        DisplayType::Dowsiness => draw_drowsiness_view(app, model, draw),
-        DisplayType::Emotion => draw_emotion_view(app, model, draw),
+    match model.replay {
+        Some(ref replay) => replay::draw_timeline(app, replay, draw, theme),
+        None => {
+            if !model.stream_status.is_connected() {
+                draw_no_signal(app, draw, theme);
+            }
+        }
+    }
+
+    theme.draw_logo(app, draw);
+    if model.show_hud {
+        hud::draw_hud(app, model, draw);
     }
 
     // Write to the window frame.
     draw.to_frame(app, &frame).unwrap();
 }
 
-fn average_from_four_electrodes(x: &[f32; 4]) -> f32 {
-    (x[0] + x[1] + x[2] + x[3]) / 4.0
+/// The built-in views, in function key order
+pub fn register_visualizations(visualizations: &mut VisualizationRegistry) {
+    visualizations.register(Box::new(FourCirclesView));
+    visualizations.register(Box::new(DrowsinessView));
+    visualizations.register(Box::new(EmotionView));
+    visualizations.register(Box::new(view_history::HistoryView::new()));
+    visualizations.register(Box::new(view_topomap::TopoMapView::new()));
+    visualizations.register(Box::new(view_leaderboard::LeaderboardView::new()));
+    visualizations.register(Box::new(view_erp::ErpView));
+    visualizations.register(Box::new(view_neurofeedback::NeurofeedbackView::new()));
+    visualizations.register(Box::new(view_crowd::CrowdView));
+    visualizations.register(Box::new(view_synchrony::SynchronyView));
 }
 
-fn asymmetry(x: &[f32; 4], n: f32) -> f32 {
-    let base = std::f32::consts::E;
-    base.powf(x[1]/n - x[2]/n)
+/// Shown over every view while no packets are arriving
+fn draw_no_signal(app: &App, draw: &nannou::app::Draw, theme: &Theme) {
+    let win = app.window_rect();
+    let y = win.bottom() + 60.0;
+
+    draw.rect()
+        .x_y(0.0, y)
+        .w_h(300.0, 50.0)
+        .color(theme.background);
+    theme
+        .text(draw, "No signal")
+        .x_y(0.0, y)
+        .font_size(32)
+        .color(theme.foreground);
 }
 
 fn draw_emotion_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
+    let theme = &model.theme;
     let lizard_mind = average_from_four_electrodes(&model.theta);
     let asymm = asymmetry(&model.alpha, lizard_mind);
 
     draw_polygon(
-        COLOR_ALPHA,
+        theme.band_color(Band::Alpha),
         asymm,
         &draw,
         app,
         model.scale,
         (0.0, 0.0),
+        theme,
     );
     draw_polygon(
-        COLOR_THETA,
+        theme.band_color(Band::Theta),
         lizard_mind,
         &draw,
         app,
         model.scale,
         (0.0, 0.0),
+        theme,
     );
 }
 
 fn draw_drowsiness_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
-    let lizard_mind = (average_from_four_electrodes(&model.theta)
-        + average_from_four_electrodes(&model.delta))
-        / 2.0;
+    let theme = &model.theme;
+    let lizard_mind = slow_waves(&model.theta, &model.delta);
     draw_polygon(
-        COLOR_THETA,
+        theme.band_color(Band::Theta),
         lizard_mind,
         &draw,
         app,
         model.scale,
         (0.0, 0.0),
+        theme,
     );
     draw_polygon(
-        COLOR_ALPHA,
+        theme.band_color(Band::Alpha),
         average_from_four_electrodes(&model.alpha),
         &draw,
         app,
         model.scale,
         (0.0, 0.0),
+        theme,
     );
 }
 
@@ -135,25 +203,30 @@ fn draw_four_circles_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
     const RIGHT_FRONT: (f32, f32) = (DISTANCE, -DISTANCE);
     const RIGHT_REAR: (f32, f32) = (DISTANCE, DISTANCE);
     const LEFT_REAR: (f32, f32) = (-DISTANCE, DISTANCE);
+    let theme = &model.theme;
 
-    draw_key(0, "Blink", blink_color(model.blink_countdown > 0), &draw);
+    draw_key(
+        0,
+        "Blink",
+        blink_color(model.blink_countdown > 0, theme),
+        &draw,
+        theme,
+    );
     draw_key(
         1,
         "Jaw Clench",
-        blink_color(model.jaw_clench_countdown > 0),
+        blink_color(model.jaw_clench_countdown > 0, theme),
         &draw,
+        theme,
     );
     draw_key(
         2,
         "Forehead",
-        blink_color(model.touching_forehead_countdown > 0),
+        blink_color(model.touching_forehead_countdown > 0, theme),
         &draw,
+        theme,
     );
-    draw_key(3, "Alpha", COLOR_ALPHA, &draw);
-    draw_key(4, "Beta", COLOR_BETA, &draw);
-    draw_key(5, "Gamma", COLOR_GAMMA, &draw);
-    draw_key(6, "Delta", COLOR_DELTA, &draw);
-    draw_key(7, "Theta", COLOR_THETA, &draw);
+    draw_band_key(3, &draw, theme);
 
     draw_concentric_polygons(&app, &model, &draw, 0, LEFT_REAR);
     draw_concentric_polygons(&app, &model, &draw, 1, LEFT_FRONT);
@@ -168,71 +241,101 @@ fn draw_concentric_polygons(
     index: usize,
     offset: (f32, f32),
 ) {
+    let theme = &model.theme;
     draw_polygon(
-        COLOR_ALPHA,
+        theme.band_color(Band::Alpha),
         model.alpha[index],
         &draw,
         app,
         model.scale,
         offset,
+        theme,
     );
     draw_polygon(
-        COLOR_BETA,
+        theme.band_color(Band::Beta),
         model.beta[index],
         &draw,
         app,
         model.scale,
         offset,
+        theme,
     );
     draw_polygon(
-        COLOR_GAMMA,
+        theme.band_color(Band::Gamma),
         model.gamma[index],
         &draw,
         app,
         model.scale,
         offset,
+        theme,
     );
     draw_polygon(
-        COLOR_DELTA,
+        theme.band_color(Band::Delta),
         model.delta[index],
         &draw,
         app,
         model.scale,
         offset,
+        theme,
     );
     draw_polygon(
-        COLOR_THETA,
+        theme.band_color(Band::Theta),
         model.theta[index],
         &draw,
         app,
         model.scale,
         offset,
+        theme,
     );
 }
 
-fn blink_color(blink: bool) -> Rgb<u8> {
+fn blink_color(blink: bool, theme: &Theme) -> Rgb<u8> {
     if blink {
-        return COLOR_NOF1_LIGHT_BLUE;
+        return theme.foreground;
     }
 
-    COLOR_BACKGROUND
+    theme.background
+}
+
+/// Draw the alpha..theta color key, starting at key row `first_index`
+pub fn draw_band_key(first_index: i32, draw: &nannou::app::Draw, theme: &Theme) {
+    for (i, band) in Band::ALL.iter().enumerate() {
+        draw_key(
+            first_index + i as i32,
+            band.name(),
+            theme.band_color(*band),
+            &draw,
+            theme,
+        );
+    }
 }
 
-fn draw_key(i: i32, text: &str, line_color: Rgb<u8>, draw: &nannou::app::Draw) {
-    let y = KEY_Y - KEY_VERT_SPACING * i as f32;
+pub fn draw_key(i: i32, text: &str, line_color: Rgb<u8>, draw: &nannou::app::Draw, theme: &Theme) {
+    let legend = theme.legend;
+    let y = legend.y - legend.spacing * i as f32;
 
-    draw.rect().x(KEY_X).y(y).w(50.0).h(10.0).color(line_color);
+    draw.rect()
+        .x(legend.x)
+        .y(y)
+        .w(50.0)
+        .h(10.0)
+        .color(line_color);
 
-    draw.text(text).x(KEY_X).y(y - 10.0);
+    theme
+        .text(draw, text)
+        .x(legend.x)
+        .y(y - 10.0)
+        .color(theme.foreground);
 }
 
-fn draw_polygon(
+pub fn draw_polygon(
     line_color: Srgb<u8>,
     value: f32,
     draw: &nannou::app::Draw,
     app: &App,
     scale: f32,
     shift: (f32, f32),
+    theme: &Theme,
 ) {
     let win = app.window_rect();
     let scale = win.x.end / scale;
@@ -249,7 +352,7 @@ fn draw_polygon(
 
     draw.polygon()
         .stroke(line_color)
-        .stroke_weight(LINE_WEIGHT)
+        .stroke_weight(theme.line_weight)
         .no_fill()
         .points(points);
 }
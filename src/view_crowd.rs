@@ -0,0 +1,150 @@
+use crate::crowd::SYNCHRONY_WINDOW;
+use crate::muse_packet::Band;
+use crate::visualization::{Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+
+const MARGIN: f32 = 60.0;
+const GLYPH_SIZE: f32 = 140.0;
+/// Glyph ring radius per bel of band power
+const GLYPH_SCALE: f32 = 30.0;
+const LABEL_FONT_SIZE: u32 = 12;
+const STATS_FONT_SIZE: u32 = 16;
+const STATS_ROW_HEIGHT: f32 = 60.0;
+const STATS_WIDTH: f32 = 400.0;
+/// Bar length per bel of the group mean
+const BAR_SCALE: f32 = 150.0;
+const BAR_HEIGHT: f32 = 12.0;
+
+/// Every headset as a small glyph of band rings, and the group mean, spread and synchrony of
+/// each band
+pub struct CrowdView;
+
+impl Visualization for CrowdView {
+    fn name(&self) -> &'static str {
+        "Crowd"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_crowd_view(app, model, draw);
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+fn draw_crowd_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let participants = model.crowd.participants();
+
+    if participants.is_empty() {
+        theme
+            .text(draw, "Waiting for headsets")
+            .x_y(0.0, 0.0)
+            .font_size(STATS_FONT_SIZE)
+            .color(theme.foreground);
+        return;
+    }
+
+    // Glyphs fill a square grid on the left
+    let columns = (participants.len() as f32).sqrt().ceil() as usize;
+    let left = win.left() + MARGIN + GLYPH_SIZE / 2.0;
+    let top = win.top() - MARGIN - GLYPH_SIZE / 2.0;
+    for (i, participant) in participants.iter().enumerate() {
+        let x = left + GLYPH_SIZE * (i % columns) as f32;
+        let y = top - GLYPH_SIZE * (i / columns) as f32;
+
+        for band in Band::ALL.iter() {
+            let radius = (participant.band_mean(*band) * GLYPH_SCALE).clamp(1.0, GLYPH_SIZE * 0.4);
+            draw.ellipse()
+                .x_y(x, y)
+                .radius(radius)
+                .no_fill()
+                .stroke(theme.band_color(*band))
+                .stroke_weight(theme.graph_line_weight);
+        }
+        theme
+            .text(draw, &participant.address.ip().to_string())
+            .x_y(x, y - GLYPH_SIZE * 0.45)
+            .w(GLYPH_SIZE)
+            .font_size(LABEL_FONT_SIZE)
+            .color(theme.foreground);
+    }
+
+    // Group statistics on the right, one row per band
+    let stats_left = win.right() - MARGIN - STATS_WIDTH;
+    let stats_top = win.top() - MARGIN;
+    for (row, band) in Band::ALL.iter().enumerate() {
+        let y = stats_top - STATS_ROW_HEIGHT * row as f32;
+        let (mean, spread) = match model.crowd.band_spread(*band) {
+            Some(stats) => stats,
+            None => continue,
+        };
+        let synchrony = match model.crowd.synchrony(*band) {
+            Some(r) => format!("sync {:.2}", r),
+            None => String::from("sync -"),
+        };
+
+        theme
+            .text(
+                draw,
+                &format!(
+                    "{} {:.2} +/- {:.2}  {}",
+                    band.name(),
+                    mean,
+                    spread,
+                    synchrony
+                ),
+            )
+            .x_y(stats_left + STATS_WIDTH / 2.0, y)
+            .w(STATS_WIDTH)
+            .left_justify()
+            .font_size(STATS_FONT_SIZE)
+            .color(theme.band_color(*band));
+
+        let bar_y = y - STATS_FONT_SIZE as f32 * 1.5;
+        let bar_width = (mean * BAR_SCALE).clamp(0.0, STATS_WIDTH);
+        draw.rect()
+            .x_y(stats_left + bar_width / 2.0, bar_y)
+            .w_h(bar_width, BAR_HEIGHT)
+            .color(theme.band_color(*band));
+        // The spread across headsets as a whisker around the end of the bar
+        draw.line()
+            .start(pt2(stats_left + (mean - spread) * BAR_SCALE, bar_y))
+            .end(pt2(stats_left + (mean + spread) * BAR_SCALE, bar_y))
+            .weight(theme.graph_line_weight)
+            .color(theme.foreground);
+    }
+
+    let synchronies: Vec<f32> = Band::ALL
+        .iter()
+        .filter_map(|band| model.crowd.synchrony(*band))
+        .collect();
+    let summary = if synchronies.is_empty() {
+        format!(
+            "{} headsets, synchrony needs two or more for a few seconds",
+            participants.len()
+        )
+    } else {
+        format!(
+            "{} headsets, synchrony {:.2} over the last {} s",
+            participants.len(),
+            synchronies.iter().sum::<f32>() / synchronies.len() as f32,
+            SYNCHRONY_WINDOW.as_secs()
+        )
+    };
+    theme
+        .text(draw, &summary)
+        .x_y(
+            stats_left + STATS_WIDTH / 2.0,
+            stats_top - STATS_ROW_HEIGHT * Band::ALL.len() as f32,
+        )
+        .w(STATS_WIDTH)
+        .left_justify()
+        .font_size(STATS_FONT_SIZE)
+        .color(theme.accent);
+}
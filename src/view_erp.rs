@@ -0,0 +1,132 @@
+use crate::erp::EEG_RATE;
+use crate::muse_packet::{Band, ELECTRODE_NAMES};
+use crate::theme::Theme;
+use crate::visualization::{Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+
+const GRAPH_MARGIN: f32 = 40.0;
+const GRAPH_RIGHT: f32 = 500.0;
+const LEGEND_SPACING: f32 = 24.0;
+
+/// Smallest vertical half range, in microvolts, so a flat average is not magnified into noise
+const MIN_MICROVOLTS: f32 = 5.0;
+
+/// Running average of the epochs around each marker condition, one graph per electrode
+pub struct ErpView;
+
+impl Visualization for ErpView {
+    fn name(&self) -> &'static str {
+        "ERP"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_erp_view(app, model, draw);
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+fn draw_erp_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let erp = &model.erp;
+    let left = win.left() + GRAPH_MARGIN;
+    let right = GRAPH_RIGHT.min(win.right() - GRAPH_MARGIN);
+    let row_height = (win.h() - GRAPH_MARGIN * 2.0) / ELECTRODE_NAMES.len() as f32;
+    let pre_ms = erp.pre.as_millis() as f32;
+    let post_ms = erp.post.as_millis() as f32;
+
+    let averages: Vec<Vec<[f32; 4]>> = erp
+        .conditions
+        .iter()
+        .map(|condition| condition.average())
+        .collect();
+    let range = averages
+        .iter()
+        .flat_map(|average| average.iter())
+        .flat_map(|sample| sample.iter())
+        .fold(MIN_MICROVOLTS, |range, value| range.max(value.abs()));
+    let marker_x = map_range(0.0, -pre_ms, post_ms, left, right);
+
+    for (electrode, name) in ELECTRODE_NAMES.iter().enumerate() {
+        let top = win.top() - GRAPH_MARGIN - row_height * electrode as f32;
+        let bottom = top - row_height + GRAPH_MARGIN / 2.0;
+        let middle = (top + bottom) / 2.0;
+
+        draw.line()
+            .start(pt2(left, middle))
+            .end(pt2(right, middle))
+            .weight(1.0)
+            .color(theme.foreground);
+        draw.line()
+            .start(pt2(marker_x, bottom))
+            .end(pt2(marker_x, top))
+            .weight(1.0)
+            .color(theme.accent);
+        theme
+            .text(draw, name)
+            .x_y(left + 20.0, top - 10.0)
+            .color(theme.foreground);
+
+        for (i, average) in averages.iter().enumerate() {
+            if erp.conditions[i].accepted == 0 {
+                continue;
+            }
+            let points: Vec<Point2> = average
+                .iter()
+                .enumerate()
+                .map(|(j, sample)| {
+                    let ms = j as f32 * 1000.0 / EEG_RATE - pre_ms;
+                    pt2(
+                        map_range(ms, -pre_ms, post_ms, left, right),
+                        map_range(sample[electrode], -range, range, bottom, top),
+                    )
+                })
+                .collect();
+            draw.polyline()
+                .weight(theme.graph_line_weight)
+                .color(condition_color(i, theme))
+                .points(points);
+        }
+    }
+
+    theme
+        .text(
+            draw,
+            &format!(
+                "-{} ms to {} ms around markers, +/-{:.0} uV (C to clear)",
+                pre_ms, post_ms, range
+            ),
+        )
+        .x_y((left + right) / 2.0, win.bottom() + GRAPH_MARGIN / 2.0)
+        .w(right - left)
+        .color(theme.foreground);
+
+    for (i, condition) in erp.conditions.iter().enumerate() {
+        theme
+            .text(
+                draw,
+                &format!(
+                    "{}: {} epochs, {} rejected",
+                    condition.name, condition.accepted, condition.rejected
+                ),
+            )
+            .x_y(
+                right + GRAPH_MARGIN * 4.0,
+                win.top() - GRAPH_MARGIN - LEGEND_SPACING * i as f32,
+            )
+            .w(GRAPH_MARGIN * 6.0)
+            .left_justify()
+            .color(condition_color(i, theme));
+    }
+}
+
+/// Conditions take the band colors in turn
+fn condition_color(index: usize, theme: &Theme) -> Srgb<u8> {
+    theme.band_color(Band::ALL[index % Band::ALL.len()])
+}
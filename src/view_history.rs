@@ -0,0 +1,173 @@
+use crate::band_history::HISTORY_WINDOWS;
+use crate::muse_packet::{Band, ELECTRODE_NAMES};
+use crate::view_circles::draw_band_key;
+use crate::visualization::{ViewContext, Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+use std::time::Duration;
+
+const GRAPH_MARGIN: f32 = 40.0;
+const GRAPH_RIGHT: f32 = 500.0;
+const MIN_VALUE_SPAN: f32 = 0.5;
+const MARKER_FONT_SIZE: u32 = 12;
+
+pub struct HistoryView {
+    window_index: usize,
+}
+
+impl HistoryView {
+    pub fn new() -> HistoryView {
+        HistoryView { window_index: 0 }
+    }
+}
+
+impl Visualization for HistoryView {
+    fn name(&self) -> &'static str {
+        "History"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_history_view(app, model, draw, self.window_index);
+    }
+
+    fn key_pressed(&mut self, _context: &ViewContext, key: Key) -> bool {
+        match key {
+            Key::Up => {
+                if self.window_index + 1 < HISTORY_WINDOWS.len() {
+                    self.window_index = self.window_index + 1;
+                }
+                true
+            }
+            Key::Down => {
+                if self.window_index > 0 {
+                    self.window_index = self.window_index - 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+/// Plot the recent history of each band as lines, one graph per electrode
+fn draw_history_view(app: &App, model: &Model, draw: &nannou::app::Draw, window_index: usize) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let window = Duration::from_secs(HISTORY_WINDOWS[window_index]);
+    // Wall clock rather than the latest sample, so lines scroll away when the signal stops
+    let end = model.now();
+    let start = end.checked_sub(window).unwrap_or(Duration::from_secs(0));
+
+    let left = win.left() + GRAPH_MARGIN;
+    let right = GRAPH_RIGHT.min(win.right() - GRAPH_MARGIN);
+    let row_height = (win.h() - GRAPH_MARGIN * 2.0) / ELECTRODE_NAMES.len() as f32;
+
+    for (electrode, name) in ELECTRODE_NAMES.iter().enumerate() {
+        let top = win.top() - GRAPH_MARGIN - row_height * electrode as f32;
+        let bottom = top - row_height + GRAPH_MARGIN / 2.0;
+        let (min, max) = value_range(model, electrode, start);
+
+        draw.line()
+            .start(pt2(left, bottom))
+            .end(pt2(right, bottom))
+            .weight(1.0)
+            .color(theme.foreground);
+        theme
+            .text(draw, name)
+            .x_y(left + 20.0, top - 10.0)
+            .color(theme.foreground);
+
+        for band in Band::ALL.iter() {
+            let points: Vec<Point2> = model
+                .history
+                .samples(*band)
+                .iter()
+                .filter(|&&(time, _)| time >= start)
+                .map(|&(time, values)| {
+                    let x = map_range(
+                        (time - start).as_secs_f32(),
+                        0.0,
+                        window.as_secs_f32(),
+                        left,
+                        right,
+                    );
+                    let y = map_range(values[electrode], min, max, bottom, top);
+                    pt2(x, y)
+                })
+                .collect();
+
+            if points.len() > 1 {
+                draw.polyline()
+                    .weight(theme.graph_line_weight)
+                    .color(theme.band_color(*band))
+                    .points(points);
+            }
+        }
+    }
+
+    let graphs_top = win.top() - GRAPH_MARGIN;
+    let graphs_bottom = win.bottom() + GRAPH_MARGIN * 1.5;
+    for &(time, ref label) in model.history.markers().iter() {
+        if time < start {
+            continue;
+        }
+        let x = map_range(
+            (time - start).as_secs_f32(),
+            0.0,
+            window.as_secs_f32(),
+            left,
+            right,
+        );
+        draw.line()
+            .start(pt2(x, graphs_bottom))
+            .end(pt2(x, graphs_top))
+            .weight(1.0)
+            .color(theme.accent);
+        theme
+            .text(draw, label)
+            .x_y(x, graphs_top + MARKER_FONT_SIZE as f32)
+            .font_size(MARKER_FONT_SIZE)
+            .color(theme.accent);
+    }
+
+    theme
+        .text(
+            draw,
+            &format!("Last {} s (Up/Down to change)", window.as_secs()),
+        )
+        .x_y((left + right) / 2.0, win.bottom() + GRAPH_MARGIN / 2.0)
+        .color(theme.foreground);
+
+    draw_band_key(0, &draw, theme);
+}
+
+/// Vertical range covering all bands of one electrode since `start`
+fn value_range(model: &Model, electrode: usize, start: Duration) -> (f32, f32) {
+    let mut min = std::f32::MAX;
+    let mut max = std::f32::MIN;
+
+    for band in Band::ALL.iter() {
+        for &(time, values) in model.history.samples(*band).iter() {
+            if time >= start {
+                min = min.min(values[electrode]);
+                max = max.max(values[electrode]);
+            }
+        }
+    }
+
+    if min > max {
+        return (0.0, MIN_VALUE_SPAN);
+    }
+    if max - min < MIN_VALUE_SPAN {
+        let middle = (max + min) / 2.0;
+        return (middle - MIN_VALUE_SPAN / 2.0, middle + MIN_VALUE_SPAN / 2.0);
+    }
+
+    (min, max)
+}
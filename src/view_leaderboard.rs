@@ -0,0 +1,133 @@
+use crate::reactions::{rank, METRIC_NAMES};
+use crate::visualization::{ViewContext, Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+
+const MAX_ROWS: usize = 15;
+const ROW_HEIGHT: f32 = 36.0;
+const NAME_WIDTH: f32 = 320.0;
+const COLUMN_WIDTH: f32 = 130.0;
+const BAR_HEIGHT: f32 = 8.0;
+const FONT_SIZE: u32 = 16;
+
+/// Memes from the last presentation ranked by how strongly they were reacted to
+pub struct LeaderboardView {
+    /// Index into `METRIC_NAMES` of the ranking metric
+    metric: usize,
+}
+
+impl LeaderboardView {
+    pub fn new() -> LeaderboardView {
+        LeaderboardView { metric: 0 }
+    }
+}
+
+impl Visualization for LeaderboardView {
+    fn name(&self) -> &'static str {
+        "Meme Leaderboard"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_leaderboard(app, model, draw, self.metric);
+    }
+
+    fn key_pressed(&mut self, _context: &ViewContext, key: Key) -> bool {
+        match key {
+            Key::Left => {
+                self.metric = (self.metric + METRIC_NAMES.len() - 1) % METRIC_NAMES.len();
+                true
+            }
+            Key::Right => {
+                self.metric = (self.metric + 1) % METRIC_NAMES.len();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+/// A table of the top memes with a bar under each row scaled to the ranking metric
+fn draw_leaderboard(app: &App, model: &Model, draw: &nannou::app::Draw, metric: usize) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let top = win.top() - ROW_HEIGHT * 2.0;
+    let left = -(NAME_WIDTH + COLUMN_WIDTH * METRIC_NAMES.len() as f32) / 2.0;
+
+    if model.reactions.is_empty() {
+        theme
+            .text(draw, "Present memes with M to rank them")
+            .x_y(0.0, 0.0)
+            .font_size(FONT_SIZE)
+            .color(theme.foreground);
+        return;
+    }
+
+    theme
+        .text(
+            draw,
+            &format!("Ranked by {} (Left/Right to change)", METRIC_NAMES[metric]),
+        )
+        .x_y(0.0, top + ROW_HEIGHT)
+        .w(win.w())
+        .font_size(FONT_SIZE)
+        .color(theme.foreground);
+
+    for (column, name) in METRIC_NAMES.iter().enumerate() {
+        let color = if column == metric {
+            theme.accent
+        } else {
+            theme.foreground
+        };
+        theme
+            .text(draw, name)
+            .x_y(
+                left + NAME_WIDTH + COLUMN_WIDTH * (column as f32 + 0.5),
+                top,
+            )
+            .w(COLUMN_WIDTH)
+            .font_size(FONT_SIZE)
+            .color(color);
+    }
+
+    let mut reactions = model.reactions.clone();
+    rank(&mut reactions, metric);
+    let largest = reactions
+        .iter()
+        .map(|reaction| reaction.metrics()[metric].abs())
+        .fold(0.0f32, f32::max);
+
+    for (row, reaction) in reactions.iter().take(MAX_ROWS).enumerate() {
+        let y = top - ROW_HEIGHT * (row as f32 + 1.0);
+        let metrics = reaction.metrics();
+
+        theme
+            .text(draw, &format!("{}. {}", row + 1, reaction.name))
+            .x_y(left + NAME_WIDTH / 2.0, y)
+            .w(NAME_WIDTH)
+            .left_justify()
+            .font_size(FONT_SIZE)
+            .color(theme.foreground);
+        for (column, value) in metrics.iter().enumerate() {
+            theme
+                .text(draw, &format!("{:.2}", value))
+                .x_y(left + NAME_WIDTH + COLUMN_WIDTH * (column as f32 + 0.5), y)
+                .w(COLUMN_WIDTH)
+                .font_size(FONT_SIZE)
+                .color(theme.foreground);
+        }
+
+        if largest > 0.0 {
+            let width = NAME_WIDTH * metrics[metric].abs() / largest;
+            draw.rect()
+                .x_y(left + width / 2.0, y - ROW_HEIGHT / 2.0 + BAR_HEIGHT)
+                .w_h(width, BAR_HEIGHT)
+                .color(theme.accent);
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use crate::derived_metrics::{average_from_four_electrodes, DerivedMetrics};
+use crate::muse_packet::Band;
+use crate::view_circles::draw_polygon;
+use crate::visualization::{ViewContext, Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Recent values kept for setting the threshold with T
+const CALIBRATION_WINDOW: Duration = Duration::from_secs(30);
+
+/// Continuous time above the threshold that earns a reward
+const REWARD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a reward is shown
+const REWARD_DISPLAY: Duration = Duration::from_secs(1);
+
+/// Longer gaps between updates, such as a stalled window, are not counted
+const MAX_STEP: Duration = Duration::from_millis(500);
+
+/// Size of the threshold ring in `draw_polygon` units. The feedback circle is this size at the
+/// threshold, and twice it one span above.
+const THRESHOLD_LEVEL: f32 = 0.5;
+const MIN_LEVEL: f32 = 0.02;
+
+/// Up and Down move the threshold by this fraction of the target's span
+const THRESHOLD_STEP: f32 = 0.05;
+
+const TEXT_FONT_SIZE: u32 = 20;
+const TEXT_SPACING: f32 = 30.0;
+const TEXT_MARGIN: f32 = 40.0;
+
+/// The signal that drives the feedback
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FeedbackTarget {
+    /// Mean alpha over all electrodes, trained up for relaxation
+    Alpha,
+    /// log10 of beta over alpha plus theta power, trained up for attention
+    Focus,
+    /// Frontal alpha asymmetry as drawn by the emotion view
+    Asymmetry,
+}
+
+const TARGETS: [FeedbackTarget; 3] = [
+    FeedbackTarget::Alpha,
+    FeedbackTarget::Focus,
+    FeedbackTarget::Asymmetry,
+];
+
+impl FeedbackTarget {
+    fn name(self) -> &'static str {
+        match self {
+            FeedbackTarget::Alpha => "alpha",
+            FeedbackTarget::Focus => "focus",
+            FeedbackTarget::Asymmetry => "asymmetry",
+        }
+    }
+
+    /// The target's current value from the smoothed band values
+    fn value(self, metrics: &DerivedMetrics) -> f32 {
+        match self {
+            FeedbackTarget::Alpha => {
+                average_from_four_electrodes(&metrics.smoothed_band(Band::Alpha))
+            }
+            FeedbackTarget::Focus => {
+                let power = |band| linear_power(&metrics.smoothed_band(band));
+                (power(Band::Beta) / (power(Band::Alpha) + power(Band::Theta))).log10()
+            }
+            FeedbackTarget::Asymmetry => metrics.asymmetry,
+        }
+    }
+
+    /// Distance above the threshold that doubles the feedback circle
+    fn span(self) -> f32 {
+        match self {
+            FeedbackTarget::Alpha | FeedbackTarget::Focus => 1.0,
+            FeedbackTarget::Asymmetry => 0.5,
+        }
+    }
+
+    /// Starting threshold, until it is set from the wearer's own values with T
+    fn default_threshold(self) -> f32 {
+        match self {
+            FeedbackTarget::Alpha => 0.8,
+            FeedbackTarget::Focus => -0.3,
+            FeedbackTarget::Asymmetry => 1.0,
+        }
+    }
+}
+
+/// Mean power over the electrodes from log band values
+fn linear_power(values: &[f32; 4]) -> f32 {
+    values.iter().map(|value| 10f32.powf(*value)).sum::<f32>() / 4.0
+}
+
+/// Statistics of one feedback session
+#[derive(Clone, Debug)]
+struct FeedbackStats {
+    elapsed: Duration,
+    above: Duration,
+    /// Time above the threshold since the last reward or dip below it
+    streak: Duration,
+    rewards: u32,
+    last_reward: Option<Duration>,
+    /// Sums for a least-squares line through the values over elapsed minutes
+    count: f64,
+    sum_t: f64,
+    sum_v: f64,
+    sum_tt: f64,
+    sum_tv: f64,
+}
+
+impl FeedbackStats {
+    fn new() -> FeedbackStats {
+        FeedbackStats {
+            elapsed: Duration::from_secs(0),
+            above: Duration::from_secs(0),
+            streak: Duration::from_secs(0),
+            rewards: 0,
+            last_reward: None,
+            count: 0.0,
+            sum_t: 0.0,
+            sum_v: 0.0,
+            sum_tt: 0.0,
+            sum_tv: 0.0,
+        }
+    }
+
+    fn add(&mut self, now: Duration, step: Duration, value: f32, above: bool) {
+        self.elapsed += step;
+        if above {
+            self.above += step;
+            self.streak += step;
+            if self.streak >= REWARD_INTERVAL {
+                self.streak -= REWARD_INTERVAL;
+                self.rewards += 1;
+                self.last_reward = Some(now);
+            }
+        } else {
+            self.streak = Duration::from_secs(0);
+        }
+
+        let t = self.elapsed.as_secs_f64() / 60.0;
+        let v = f64::from(value);
+        self.count += 1.0;
+        self.sum_t += t;
+        self.sum_v += v;
+        self.sum_tt += t * t;
+        self.sum_tv += t * v;
+    }
+
+    fn above_fraction(&self) -> f32 {
+        if self.elapsed == Duration::from_secs(0) {
+            return 0.0;
+        }
+
+        self.above.as_secs_f32() / self.elapsed.as_secs_f32()
+    }
+
+    /// Change of the target per minute over the session, once there is enough to fit a line
+    fn trend(&self) -> Option<f32> {
+        let denominator = self.count * self.sum_tt - self.sum_t * self.sum_t;
+        if self.count < 2.0 || denominator <= f64::EPSILON {
+            return None;
+        }
+
+        Some(((self.count * self.sum_tv - self.sum_t * self.sum_v) / denominator) as f32)
+    }
+}
+
+/// A circle that grows with the target signal and lights up above a threshold, with rewards
+/// for staying above it and statistics of the session
+pub struct NeurofeedbackView {
+    /// Index into `TARGETS`
+    target: usize,
+    thresholds: [f32; 3],
+    value: f32,
+    /// Recent values of the current target, for setting the threshold
+    recent: VecDeque<(Duration, f32)>,
+    last_update: Option<Duration>,
+    /// Statistics are only collected while a session is running, started and stopped with S
+    running: bool,
+    stats: FeedbackStats,
+}
+
+impl NeurofeedbackView {
+    pub fn new() -> NeurofeedbackView {
+        NeurofeedbackView {
+            target: 0,
+            thresholds: [
+                TARGETS[0].default_threshold(),
+                TARGETS[1].default_threshold(),
+                TARGETS[2].default_threshold(),
+            ],
+            value: 0.0,
+            recent: VecDeque::new(),
+            last_update: None,
+            running: false,
+            stats: FeedbackStats::new(),
+        }
+    }
+
+    fn target(&self) -> FeedbackTarget {
+        TARGETS[self.target]
+    }
+
+    fn threshold(&self) -> f32 {
+        self.thresholds[self.target]
+    }
+
+    fn select_target(&mut self, target: usize) {
+        self.target = target;
+        self.recent.clear();
+        self.stats = FeedbackStats::new();
+    }
+
+    /// Place the threshold at the median of the last 30 seconds, so it is reached half the time
+    fn calibrate(&mut self) {
+        let mut values: Vec<f32> = self.recent.iter().map(|&(_, value)| value).collect();
+        if values.is_empty() {
+            return;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        self.thresholds[self.target] = values[values.len() / 2];
+        println!(
+            "Neurofeedback {} threshold {:.3}",
+            self.target().name(),
+            self.threshold()
+        );
+    }
+
+    fn toggle_session(&mut self) {
+        if self.running {
+            println!(
+                "Neurofeedback {}: {:.0}% of {} s above threshold, {} rewards",
+                self.target().name(),
+                self.stats.above_fraction() * 100.0,
+                self.stats.elapsed.as_secs(),
+                self.stats.rewards
+            );
+        } else {
+            self.stats = FeedbackStats::new();
+        }
+        self.running = !self.running;
+    }
+}
+
+impl Visualization for NeurofeedbackView {
+    fn name(&self) -> &'static str {
+        "Neurofeedback"
+    }
+
+    fn update(&mut self, _app: &App, context: &ViewContext) {
+        let now = context.now;
+        let step = self
+            .last_update
+            .and_then(|last| now.checked_sub(last))
+            .filter(|&step| step <= MAX_STEP);
+        self.last_update = Some(now);
+
+        // Without data the values only decay, which should not count against the wearer
+        if !context.receiving {
+            return;
+        }
+
+        self.value = self.target().value(context.metrics);
+        if !self.value.is_finite() {
+            return;
+        }
+        self.recent.push_back((now, self.value));
+        while let Some(&(time, _)) = self.recent.front() {
+            if time + CALIBRATION_WINDOW < now {
+                self.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if let (true, Some(step)) = (self.running, step) {
+            let above = self.value >= self.threshold();
+            self.stats.add(now, step, self.value, above);
+        }
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_neurofeedback_view(app, model, draw, self);
+    }
+
+    fn key_pressed(&mut self, _context: &ViewContext, key: Key) -> bool {
+        let step = self.target().span() * THRESHOLD_STEP;
+        match key {
+            Key::Left => {
+                self.select_target((self.target + TARGETS.len() - 1) % TARGETS.len());
+                true
+            }
+            Key::Right => {
+                self.select_target((self.target + 1) % TARGETS.len());
+                true
+            }
+            Key::Up => {
+                self.thresholds[self.target] += step;
+                true
+            }
+            Key::Down => {
+                self.thresholds[self.target] -= step;
+                true
+            }
+            Key::T => {
+                self.calibrate();
+                true
+            }
+            Key::S => {
+                self.toggle_session();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+fn draw_neurofeedback_view(
+    app: &App,
+    model: &Model,
+    draw: &nannou::app::Draw,
+    view: &NeurofeedbackView,
+) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let target = view.target();
+    let threshold = view.threshold();
+    let above = view.value >= threshold;
+    let level = (THRESHOLD_LEVEL * (1.0 + (view.value - threshold) / target.span()))
+        .clamp(MIN_LEVEL, THRESHOLD_LEVEL * 2.0);
+
+    draw_polygon(
+        theme.foreground,
+        THRESHOLD_LEVEL,
+        draw,
+        app,
+        model.scale,
+        (0.0, 0.0),
+        theme,
+    );
+    let color = if above {
+        theme.accent
+    } else {
+        theme.band_color(Band::Alpha)
+    };
+    draw_polygon(color, level, draw, app, model.scale, (0.0, 0.0), theme);
+
+    let now = model.now();
+    let rewarded = view
+        .stats
+        .last_reward
+        .map_or(false, |time| time + REWARD_DISPLAY > now);
+    if rewarded {
+        theme
+            .text(draw, "Reward!")
+            .x_y(0.0, 0.0)
+            .font_size(TEXT_FONT_SIZE * 2)
+            .color(theme.accent);
+    }
+
+    theme.text(draw, &format!(
+        "{} {:.2}, threshold {:.2} (Left/Right target, Up/Down threshold, T to set from the last 30 s)",
+        target.name(),
+        view.value,
+        threshold
+    ))
+    .x_y(0.0, win.top() - TEXT_MARGIN)
+    .w(win.w())
+    .font_size(TEXT_FONT_SIZE)
+    .color(theme.foreground);
+
+    let stats = &view.stats;
+    let mut lines = vec![if view.running {
+        String::from("Session running, S to stop")
+    } else {
+        String::from("S to start a session")
+    }];
+    if stats.elapsed > Duration::from_secs(0) {
+        let seconds = stats.elapsed.as_secs();
+        lines.push(format!("Time {}:{:02}", seconds / 60, seconds % 60));
+        lines.push(format!(
+            "Above threshold {:.0}%",
+            stats.above_fraction() * 100.0
+        ));
+        lines.push(format!("Rewards {}", stats.rewards));
+        if let Some(trend) = stats.trend() {
+            lines.push(format!("Trend {:+.3} per minute", trend));
+        }
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        theme
+            .text(draw, line)
+            .x_y(
+                win.left() + TEXT_MARGIN * 4.0,
+                win.bottom() + TEXT_MARGIN * 3.0 + TEXT_SPACING * (lines.len() - 1 - i) as f32,
+            )
+            .w(TEXT_MARGIN * 8.0)
+            .left_justify()
+            .font_size(TEXT_FONT_SIZE)
+            .color(theme.foreground);
+    }
+}
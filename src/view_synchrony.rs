@@ -0,0 +1,142 @@
+use crate::muse_packet::Band;
+use crate::synchrony::{SynchronySample, SERIES_HISTORY};
+use crate::theme::Theme;
+use crate::visualization::{Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+use std::time::Duration;
+
+/// Time shown across the graphs, all the tracker keeps outside a recording
+const GRAPH_WINDOW: Duration = SERIES_HISTORY;
+const GRAPH_MARGIN: f32 = 60.0;
+const LEGEND_WIDTH: f32 = 260.0;
+const TEXT_FONT_SIZE: u32 = 16;
+const LEGEND_ROW_HEIGHT: f32 = 24.0;
+
+/// Picks the per-band values one graph plots
+type GraphValues = fn(&SynchronySample) -> &[Option<f32>; 5];
+
+/// Band power correlation and phase locking between two headsets over the last two minutes
+pub struct SynchronyView;
+
+impl Visualization for SynchronyView {
+    fn name(&self) -> &'static str {
+        "Synchrony"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_synchrony_view(app, model, draw);
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+fn draw_synchrony_view(app: &App, model: &Model, draw: &nannou::app::Draw) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+
+    let (a, b) = match model.synchrony.pair(&model.crowd) {
+        Some(pair) => pair,
+        None => {
+            theme
+                .text(draw, "Waiting for two headsets")
+                .x_y(0.0, 0.0)
+                .font_size(TEXT_FONT_SIZE)
+                .color(theme.foreground);
+            return;
+        }
+    };
+    theme
+        .text(draw, &format!("{} and {}", a, b))
+        .x_y(0.0, win.top() - GRAPH_MARGIN / 2.0)
+        .w(win.w())
+        .font_size(TEXT_FONT_SIZE)
+        .color(theme.accent);
+
+    // Wall clock rather than the latest sample, so lines scroll away when the signal stops
+    let end = model.now();
+    let start = end
+        .checked_sub(GRAPH_WINDOW)
+        .unwrap_or(Duration::from_secs(0));
+    let samples: Vec<&SynchronySample> = model
+        .synchrony
+        .series()
+        .iter()
+        .filter(|sample| sample.time >= start && sample.devices == (a, b))
+        .collect();
+
+    let left = win.left() + GRAPH_MARGIN;
+    let right = win.right() - GRAPH_MARGIN - LEGEND_WIDTH;
+    let row_height = (win.h() - GRAPH_MARGIN * 2.0) / 2.0;
+    let graphs: [(&str, f32, GraphValues); 2] = [
+        ("Band power correlation", -1.0, |sample| &sample.correlation),
+        ("Phase locking", 0.0, |sample| &sample.plv),
+    ];
+
+    for (row, &(name, min, values)) in graphs.iter().enumerate() {
+        let top = win.top() - GRAPH_MARGIN - row_height * row as f32;
+        let bottom = top - row_height + GRAPH_MARGIN / 2.0;
+
+        draw.line()
+            .start(pt2(left, bottom))
+            .end(pt2(right, bottom))
+            .weight(1.0)
+            .color(theme.foreground);
+        theme
+            .text(draw, &format!("{} ({} to 1)", name, min))
+            .x_y(left + 100.0, top - 10.0)
+            .w(200.0)
+            .left_justify()
+            .color(theme.foreground);
+
+        for band in Band::ALL.iter() {
+            // Gaps where a value could not be computed split the line
+            let mut points: Vec<Point2> = Vec::new();
+            for sample in samples.iter() {
+                match values(sample)[band.index()] {
+                    Some(value) => {
+                        let x = map_range(
+                            (sample.time - start).as_secs_f32(),
+                            0.0,
+                            GRAPH_WINDOW.as_secs_f32(),
+                            left,
+                            right,
+                        );
+                        points.push(pt2(x, map_range(value, min, 1.0, bottom, top)));
+                    }
+                    None => draw_trace(draw, theme, *band, points.split_off(0)),
+                }
+            }
+            draw_trace(draw, theme, *band, points);
+
+            let legend_y = top - LEGEND_ROW_HEIGHT * (band.index() as f32 + 1.0);
+            let current = match samples
+                .last()
+                .and_then(|sample| values(sample)[band.index()])
+            {
+                Some(value) => format!("{} {:.2}", band.name(), value),
+                None => format!("{} -", band.name()),
+            };
+            theme
+                .text(draw, &current)
+                .x_y(right + GRAPH_MARGIN / 2.0 + LEGEND_WIDTH / 2.0, legend_y)
+                .w(LEGEND_WIDTH)
+                .left_justify()
+                .font_size(TEXT_FONT_SIZE)
+                .color(theme.band_color(*band));
+        }
+    }
+}
+
+fn draw_trace(draw: &nannou::app::Draw, theme: &Theme, band: Band, points: Vec<Point2>) {
+    if points.len() > 1 {
+        draw.polyline()
+            .weight(theme.graph_line_weight)
+            .color(theme.band_color(band))
+            .points(points);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::band_history::HISTORY_WINDOWS;
+use crate::muse_packet::{Band, ELECTRODE_NAMES};
+use crate::theme::Theme;
+use crate::visualization::{ViewContext, Visualization, VisualizationConfig};
+use crate::Model;
+use nannou::prelude::*;
+use std::time::Duration;
+
+const HEAD_LINE_WEIGHT: f32 = 4.0;
+const GRID_RESOLUTION: usize = 48;
+const LEGEND_STEPS: usize = 32;
+const LEGEND_WIDTH: f32 = 30.0;
+const MIN_VALUE_SPAN: f32 = 0.2;
+
+/// Electrode positions on a unit head, nose up, in the order TP9, AF7, AF8, TP10
+pub const ELECTRODE_POSITIONS: [(f32, f32); 4] =
+    [(-0.95, -0.35), (-0.55, 0.77), (0.55, 0.77), (0.95, -0.35)];
+
+/// Color ramp used for the map and the legend, from low to high power
+const SCALE_STOPS: [(u8, u8, u8); 5] = [
+    (31, 18, 171),
+    (0, 200, 200),
+    (120, 230, 80),
+    (255, 220, 40),
+    (240, 40, 40),
+];
+
+pub struct TopoMapView {
+    band: Band,
+}
+
+impl TopoMapView {
+    pub fn new() -> TopoMapView {
+        TopoMapView { band: Band::Alpha }
+    }
+}
+
+impl Visualization for TopoMapView {
+    fn name(&self) -> &'static str {
+        "Topographic Map"
+    }
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw) {
+        draw_topomap_view(app, model, draw, self.band);
+    }
+
+    fn key_pressed(&mut self, _context: &ViewContext, key: Key) -> bool {
+        let i = self.band.index();
+        match key {
+            Key::Left => {
+                self.band = Band::ALL[(i + Band::ALL.len() - 1) % Band::ALL.len()];
+                true
+            }
+            Key::Right => {
+                self.band = Band::ALL[(i + 1) % Band::ALL.len()];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: true,
+        }
+    }
+}
+
+/// Interpolate the selected band across a head outline at the Muse electrode sites
+fn draw_topomap_view(app: &App, model: &Model, draw: &nannou::app::Draw, band: Band) {
+    let theme = &model.theme;
+    let win = app.window_rect();
+    let radius = win.w().min(win.h()) * 0.35;
+    let values = model.band_values(band);
+    let (min, max) = value_range(model, band, &values);
+
+    let cell = radius * 2.0 / GRID_RESOLUTION as f32;
+    for row in 0..GRID_RESOLUTION {
+        for column in 0..GRID_RESOLUTION {
+            let x = -1.0 + (column as f32 + 0.5) * 2.0 / GRID_RESOLUTION as f32;
+            let y = -1.0 + (row as f32 + 0.5) * 2.0 / GRID_RESOLUTION as f32;
+            if x * x + y * y > 1.0 {
+                continue;
+            }
+
+            let value = interpolate(&values, (x, y));
+            draw.rect()
+                .x_y(x * radius, y * radius)
+                .w_h(cell, cell)
+                .color(scale_color((value - min) / (max - min)));
+        }
+    }
+
+    draw_head(draw, radius, theme);
+
+    for (i, &(x, y)) in ELECTRODE_POSITIONS.iter().enumerate() {
+        draw.ellipse()
+            .x_y(x * radius, y * radius)
+            .radius(8.0)
+            .color(theme.foreground);
+        theme
+            .text(draw, ELECTRODE_NAMES[i])
+            .x_y(x * radius, y * radius - 20.0)
+            .color(theme.foreground);
+    }
+
+    draw_legend(draw, radius, min, max, theme);
+
+    theme
+        .text(
+            draw,
+            &format!("{} (Left/Right to change band)", band.name()),
+        )
+        .x_y(0.0, radius + 80.0)
+        .color(theme.band_color(band));
+}
+
+/// Inverse distance weighted estimate at `point` from the four electrode values
+fn interpolate(values: &[f32; 4], point: (f32, f32)) -> f32 {
+    let mut weighted_sum = 0.0;
+    let mut weight_total = 0.0;
+
+    for (i, &(x, y)) in ELECTRODE_POSITIONS.iter().enumerate() {
+        let distance_squared = (point.0 - x).powi(2) + (point.1 - y).powi(2);
+        if distance_squared < std::f32::EPSILON {
+            return values[i];
+        }
+        let weight = 1.0 / distance_squared;
+        weighted_sum += weight * values[i];
+        weight_total += weight;
+    }
+
+    weighted_sum / weight_total
+}
+
+/// Range of the band over the shortest history window, so the colors stay steady
+fn value_range(model: &Model, band: Band, current: &[f32; 4]) -> (f32, f32) {
+    let mut min = current.iter().cloned().fold(std::f32::MAX, f32::min);
+    let mut max = current.iter().cloned().fold(std::f32::MIN, f32::max);
+
+    if let Some(end) = model.history.latest_time() {
+        let start = end
+            .checked_sub(Duration::from_secs(HISTORY_WINDOWS[0]))
+            .unwrap_or(Duration::from_secs(0));
+        for &(time, values) in model.history.samples(band).iter() {
+            if time >= start {
+                for value in values.iter() {
+                    min = min.min(*value);
+                    max = max.max(*value);
+                }
+            }
+        }
+    }
+
+    if max - min < MIN_VALUE_SPAN {
+        let middle = (max + min) / 2.0;
+        return (middle - MIN_VALUE_SPAN / 2.0, middle + MIN_VALUE_SPAN / 2.0);
+    }
+
+    (min, max)
+}
+
+pub fn scale_color(t: f32) -> Srgb<u8> {
+    let t = t.max(0.0).min(1.0) * (SCALE_STOPS.len() - 1) as f32;
+    let i = (t.floor() as usize).min(SCALE_STOPS.len() - 2);
+    let f = t - i as f32;
+    let (r0, g0, b0) = SCALE_STOPS[i];
+    let (r1, g1, b1) = SCALE_STOPS[i + 1];
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f) as u8;
+
+    Srgb::new(mix(r0, r1), mix(g0, g1), mix(b0, b1))
+}
+
+fn draw_head(draw: &nannou::app::Draw, radius: f32, theme: &Theme) {
+    draw.ellipse()
+        .x_y(0.0, 0.0)
+        .radius(radius)
+        .no_fill()
+        .stroke(theme.foreground)
+        .stroke_weight(HEAD_LINE_WEIGHT);
+
+    // Nose
+    draw.polyline()
+        .weight(HEAD_LINE_WEIGHT)
+        .color(theme.foreground)
+        .points(vec![
+            pt2(-radius * 0.12, radius * 0.99),
+            pt2(0.0, radius * 1.15),
+            pt2(radius * 0.12, radius * 0.99),
+        ]);
+
+    // Ears
+    for side in [-1.0, 1.0].iter() {
+        draw.ellipse()
+            .x_y(side * radius * 1.04, 0.0)
+            .w_h(radius * 0.1, radius * 0.3)
+            .no_fill()
+            .stroke(theme.foreground)
+            .stroke_weight(HEAD_LINE_WEIGHT);
+    }
+}
+
+fn draw_legend(draw: &nannou::app::Draw, radius: f32, min: f32, max: f32, theme: &Theme) {
+    let x = radius + 150.0;
+    let height = radius * 1.5;
+    let step = height / LEGEND_STEPS as f32;
+    let bottom = -height / 2.0;
+
+    for i in 0..LEGEND_STEPS {
+        draw.rect()
+            .x_y(x, bottom + step * (i as f32 + 0.5))
+            .w_h(LEGEND_WIDTH, step)
+            .color(scale_color(i as f32 / (LEGEND_STEPS - 1) as f32));
+    }
+
+    theme
+        .text(draw, &format!("{:.2}", max))
+        .x_y(x + LEGEND_WIDTH * 2.0, bottom + height)
+        .color(theme.foreground);
+    theme
+        .text(draw, &format!("{:.2}", min))
+        .x_y(x + LEGEND_WIDTH * 2.0, bottom)
+        .color(theme.foreground);
+}
@@ -0,0 +1,171 @@
+use crate::derived_metrics::DerivedMetrics;
+use crate::theme::Theme;
+use crate::Model;
+use nannou::prelude::*;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::time::Duration;
+
+/// Function keys bound to views in registration order
+const FUNCTION_KEYS: [Key; 12] = [
+    Key::F1,
+    Key::F2,
+    Key::F3,
+    Key::F4,
+    Key::F5,
+    Key::F6,
+    Key::F7,
+    Key::F8,
+    Key::F9,
+    Key::F10,
+    Key::F11,
+    Key::F12,
+];
+
+const LIST_X: f32 = -600.0;
+const LIST_Y: f32 = 300.0;
+const LIST_VERT_SPACING: f32 = 30.0;
+
+/// How the frame around a visualization is prepared
+#[derive(Clone, Copy, Debug)]
+pub struct VisualizationConfig {
+    /// Clear every frame instead of leaving fading trails
+    pub clear_every_frame: bool,
+}
+
+impl Default for VisualizationConfig {
+    fn default() -> VisualizationConfig {
+        VisualizationConfig {
+            clear_every_frame: false,
+        }
+    }
+}
+
+/// The part of the model views read while they update or take keys. The registry holding the
+/// views is itself part of the model, so they cannot be lent the whole of it then.
+#[derive(Clone, Copy, Debug)]
+pub struct ViewContext<'a> {
+    pub metrics: &'a DerivedMetrics,
+    /// True while a headset is connected or a session is replaying
+    pub receiving: bool,
+    /// Time of this update, from `Model::now`
+    pub now: Duration,
+}
+
+/// One selectable view of the Muse signals
+pub trait Visualization {
+    fn name(&self) -> &'static str;
+
+    fn update(&mut self, _app: &App, _context: &ViewContext) {}
+
+    fn draw(&self, app: &App, model: &Model, draw: &nannou::app::Draw);
+
+    /// Return true if the key was used by this view
+    fn key_pressed(&mut self, _context: &ViewContext, _key: Key) -> bool {
+        false
+    }
+
+    fn config(&self) -> VisualizationConfig {
+        VisualizationConfig::default()
+    }
+}
+
+/// All available views, the current selection and the view list overlay
+pub struct VisualizationRegistry {
+    views: Vec<Box<dyn Visualization>>,
+    current: usize,
+    pub show_list: bool,
+}
+
+impl Debug for VisualizationRegistry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<VisualizationRegistry: {} views>", self.views.len())
+    }
+}
+
+impl VisualizationRegistry {
+    pub fn new() -> VisualizationRegistry {
+        VisualizationRegistry {
+            views: Vec::new(),
+            current: 0,
+            show_list: false,
+        }
+    }
+
+    /// Add a view, bound to the next free function key
+    pub fn register(&mut self, view: Box<dyn Visualization>) {
+        self.views.push(view);
+    }
+
+    pub fn current(&self) -> &dyn Visualization {
+        self.views
+            .get(self.current)
+            .expect("No visualization registered")
+            .as_ref()
+    }
+
+    pub fn select(&mut self, index: usize) {
+        if index < self.views.len() {
+            self.current = index;
+        }
+    }
+
+    /// Select the view with this name, ignoring case. Returns false if there is none.
+    pub fn select_by_name(&mut self, name: &str) -> bool {
+        match self
+            .views
+            .iter()
+            .position(|view| view.name().eq_ignore_ascii_case(name))
+        {
+            Some(index) => {
+                self.current = index;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.current = (self.current + 1) % self.views.len();
+    }
+
+    /// Index of the view bound to a function key
+    pub fn function_key_index(&self, key: Key) -> Option<usize> {
+        FUNCTION_KEYS
+            .iter()
+            .position(|&k| k == key)
+            .filter(|&index| index < self.views.len())
+    }
+
+    /// Call `update` on every view so they keep their state while hidden
+    pub fn update(&mut self, app: &App, context: &ViewContext) {
+        for view in self.views.iter_mut() {
+            view.update(app, context);
+        }
+    }
+
+    /// Offer a key to the current view, returning true if it was used
+    pub fn key_pressed(&mut self, context: &ViewContext, key: Key) -> bool {
+        match self.views.get_mut(self.current) {
+            Some(view) => view.key_pressed(context, key),
+            None => false,
+        }
+    }
+
+    pub fn draw_list(&self, draw: &nannou::app::Draw, theme: &Theme) {
+        for (i, view) in self.views.iter().enumerate() {
+            let key = if i < FUNCTION_KEYS.len() {
+                format!("F{}", i + 1)
+            } else {
+                String::from("Tab")
+            };
+            let marker = if i == self.current { ">" } else { " " };
+
+            theme
+                .text(draw, &format!("{} {}  {}", marker, key, view.name()))
+                .x_y(LIST_X, LIST_Y - LIST_VERT_SPACING * i as f32)
+                .color(theme.foreground);
+        }
+    }
+}
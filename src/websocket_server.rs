@@ -0,0 +1,271 @@
+use crate::derived_metrics::DerivedMetrics;
+use crate::muse_packet::{Band, MuseMessage};
+use crate::synchrony::SynchronySample;
+use serde_json::{json, Value};
+use std::env;
+use std::fmt;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+/// Address to serve WebSocket clients on, e.g. "0.0.0.0:9001"
+pub const WEBSOCKET_ENV: &str = "MEME_MACHINE_WEBSOCKET";
+
+/// How long a client thread waits for a subscription message before sending queued updates
+const CLIENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Updates queued for one client, about two seconds of everything a headset sends. A client
+/// that falls this far behind is disconnected rather than left to grow the queue.
+const CLIENT_QUEUE_LENGTH: usize = 1024;
+
+/// Type name used for derived metric updates, which have no device
+const METRICS_TYPE: &str = "metrics";
+
+/// Type name used for synchrony between two headsets, which are listed in the update itself
+const SYNCHRONY_TYPE: &str = "synchrony";
+
+/// One JSON update, with what clients filter it on
+struct Update {
+    message_type: &'static str,
+    device: Option<String>,
+    json: String,
+}
+
+/// Message types and devices a client asked for. Empty means everything.
+///
+/// Clients send `{"types": ["alpha", "metrics"], "devices": ["192.168.1.5:5000"]}`.
+struct Subscription {
+    types: Vec<String>,
+    devices: Vec<String>,
+}
+
+impl Subscription {
+    fn all() -> Subscription {
+        Subscription {
+            types: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    fn from_json(text: &str) -> Option<Subscription> {
+        let value: Value = serde_json::from_str(text).ok()?;
+
+        Some(Subscription {
+            types: string_list(&value["types"]),
+            devices: string_list(&value["devices"]),
+        })
+    }
+
+    fn matches(&self, update: &Update) -> bool {
+        let type_matches =
+            self.types.is_empty() || self.types.iter().any(|t| t == update.message_type);
+        let device_matches = match update.device {
+            Some(ref device) => self.devices.is_empty() || self.devices.contains(device),
+            None => true,
+        };
+
+        type_matches && device_matches
+    }
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    match value.as_array() {
+        Some(values) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Publishes Muse messages and derived metrics as JSON to browser clients
+pub struct WebSocketServer {
+    clients: Arc<Mutex<Vec<SyncSender<Arc<Update>>>>>,
+}
+
+impl Debug for WebSocketServer {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "<WebSocketServer>")
+    }
+}
+
+impl WebSocketServer {
+    /// Start accepting clients in the background
+    pub fn bind(address: &str) -> io::Result<WebSocketServer> {
+        let listener = TcpListener::bind(address)?;
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accept_clients = clients.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => accept_client(stream, &accept_clients),
+                    Err(e) => eprintln!("WebSocket connection failed: {}", e),
+                }
+            }
+        });
+
+        Ok(WebSocketServer { clients: clients })
+    }
+
+    /// A server on the address in `MEME_MACHINE_WEBSOCKET`, if it is set
+    pub fn from_env() -> Option<WebSocketServer> {
+        let address = env::var(WEBSOCKET_ENV).ok()?;
+
+        match WebSocketServer::bind(&address) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                eprintln!("Could not start WebSocket server on {}: {}", address, e);
+                None
+            }
+        }
+    }
+
+    pub fn publish_message(&self, muse_message: &MuseMessage) {
+        let message_type = muse_message.muse_message_type.name();
+        let device = muse_message.ip_address.to_string();
+        let json = json!({
+            "type": message_type,
+            "device": device,
+            "time": muse_message.time.as_secs_f64(),
+            "values": muse_message.muse_message_type.values(),
+        });
+
+        self.publish(Update {
+            message_type: message_type,
+            device: Some(device),
+            json: json.to_string(),
+        });
+    }
+
+    pub fn publish_metrics(&self, time: Duration, metrics: &DerivedMetrics) {
+        let mut smoothed = serde_json::Map::new();
+        for band in Band::ALL.iter() {
+            smoothed.insert(
+                band.stream_name().to_string(),
+                json!(metrics.smoothed_band(*band).to_vec()),
+            );
+        }
+        let json = json!({
+            "type": METRICS_TYPE,
+            "time": time.as_secs_f64(),
+            "smoothed": smoothed,
+            "asymmetry": metrics.asymmetry,
+            "drowsiness": metrics.drowsiness,
+            "quality": metrics.quality,
+        });
+
+        self.publish(Update {
+            message_type: METRICS_TYPE,
+            device: None,
+            json: json.to_string(),
+        });
+    }
+
+    pub fn publish_synchrony(&self, sample: &SynchronySample) {
+        let mut correlation = serde_json::Map::new();
+        let mut plv = serde_json::Map::new();
+        for band in Band::ALL.iter() {
+            let name = band.stream_name().to_string();
+            correlation.insert(name.clone(), json!(sample.correlation[band.index()]));
+            plv.insert(name, json!(sample.plv[band.index()]));
+        }
+        let json = json!({
+            "type": SYNCHRONY_TYPE,
+            "time": sample.time.as_secs_f64(),
+            "devices": [sample.devices.0.to_string(), sample.devices.1.to_string()],
+            "correlation": correlation,
+            "plv": plv,
+        });
+
+        self.publish(Update {
+            message_type: SYNCHRONY_TYPE,
+            device: None,
+            json: json.to_string(),
+        });
+    }
+
+    fn publish(&self, update: Update) {
+        let update = Arc::new(update);
+        let mut clients = self.clients.lock().expect("WebSocket client list poisoned");
+
+        // A disconnected channel means the client thread has finished
+        clients.retain(|client| match client.try_send(update.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Disconnecting a WebSocket client that stopped reading");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+fn accept_client(stream: TcpStream, clients: &Arc<Mutex<Vec<SyncSender<Arc<Update>>>>>) {
+    let socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = socket
+        .get_ref()
+        .set_read_timeout(Some(CLIENT_POLL_INTERVAL))
+    {
+        eprintln!("Could not configure WebSocket client: {}", e);
+        return;
+    }
+
+    let (tx, rx) = mpsc::sync_channel(CLIENT_QUEUE_LENGTH);
+    clients
+        .lock()
+        .expect("WebSocket client list poisoned")
+        .push(tx);
+
+    thread::spawn(move || serve_client(socket, rx));
+}
+
+/// Alternate between reading subscription changes and writing queued updates
+fn serve_client(mut socket: WebSocket<TcpStream>, rx: Receiver<Arc<Update>>) {
+    let mut subscription = Subscription::all();
+
+    loop {
+        match socket.read_message() {
+            Ok(Message::Text(text)) => match Subscription::from_json(&text) {
+                Some(s) => subscription = s,
+                None => eprintln!("Ignoring WebSocket message {:?}", text),
+            },
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(_) => return,
+        }
+
+        loop {
+            let update = match rx.try_recv() {
+                Ok(update) => update,
+                Err(TryRecvError::Empty) => break,
+                // The server let go of this client for falling behind, or shut down
+                Err(TryRecvError::Disconnected) => return,
+            };
+            if subscription.matches(&update) {
+                if socket
+                    .write_message(Message::Text(update.json.clone()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}